@@ -0,0 +1,265 @@
+// 6502 assembler for single instructions, the reverse of the opcode table.
+// Used by the monitor's a command.
+use opcodes::*;
+use symbols::*;
+
+// A parsed operand value, and whether it was written as a word ($00FF)
+// rather than a byte, so that it keeps absolute addressing
+struct Value {
+    val: u16,
+    word: bool,
+}
+
+// value := term (('+'|'-') term)*
+// term := $hex | %binary | decimal | 'c' | * (the address) | label
+fn parse_value(s: &str, pc: u16, symbols: &Symbols) -> Result<Value, String> {
+    let mut total: i32 = 0;
+    let mut word = false;
+    let mut sign = 1;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err("Missing operand".to_string());
+    }
+    loop {
+        let end = if rest.starts_with('\'') && rest.is_char_boundary(3) {
+            3
+        } else {
+            rest.find(|c| c == '+' || c == '-').unwrap_or(rest.len())
+        };
+        let (term, tail) = rest.split_at(end);
+        let term = term.trim();
+        let (val, is_word) = if term.starts_with('$') {
+            match u16::from_str_radix(&term[1..], 16) {
+                Ok(n) => (n, term.len() > 3),
+                Err(_) => return Err(format!("Invalid number {}", term)),
+            }
+        } else if term.starts_with('%') {
+            match u16::from_str_radix(&term[1..], 2) {
+                Ok(n) => (n, term.len() > 9),
+                Err(_) => return Err(format!("Invalid number {}", term)),
+            }
+        } else if term.len() == 3 && term.starts_with('\'') && term.ends_with('\'') {
+            (term.as_bytes()[1] as u16, false)
+        } else if term == "*" {
+            (pc, false)
+        } else if term.chars().all(|c| c.is_digit(10)) && !term.is_empty() {
+            match term.parse::<u16>() {
+                Ok(n) => (n, false),
+                Err(_) => return Err(format!("Invalid number {}", term)),
+            }
+        } else {
+            match symbols.lookup(term) {
+                Some(addr) => (addr, false),
+                None => return Err(format!("Unknown symbol {}", term)),
+            }
+        };
+        total += sign * val as i32;
+        word = word || is_word;
+        if tail.is_empty() {
+            break;
+        }
+        sign = if tail.starts_with('-') { -1 } else { 1 };
+        rest = tail[1..].trim();
+    }
+    if total < 0 || total > 0xFFFF {
+        return Err(format!("Value out of range {}", s.trim()));
+    }
+    Ok(Value {
+        val: total as u16,
+        word: word || total > 0xFF,
+    })
+}
+
+// Strip an index register suffix (",X" or ",Y") from an operand
+fn strip_index<'a>(s: &'a str, reg: &str) -> Option<&'a str> {
+    match s.rfind(',') {
+        Some(ix) if s[ix + 1..].trim().eq_ignore_ascii_case(reg) => Some(s[..ix].trim()),
+        _ => None,
+    }
+}
+
+// Pick the zero page form of an instruction when the operand fits in a
+// byte and the instruction has one, otherwise the absolute form
+fn zp_or_abs(name: &str, v: &Value, zp: Mode, abs: Mode) -> Result<(&'static Opcode, u16), String> {
+    if !v.word {
+        if let Some(op) = find_opcode(name, zp) {
+            return Ok((op, v.val));
+        }
+    }
+    match find_opcode(name, abs) {
+        Some(op) => Ok((op, v.val)),
+        None => Err(format!("{} can't be used with this addressing mode", name)),
+    }
+}
+
+// Assemble one instruction (such as "LDA ($FB),Y" or "BNE loop") for the
+// address pc, returning its bytes
+pub fn assemble(line: &str, pc: u16, symbols: &Symbols) -> Result<Vec<u8>, String> {
+    let line = line.trim();
+    let (name, operand) = match line.find(char::is_whitespace) {
+        Some(ix) => (line[..ix].to_uppercase(), line[ix..].trim()),
+        None => (line.to_uppercase(), ""),
+    };
+    if !is_mnemonic(&name) {
+        return Err(format!("Unknown instruction {}", name));
+    }
+    let mode_err = || format!("{} can't be used with this addressing mode", name);
+    let find = |mode: Mode| find_opcode(&name, mode).ok_or_else(&mode_err);
+
+    let (opcode, val) = if operand.is_empty() {
+        match find_opcode(&name, Mode::Implied) {
+            Some(op) => (op, 0),
+            None => (try!(find(Mode::Accumulator)), 0),
+        }
+    } else if operand.eq_ignore_ascii_case("A") {
+        (try!(find(Mode::Accumulator)), 0)
+    } else if operand.starts_with('#') {
+        let text = operand[1..].trim();
+        let val = if text.starts_with('<') {
+            try!(parse_value(&text[1..], pc, symbols)).val & 0xFF
+        } else if text.starts_with('>') {
+            try!(parse_value(&text[1..], pc, symbols)).val >> 8
+        } else {
+            let v = try!(parse_value(text, pc, symbols));
+            if v.val > 0xFF {
+                return Err(format!(
+                    "Immediate value ${:04X} is more than a byte",
+                    v.val
+                ));
+            }
+            v.val
+        };
+        (try!(find(Mode::Immediate)), val)
+    } else if operand.starts_with('(') {
+        if let Some(inner) = strip_index(operand, "Y") {
+            if !inner.ends_with(')') {
+                return Err(format!("Invalid operand {}", operand));
+            }
+            let v = try!(parse_value(&inner[1..inner.len() - 1], pc, symbols));
+            (try!(find(Mode::IndirectY)), v.val)
+        } else if operand.ends_with(')') {
+            let inner = &operand[1..operand.len() - 1];
+            match strip_index(inner, "X") {
+                Some(zp) => {
+                    let v = try!(parse_value(zp, pc, symbols));
+                    (try!(find(Mode::IndirectX)), v.val)
+                }
+                None => {
+                    let v = try!(parse_value(inner, pc, symbols));
+                    (try!(find(Mode::Indirect)), v.val)
+                }
+            }
+        } else {
+            return Err(format!("Invalid operand {}", operand));
+        }
+    } else if let Some(base) = strip_index(operand, "X") {
+        let v = try!(parse_value(base, pc, symbols));
+        try!(zp_or_abs(&name, &v, Mode::ZeroPageX, Mode::AbsoluteX))
+    } else if let Some(base) = strip_index(operand, "Y") {
+        let v = try!(parse_value(base, pc, symbols));
+        try!(zp_or_abs(&name, &v, Mode::ZeroPageY, Mode::AbsoluteY))
+    } else if let Some(op) = find_opcode(&name, Mode::Relative) {
+        let target = try!(parse_value(operand, pc, symbols)).val;
+        let offset = target as i32 - (pc as i32 + 2);
+        if offset < -128 || offset > 127 {
+            return Err(format!("Branch to ${:04X} is out of range", target));
+        }
+        (op, offset as u8 as u16)
+    } else {
+        let v = try!(parse_value(operand, pc, symbols));
+        try!(zp_or_abs(&name, &v, Mode::ZeroPage, Mode::Absolute))
+    };
+
+    if (opcode.mode == Mode::IndirectX || opcode.mode == Mode::IndirectY) && val > 0xFF {
+        return Err(format!("${:04X} is not a zero page address", val));
+    }
+    let mut bytes = vec![opcode.code];
+    match opcode.bytes {
+        2 => bytes.push(val as u8),
+        3 => bytes.extend_from_slice(&[val as u8, (val >> 8) as u8]),
+        _ => {}
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asm(line: &str) -> Vec<u8> {
+        let mut symbols = Symbols::new();
+        symbols.add(0xFFD2, "CHROUT");
+        symbols.add(0xFB, "ptr");
+        assemble(line, 0xC000, &symbols).unwrap()
+    }
+
+    #[test]
+    fn every_opcode() {
+        let symbols = Symbols::new();
+        for code in 0..256 {
+            let op = match get_opcode(code as u8) {
+                Some(op) => op,
+                None => continue,
+            };
+            let operand = match op.mode {
+                Mode::Implied => "",
+                Mode::Accumulator => "A",
+                Mode::Immediate => "#$12",
+                Mode::ZeroPage => "$12",
+                Mode::ZeroPageX => "$12,X",
+                Mode::ZeroPageY => "$12,Y",
+                Mode::Absolute => "$1234",
+                Mode::AbsoluteX => "$1234,X",
+                Mode::AbsoluteY => "$1234,Y",
+                Mode::Indirect => "($1234)",
+                Mode::IndirectX => "($12,X)",
+                Mode::IndirectY => "($12),Y",
+                Mode::Relative => "$C012",
+            };
+            let line = format!("{} {}", op.name, operand);
+            let bytes = assemble(&line, 0xC000, &symbols).unwrap();
+            assert_eq!(bytes[0], op.code, "{}", line);
+            assert_eq!(bytes.len(), op.bytes as usize, "{}", line);
+        }
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(asm("lda #$ff"), vec![0xA9, 0xFF]);
+        assert_eq!(asm("LDA #'-'"), vec![0xA9, 0x2D]);
+        assert_eq!(asm("LDA #%101"), vec![0xA9, 0x05]);
+        assert_eq!(asm("LDA $FB"), vec![0xA5, 0xFB]);
+        assert_eq!(asm("LDA $00FB"), vec![0xAD, 0xFB, 0x00]);
+        assert_eq!(asm("LDA ptr+1"), vec![0xA5, 0xFC]);
+        assert_eq!(asm("LDA ( ptr ) , y"), vec![0xB1, 0xFB]);
+        assert_eq!(asm("LDX 16,Y"), vec![0xB6, 0x10]);
+        assert_eq!(asm("STA $0400,Y"), vec![0x99, 0x00, 0x04]);
+        assert_eq!(asm("LDA #<CHROUT"), vec![0xA9, 0xD2]);
+        assert_eq!(asm("LDA #>CHROUT"), vec![0xA9, 0xFF]);
+        assert_eq!(asm("JSR CHROUT"), vec![0x20, 0xD2, 0xFF]);
+        assert_eq!(asm("JMP ($0314)"), vec![0x6C, 0x14, 0x03]);
+        assert_eq!(asm("ASL"), vec![0x0A]);
+        assert_eq!(asm("BNE *"), vec![0xD0, 0xFE]);
+        assert_eq!(asm("BCC $C081"), vec![0x90, 0x7F]);
+        assert_eq!(asm("BCS $BF82"), vec![0xB0, 0x80]);
+    }
+
+    #[test]
+    fn errors() {
+        let symbols = Symbols::new();
+        for line in &[
+            "FOO",
+            "STA #$01",
+            "LDA #$100",
+            "BNE $C082",
+            "JMP ($12),Y",
+            "LDA (label),Y",
+            "LDA ($1234),Y",
+            "INX A",
+            "LDA 0-1",
+            "LDA $FFFF+2",
+        ] {
+            assert!(assemble(line, 0xC000, &symbols).is_err(), "{}", line);
+        }
+    }
+}
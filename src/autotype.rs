@@ -0,0 +1,138 @@
+use keyboard::*;
+use memory::*;
+use petscii::*;
+use std::collections::VecDeque;
+
+pub const KEYBOARD_BUFFER: u16 = 0x0277;
+pub const KEYBOARD_BUFFER_LEN: u16 = 0x00C6;
+pub const KEYBOARD_BUFFER_SIZE: u16 = 0x0289;
+pub const CURSOR_ROW: u16 = 0x00D6;
+pub const SCREEN_PAGE: u16 = 0x0288;
+
+// "READY." in screen codes
+static READY_PROMPT: &'static [u8] = &[0x12, 0x05, 0x01, 0x04, 0x19, 0x2E];
+
+// Screen refreshes a key is held down and then released for in matrix mode,
+// long enough for the KERNAL (or a program) to scan it in each state
+const KEY_HOLD_FRAMES: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TypeMode {
+    Buffer, // Fill the KERNAL keyboard buffer
+    Matrix, // Press keys on the keyboard matrix
+}
+
+impl TypeMode {
+    pub fn from_str(s: &str) -> Option<TypeMode> {
+        match s {
+            "buffer" => Some(TypeMode::Buffer),
+            "matrix" => Some(TypeMode::Matrix),
+            _ => None,
+        }
+    }
+}
+
+// True if BASIC is sitting at the READY prompt with an empty keyboard buffer
+pub fn basic_ready(mem: &MemIO) -> bool {
+    let row = mem.read_byte(CURSOR_ROW) as u16;
+    if row == 0 || row > 24 || mem.read_byte(KEYBOARD_BUFFER_LEN) != 0 {
+        return false;
+    }
+
+    let line = ((mem.read_byte(SCREEN_PAGE) as u16) << 8) + (row - 1) * 40;
+    READY_PROMPT
+        .iter()
+        .enumerate()
+        .all(|(ix, &c)| mem.read_byte(line + ix as u16) == c)
+}
+
+// Types PETSCII text into the C64, a few characters per screen refresh
+pub struct AutoType {
+    mode: TypeMode,
+    queue: VecDeque<u8>,
+    wait_ready: bool,
+    frames: u8,
+}
+
+impl AutoType {
+    pub fn new(mode: TypeMode) -> AutoType {
+        AutoType {
+            mode: mode,
+            queue: VecDeque::new(),
+            wait_ready: false,
+            frames: 0,
+        }
+    }
+
+    // Queue host text for typing. With wait_ready set, typing only starts
+    // once BASIC shows the READY prompt (e.g. right after power on).
+    pub fn type_text(&mut self, text: &str, wait_ready: bool) {
+        let petscii = str_to_petscii(text);
+        if petscii.len() < text.replace("\r\n", "\n").chars().count() {
+            println!("Some characters have no PETSCII equivalent and were skipped");
+        }
+        self.queue.extend(petscii);
+        self.wait_ready = self.wait_ready || wait_ready;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.frames == 0
+    }
+
+    // Called on every screen refresh. Returns the keys the typer holds down.
+    pub fn refresh(&mut self, mem: &mut MemIO) -> [u8; 8] {
+        if self.is_idle() {
+            return [0; 8];
+        }
+        if self.wait_ready {
+            if !basic_ready(mem) {
+                return [0; 8];
+            }
+            self.wait_ready = false;
+        }
+
+        match self.mode {
+            TypeMode::Buffer => {
+                self.fill_buffer(mem);
+                [0; 8]
+            }
+            TypeMode::Matrix => self.press_next(),
+        }
+    }
+
+    fn fill_buffer(&mut self, mem: &mut MemIO) {
+        let size = match mem.read_byte(KEYBOARD_BUFFER_SIZE) {
+            0 => 10,
+            n => n.min(10),
+        };
+        let mut len = mem.read_byte(KEYBOARD_BUFFER_LEN);
+        while len < size {
+            match self.queue.pop_front() {
+                Some(p) => {
+                    mem.write_byte(KEYBOARD_BUFFER + len as u16, p);
+                    len += 1;
+                }
+                None => break,
+            }
+        }
+        mem.write_byte(KEYBOARD_BUFFER_LEN, len);
+    }
+
+    fn press_next(&mut self) -> [u8; 8] {
+        // Hold the key for KEY_HOLD_FRAMES, then release for as long again
+        self.frames += 1;
+        if self.frames > KEY_HOLD_FRAMES * 2 {
+            self.frames = 0;
+            self.queue.pop_front();
+            return [0; 8];
+        }
+        if self.frames > KEY_HOLD_FRAMES {
+            return [0; 8];
+        }
+
+        match self.queue.front().and_then(|&p| petscii_key(p)) {
+            Some((row, col, shift)) => kbd_key_matrix(row, col, shift),
+            None => [0; 8],
+        }
+    }
+}
@@ -0,0 +1,387 @@
+// c64disk: create and edit D64, D71, D81 and G64 disk images from the
+// command line, using the emulator's disk image code
+extern crate clap;
+extern crate retroc64;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use retroc64::d64::*;
+use retroc64::diskimage::*;
+use retroc64::petscii::*;
+use retroc64::utils::*;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+
+fn fail(msg: &str) -> ! {
+    println!("c64disk: {}", msg);
+    process::exit(1)
+}
+
+fn display_name(name: &[u8]) -> String {
+    name.iter()
+        .filter_map(|&c| petscii_to_char(c))
+        .collect::<String>()
+        .to_uppercase()
+}
+
+// Open an image, entering a D81 partition if one is given
+fn open_image(matches: &ArgMatches) -> (String, Box<DiskImage>) {
+    let path = matches.value_of("image").unwrap().to_string();
+    let mut image = match load_image(&path) {
+        Some(image) => image,
+        None => process::exit(1),
+    };
+    if let Some(p) = matches.value_of("partition") {
+        if let Err(e) = image.change_dir(&str_to_petscii(p)) {
+            fail(&format!("can't enter partition {}: {}", p, e));
+        }
+    }
+    (path, image)
+}
+
+fn save_image(path: &str, image: &DiskImage) {
+    if let Err(e) = image.save(path) {
+        fail(&format!("unable to write {}: {}", path, e));
+    }
+}
+
+fn parse_type(s: &str) -> u8 {
+    match s.to_lowercase().as_str() {
+        "prg" => FILE_PRG,
+        "seq" => FILE_SEQ,
+        "usr" => FILE_USR,
+        _ => fail(&format!("unknown file type {} (prg, seq or usr)", s)),
+    }
+}
+
+fn do_create(matches: &ArgMatches) {
+    let path = matches.value_of("image").unwrap();
+    if Path::new(path).exists() && !matches.is_present("force") {
+        fail(&format!("{} exists (use --force to overwrite)", path));
+    }
+    let name = str_to_petscii(matches.value_of("name").unwrap_or("EMPTY"));
+    let id = str_to_petscii(matches.value_of("id").unwrap_or("00"));
+    let image = new_image(path, &name, &id);
+    save_image(path, &*image);
+}
+
+fn do_list(matches: &ArgMatches) {
+    let (_, image) = open_image(matches);
+    let (name, id) = image.header();
+    let id: String = id
+        .iter()
+        .map(|&c| petscii_to_char(c).unwrap_or(' '))
+        .collect();
+    println!("0 \"{:<16}\" {}", display_name(&name), id.to_uppercase());
+    for e in image.directory() {
+        let quoted = format!("\"{}\"", display_name(&e.name));
+        println!(
+            "{:<5}{:<18}{}{}{}",
+            e.blocks,
+            quoted,
+            if (e.file_type & FILE_CLOSED) > 0 {
+                ' '
+            } else {
+                '*'
+            },
+            e.type_name(),
+            if (e.file_type & FILE_LOCKED) > 0 {
+                "<"
+            } else {
+                ""
+            }
+        );
+    }
+    println!("{} BLOCKS FREE.", image.blocks_free());
+}
+
+fn do_add(matches: &ArgMatches) {
+    let (path, mut image) = open_image(matches);
+    let files: Vec<&str> = matches.values_of("files").unwrap().collect();
+    if matches.is_present("as") && files.len() > 1 {
+        fail("--as needs a single file");
+    }
+
+    for f in files {
+        let data = match load_file(f) {
+            Some(d) => d,
+            None => process::exit(1),
+        };
+        let host = Path::new(f);
+        let ext = host
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_string();
+        let file_type = match matches.value_of("type") {
+            Some(t) => parse_type(t),
+            None if ext.eq_ignore_ascii_case("seq") || ext.eq_ignore_ascii_case("usr") => {
+                parse_type(&ext)
+            }
+            None => FILE_PRG,
+        };
+        let name = match matches.value_of("as") {
+            Some(n) => n,
+            None => host.file_stem().and_then(|s| s.to_str()).unwrap_or(f),
+        };
+        let name = str_to_petscii(name);
+        if name.is_empty() || name.len() > 16 {
+            fail(&format!("{}: names must be 1 to 16 characters", f));
+        }
+        if let Err(e) = image.write_file(&name, file_type, &data) {
+            fail(&format!("can't add {}: {}", f, e));
+        }
+        println!("Added {} as \"{}\"", f, display_name(&name));
+    }
+    save_image(&path, &*image);
+}
+
+fn do_extract(matches: &ArgMatches) {
+    let (_, image) = open_image(matches);
+    let out = matches.value_of("out").unwrap_or(".");
+    let patterns: Vec<Vec<u8>> = match matches.values_of("names") {
+        Some(names) => names.map(str_to_petscii).collect(),
+        None => vec![b"*".to_vec()],
+    };
+
+    let mut count = 0;
+    for e in image.directory() {
+        let file_type = e.file_type & 0x07;
+        if (e.file_type & FILE_CLOSED) == 0 || file_type == FILE_DEL || file_type > FILE_USR {
+            continue;
+        }
+        if !patterns.iter().any(|p| name_matches(p, &e.name)) {
+            continue;
+        }
+        let data = match image.read_chain(e.track, e.sector) {
+            Ok(d) => d,
+            Err(e) => fail(&e),
+        };
+        let stem: String = e
+            .name
+            .iter()
+            .filter_map(|&c| petscii_to_char(c))
+            .map(|c| if c == '/' { '_' } else { c })
+            .collect();
+        let host = Path::new(out).join(format!("{}.{}", stem, e.type_name().to_lowercase()));
+        if let Err(err) = File::create(&host).and_then(|mut f| f.write_all(&data)) {
+            fail(&format!("couldn't write {}: {}", host.display(), err));
+        }
+        println!(
+            "Extracted \"{}\" to {}",
+            display_name(&e.name),
+            host.display()
+        );
+        count += 1;
+    }
+    if count == 0 {
+        fail("no matching files");
+    }
+}
+
+fn do_delete(matches: &ArgMatches) {
+    let (path, mut image) = open_image(matches);
+    let mut count = 0;
+    for name in matches.values_of("names").unwrap() {
+        let pattern = str_to_petscii(name);
+        for e in image.directory() {
+            if name_matches(&pattern, &e.name) && (e.file_type & FILE_LOCKED) > 0 {
+                println!("{} is locked", display_name(&e.name));
+            }
+        }
+        count += image.delete(&pattern);
+    }
+    println!("{} files deleted", count);
+    save_image(&path, &*image);
+}
+
+fn do_rename(matches: &ArgMatches) {
+    let (path, mut image) = open_image(matches);
+    let old = str_to_petscii(matches.value_of("old").unwrap());
+    let new = str_to_petscii(matches.value_of("new").unwrap());
+    if new.is_empty() || new.len() > 16 {
+        fail("names must be 1 to 16 characters");
+    }
+    if let Err(e) = image.rename(&old, &new) {
+        fail(&e);
+    }
+    save_image(&path, &*image);
+}
+
+fn do_validate(matches: &ArgMatches) {
+    let (path, mut image) = open_image(matches);
+    let fix = matches.is_present("fix");
+    let problems = image.validate(fix);
+    for p in problems.iter() {
+        println!("{}", p);
+    }
+    if problems.is_empty() {
+        println!("BAM OK, {} blocks free", image.blocks_free());
+    } else if fix {
+        save_image(&path, &*image);
+        println!("BAM fixed, {} blocks free", image.blocks_free());
+    } else {
+        process::exit(1);
+    }
+}
+
+fn do_dump(matches: &ArgMatches) {
+    let (_, image) = open_image(matches);
+    let number = |arg: &str| -> u8 {
+        let s = matches.value_of(arg).unwrap();
+        match s.parse::<u8>() {
+            Ok(n) => n,
+            Err(_) => fail(&format!("invalid {}: {}", arg, s)),
+        }
+    };
+    let track = number("track");
+    let sectors = if matches.is_present("sector") {
+        vec![number("sector")]
+    } else {
+        (0..image.sectors(track)).collect()
+    };
+
+    for s in sectors {
+        let buf = match image.read_sector(track, s) {
+            Some(b) => b.to_vec(),
+            None => fail(&format!("no track {} sector {}", track, s)),
+        };
+        println!("Track {} sector {}:", track, s);
+        for offset in (0..SECTOR_SIZE).filter(|o| o % 16 == 0) {
+            hexdump(&buf, offset, 16);
+        }
+    }
+}
+
+fn main() {
+    let image = || {
+        Arg::with_name("image")
+            .value_name("IMAGE")
+            .required(true)
+            .index(1)
+    };
+
+    let matches = App::new("c64disk")
+        .version("0.1.0")
+        .about("Create and edit D64, D71, D81 and G64 disk images")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("partition")
+                .short("p")
+                .long("partition")
+                .value_name("NAME")
+                .help("Work inside a D81 partition")
+                .takes_value(true)
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("create")
+                .about("Create a blank image (type from the extension)")
+                .arg(image())
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .takes_value(true)
+                        .help("Disk name"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .long("id")
+                        .takes_value(true)
+                        .help("Disk id"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Overwrite an existing file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the directory")
+                .arg(image()),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Add host files (replacing files of the same name)")
+                .arg(image())
+                .arg(
+                    Arg::with_name("files")
+                        .value_name("FILE")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("as")
+                        .long("as")
+                        .value_name("NAME")
+                        .takes_value(true)
+                        .help("Name on disk (default: the host file name)"),
+                )
+                .arg(
+                    Arg::with_name("type")
+                        .long("type")
+                        .value_name("TYPE")
+                        .takes_value(true)
+                        .help("File type: prg, seq or usr (default: from the extension)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Extract files (all if no names are given)")
+                .arg(image())
+                .arg(Arg::with_name("names").value_name("NAME").multiple(true))
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .value_name("DIR")
+                        .takes_value(true)
+                        .help("Directory to write to (default: current)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Delete files (wildcards * and ? allowed)")
+                .arg(image())
+                .arg(
+                    Arg::with_name("names")
+                        .value_name("NAME")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rename")
+                .about("Rename a file")
+                .arg(image())
+                .arg(Arg::with_name("old").value_name("OLD").required(true))
+                .arg(Arg::with_name("new").value_name("NEW").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check the BAM against the files")
+                .arg(image())
+                .arg(Arg::with_name("fix").long("fix").help("Correct the BAM")),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Hex dump a sector (or a whole track)")
+                .arg(image())
+                .arg(Arg::with_name("track").value_name("TRACK").required(true))
+                .arg(Arg::with_name("sector").value_name("SECTOR")),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("create", Some(m)) => do_create(m),
+        ("list", Some(m)) => do_list(m),
+        ("add", Some(m)) => do_add(m),
+        ("extract", Some(m)) => do_extract(m),
+        ("delete", Some(m)) => do_delete(m),
+        ("rename", Some(m)) => do_rename(m),
+        ("validate", Some(m)) => do_validate(m),
+        ("dump", Some(m)) => do_dump(m),
+        _ => {}
+    }
+}
@@ -0,0 +1,634 @@
+// Monitor breakpoints: a table of numbered breakpoints, each of which can
+// be disabled, ignored a number of times, removed after its first hit or
+// made conditional on registers and memory, and watchpoints on memory
+use cpu::*;
+use memc64::*;
+use std::cell::RefCell;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Register {
+    A,
+    X,
+    Y,
+    SP,
+    PC,
+    ST,
+    // A single status flag, by its bit in ST
+    Flag(u8),
+}
+
+enum Value {
+    Register(Register),
+    Number(u16),
+    // The byte at an address
+    Memory(Box<Value>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+enum Expr {
+    Compare(Value, Compare, Value),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+pub struct Condition {
+    expr: Expr,
+}
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    Number(u16),
+    Name(String),
+    Compare(Compare),
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).cloned();
+        let (token, len) = match (c, next) {
+            (' ', _) | ('\t', _) => {
+                i += 1;
+                continue;
+            }
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Compare(Compare::Eq), 2),
+            ('!', Some('=')) => (Token::Compare(Compare::Ne), 2),
+            ('<', Some('=')) => (Token::Compare(Compare::Le), 2),
+            ('>', Some('=')) => (Token::Compare(Compare::Ge), 2),
+            ('<', _) => (Token::Compare(Compare::Lt), 1),
+            ('>', _) => (Token::Compare(Compare::Gt), 1),
+            ('$', _) | ('%', _) | ('0'...'9', _) => {
+                let (radix, start) = match c {
+                    '$' => (16, i + 1),
+                    '%' => (2, i + 1),
+                    _ => (10, i),
+                };
+                let mut end = start;
+                while end < chars.len() && chars[end].is_digit(radix) {
+                    end += 1;
+                }
+                let digits: String = chars[start..end].iter().cloned().collect();
+                match u32::from_str_radix(&digits, radix) {
+                    Ok(n) if n <= 0xFFFF => (Token::Number(n as u16), end - i),
+                    _ => {
+                        let rest: String = chars[i..].iter().cloned().collect();
+                        return Err(format!("Invalid number at '{}'", rest));
+                    }
+                }
+            }
+            (c, _) if c.is_alphabetic() => {
+                let mut end = i;
+                while end < chars.len() && chars[end].is_alphanumeric() {
+                    end += 1;
+                }
+                let name: String = chars[i..end].iter().cloned().collect();
+                (Token::Name(name.to_uppercase()), end - i)
+            }
+            _ => return Err(format!("Unexpected '{}' in condition", c)),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn accept(&mut self, t: Token) -> bool {
+        if self.tokens.get(self.pos) == Some(&t) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // or := and ('||' and)*
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut cond = try!(self.and());
+        while self.accept(Token::Or) {
+            cond = Expr::Or(Box::new(cond), Box::new(try!(self.and())));
+        }
+        Ok(cond)
+    }
+
+    // and := compare ('&&' compare)*
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut cond = try!(self.compare());
+        while self.accept(Token::And) {
+            cond = Expr::And(Box::new(cond), Box::new(try!(self.compare())));
+        }
+        Ok(cond)
+    }
+
+    fn compare(&mut self) -> Result<Expr, String> {
+        let left = try!(self.value());
+        let op = match self.next() {
+            Some(Token::Compare(op)) => op,
+            _ => return Err("Expected one of == != < <= > >=".to_string()),
+        };
+        let right = try!(self.value());
+        Ok(Expr::Compare(left, op, right))
+    }
+
+    // A number, a register or flag, or (value) for the byte at an address
+    fn value(&mut self) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Value::Number(n)),
+            Some(Token::Open) => {
+                let addr = try!(self.value());
+                if self.accept(Token::Close) {
+                    Ok(Value::Memory(Box::new(addr)))
+                } else {
+                    Err("Missing )".to_string())
+                }
+            }
+            Some(Token::Name(name)) => {
+                let reg = match name.as_str() {
+                    "A" => Register::A,
+                    "X" => Register::X,
+                    "Y" => Register::Y,
+                    "SP" => Register::SP,
+                    "PC" => Register::PC,
+                    "ST" | "P" => Register::ST,
+                    "C" => Register::Flag(Flags::Carry as u8),
+                    "Z" => Register::Flag(Flags::Zero as u8),
+                    "I" => Register::Flag(Flags::Interrupt as u8),
+                    "D" => Register::Flag(Flags::Decimal as u8),
+                    "B" => Register::Flag(Flags::Break as u8),
+                    "V" => Register::Flag(Flags::Overflow as u8),
+                    "N" => Register::Flag(Flags::Sign as u8),
+                    _ => return Err(format!("Unknown register {} (use $ for hex numbers)", name)),
+                };
+                Ok(Value::Register(reg))
+            }
+            _ => Err("Expected a number, register or (address)".to_string()),
+        }
+    }
+}
+
+impl Value {
+    fn eval(&self, cpu: &CPU, mem: &MemC64) -> u16 {
+        match *self {
+            Value::Number(n) => n,
+            // Reading I/O registers for the monitor mustn't acknowledge them
+            Value::Memory(ref addr) => mem.peek(MemView::Cpu, addr.eval(cpu, mem)) as u16,
+            Value::Register(reg) => match reg {
+                Register::A => cpu.get_a() as u16,
+                Register::X => cpu.get_x() as u16,
+                Register::Y => cpu.get_y() as u16,
+                Register::SP => cpu.get_sp() as u16,
+                Register::PC => cpu.get_pc(),
+                Register::ST => cpu.get_st() as u16,
+                Register::Flag(bit) => {
+                    if (cpu.get_st() & bit) > 0 {
+                        1
+                    } else {
+                        0
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl Condition {
+    // Parse a condition such as "A==$FF && ($FB)>10"
+    pub fn parse(s: &str) -> Result<Condition, String> {
+        let mut parser = Parser {
+            tokens: try!(tokenize(s)),
+            pos: 0,
+        };
+        let expr = try!(parser.or());
+        match parser.next() {
+            None => Ok(Condition { expr: expr }),
+            Some(_) => Err("Unexpected text after condition".to_string()),
+        }
+    }
+
+    pub fn eval(&self, cpu: &CPU, mem: &MemC64) -> bool {
+        self.expr.eval(cpu, mem)
+    }
+}
+
+impl Expr {
+    fn eval(&self, cpu: &CPU, mem: &MemC64) -> bool {
+        match *self {
+            Expr::Compare(ref l, op, ref r) => {
+                let (l, r) = (l.eval(cpu, mem), r.eval(cpu, mem));
+                match op {
+                    Compare::Eq => l == r,
+                    Compare::Ne => l != r,
+                    Compare::Lt => l < r,
+                    Compare::Le => l <= r,
+                    Compare::Gt => l > r,
+                    Compare::Ge => l >= r,
+                }
+            }
+            Expr::And(ref l, ref r) => l.eval(cpu, mem) && r.eval(cpu, mem),
+            Expr::Or(ref l, ref r) => l.eval(cpu, mem) || r.eval(cpu, mem),
+        }
+    }
+}
+
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: u16,
+    pub enabled: bool,
+    pub temporary: bool,
+    pub hits: u32,
+    pub ignore: u32,
+    // The condition as typed, for listing, and parsed
+    pub condition: Option<(String, Condition)>,
+}
+
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    next_id: usize,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints {
+            list: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    // Add a breakpoint and return its id
+    pub fn add(
+        &mut self,
+        addr: u16,
+        temporary: bool,
+        condition: Option<(String, Condition)>,
+    ) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Breakpoint {
+            id: id,
+            addr: addr,
+            enabled: true,
+            temporary: temporary,
+            hits: 0,
+            ignore: 0,
+            condition: condition,
+        });
+        id
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.list.iter_mut().find(|b| b.id == id)
+    }
+
+    pub fn delete(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|b| b.id != id);
+        self.list.len() < len
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    // Called with the PC of the next instruction. Returns the id of the
+    // breakpoint to stop at, if any; hits are counted whenever the
+    // condition holds, even while being ignored.
+    pub fn check(&mut self, cpu: &CPU, mem: &MemC64) -> Option<usize> {
+        if self.list.is_empty() {
+            return None;
+        }
+        let pc = cpu.get_pc();
+        let mut hit = None;
+        for b in self.list.iter_mut() {
+            if b.addr != pc || !b.enabled {
+                continue;
+            }
+            if let Some((_, ref cond)) = b.condition {
+                if !cond.eval(cpu, mem) {
+                    continue;
+                }
+            }
+            b.hits += 1;
+            if b.ignore > 0 {
+                b.ignore -= 1;
+                continue;
+            }
+            if hit.is_none() {
+                hit = Some((b.id, b.temporary));
+            }
+        }
+        match hit {
+            Some((id, temporary)) => {
+                if temporary {
+                    self.delete(id);
+                }
+                Some(id)
+            }
+            None => None,
+        }
+    }
+
+    pub fn print(&self) {
+        if self.list.is_empty() {
+            println!("No breakpoints");
+            return;
+        }
+        println!("ID  Address  Enabled  Hits  Ignore  Condition  (* = temporary)");
+        for b in self.list.iter() {
+            println!(
+                "{:<3} ${:04X}{}   {:<7}  {:<4}  {:<6}  {}",
+                b.id,
+                b.addr,
+                if b.temporary { "*" } else { " " },
+                if b.enabled { "yes" } else { "no" },
+                b.hits,
+                b.ignore,
+                match b.condition {
+                    Some((ref text, _)) => text.as_str(),
+                    None => "",
+                }
+            );
+        }
+    }
+}
+
+// Accesses a watchpoint can trigger on
+pub const WATCH_READ: u8 = 0x01;
+pub const WATCH_WRITE: u8 = 0x02;
+pub const WATCH_EXECUTE: u8 = 0x04;
+pub const WATCH_VIC: u8 = 0x08;
+
+// Where an access went
+#[derive(Clone, Copy, PartialEq)]
+pub enum Bank {
+    Ram,
+    BasicRom,
+    KernalRom,
+    CharRom,
+    Io,
+}
+
+impl Bank {
+    fn name(&self) -> &'static str {
+        match *self {
+            Bank::Ram => "RAM",
+            Bank::BasicRom => "BASIC ROM",
+            Bank::KernalRom => "KERNAL ROM",
+            Bank::CharRom => "CHAR ROM",
+            Bank::Io => "I/O",
+        }
+    }
+}
+
+pub struct Watchpoint {
+    pub id: usize,
+    pub start: u16,
+    pub end: u16,
+    pub access: u8,
+}
+
+pub struct WatchHit {
+    pub id: usize,
+    pub access: u8,
+    pub addr: u16,
+    pub pc: u16,
+    pub old: u8,
+    pub new: u8,
+    pub bank: Bank,
+}
+
+impl WatchHit {
+    pub fn describe(&self) -> String {
+        let at = format!("${:04X} ({})", self.addr, self.bank.name());
+        match self.access {
+            WATCH_WRITE => format!(
+                "Watchpoint {}: write {} at PC ${:04X}: ${:02X} -> ${:02X}",
+                self.id, at, self.pc, self.old, self.new
+            ),
+            WATCH_EXECUTE => format!("Watchpoint {}: execute {}", self.id, at),
+            WATCH_VIC => format!("Watchpoint {}: VIC read {}: ${:02X}", self.id, at, self.new),
+            _ => format!(
+                "Watchpoint {}: read {} at PC ${:04X}: ${:02X}",
+                self.id, at, self.pc, self.new
+            ),
+        }
+    }
+}
+
+// Watched address ranges, checked by the memory on every access. Hits are
+// collected during an instruction and picked up by the monitor after it.
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    next_id: usize,
+    pc: u16,
+    len: u8,
+    hits: RefCell<Vec<WatchHit>>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints {
+            list: Vec::new(),
+            next_id: 1,
+            pc: 0,
+            len: 0,
+            hits: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn add(&mut self, start: u16, end: u16, access: u8) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Watchpoint {
+            id: id,
+            start: start,
+            end: end,
+            access: access,
+        });
+        id
+    }
+
+    pub fn delete(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|w| w.id != id);
+        self.list.len() < len
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    // Start of an instruction of len bytes at pc: forget the hits of the
+    // last one
+    pub fn begin(&mut self, pc: u16, len: u8) {
+        self.pc = pc;
+        self.len = len;
+        self.hits.borrow_mut().clear();
+    }
+
+    pub fn watches(&self, addr: u16, access: u8) -> bool {
+        self.list
+            .iter()
+            .any(|w| (w.access & access) > 0 && addr >= w.start && addr <= w.end)
+    }
+
+    pub fn record(&self, addr: u16, access: u8, old: u8, new: u8, bank: Bank) {
+        // The CPU fetching the instruction itself is not a data read
+        if access == WATCH_READ && addr.wrapping_sub(self.pc) < self.len as u16 {
+            return;
+        }
+        for w in self.list.iter() {
+            if (w.access & access) > 0 && addr >= w.start && addr <= w.end {
+                self.hits.borrow_mut().push(WatchHit {
+                    id: w.id,
+                    access: access,
+                    addr: addr,
+                    pc: self.pc,
+                    old: old,
+                    new: new,
+                    bank: bank,
+                });
+            }
+        }
+    }
+
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        ::std::mem::replace(&mut *self.hits.borrow_mut(), Vec::new())
+    }
+
+    pub fn print(&self) {
+        if self.list.is_empty() {
+            println!("No watchpoints");
+            return;
+        }
+        println!("ID  Range        Access");
+        for w in self.list.iter() {
+            let access: String = [
+                (WATCH_READ, 'r'),
+                (WATCH_WRITE, 'w'),
+                (WATCH_EXECUTE, 'x'),
+                (WATCH_VIC, 'v'),
+            ]
+            .iter()
+            .filter(|&&(bit, _)| (w.access & bit) > 0)
+            .map(|&(_, c)| c)
+            .collect();
+            println!("{:<3} ${:04X}-${:04X}  {}", w.id, w.start, w.end, access);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str, cpu: &CPU, mem: &MemC64) -> bool {
+        Condition::parse(s).unwrap().eval(cpu, mem)
+    }
+
+    #[test]
+    fn conditions() {
+        let mut cpu = CPU::new();
+        let mut mem = MemC64::new();
+        cpu.set_a(0xFF);
+        cpu.set_x(3);
+        mem.poke(MemView::Ram, 0xFB, 0x10);
+        mem.poke(MemView::Ram, 0x10, 0x42);
+
+        // && binds tighter than ||
+        assert!(eval("A==1 && X==3 || X==3", &cpu, &mem));
+        assert!(eval("X==3 || X==4 && A==1", &cpu, &mem));
+        assert!(!eval("(X)==3 && A==1 || X==4", &cpu, &mem));
+
+        assert!(eval("($FB)==$10", &cpu, &mem));
+        assert!(eval("(($FB))==$42", &cpu, &mem));
+        assert!(eval("X==%11 && A==255 && A>=%11111111", &cpu, &mem));
+        assert!(eval("a!=x && y<X", &cpu, &mem));
+
+        cpu.set_flag(Flags::Carry, true);
+        cpu.set_flag(Flags::Zero, false);
+        assert!(eval("C==1 && Z==0", &cpu, &mem));
+        assert!(eval("ST==P", &cpu, &mem));
+    }
+
+    #[test]
+    fn condition_errors() {
+        for s in &[
+            "A==",
+            "A=$FF",
+            "($FB",
+            "($FB)==1 X",
+            "A==$10000",
+            "Q==1",
+            "",
+        ] {
+            assert!(Condition::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn check() {
+        let mut cpu = CPU::new();
+        let mem = MemC64::new();
+        let mut bps = Breakpoints::new();
+
+        let counted = bps.add(0xC000, false, None);
+        bps.get_mut(counted).unwrap().ignore = 2;
+        cpu.set_pc(0xC000);
+        assert_eq!(bps.check(&cpu, &mem), None);
+        assert_eq!(bps.check(&cpu, &mem), None);
+        assert_eq!(bps.check(&cpu, &mem), Some(counted));
+        assert_eq!(bps.check(&cpu, &mem), Some(counted));
+        assert_eq!(bps.get_mut(counted).unwrap().hits, 4);
+
+        // Only counted while the condition holds
+        let cond = Condition::parse("X==4").unwrap();
+        let conditional = bps.add(0xC010, false, Some(("X==4".to_string(), cond)));
+        cpu.set_pc(0xC010);
+        cpu.set_x(3);
+        assert_eq!(bps.check(&cpu, &mem), None);
+        assert_eq!(bps.get_mut(conditional).unwrap().hits, 0);
+        cpu.set_x(4);
+        assert_eq!(bps.check(&cpu, &mem), Some(conditional));
+
+        let temporary = bps.add(0xC020, true, None);
+        cpu.set_pc(0xC020);
+        assert_eq!(bps.check(&cpu, &mem), Some(temporary));
+        assert!(bps.get_mut(temporary).is_none());
+        assert_eq!(bps.check(&cpu, &mem), None);
+    }
+}
@@ -0,0 +1,223 @@
+// Program containers: T64 tape archives and PC64 P00 files. Both hold
+// programs in PRG form (load address followed by data) under a C64 name.
+use d64::*;
+use utils::*;
+
+const T64_HEADER_SIZE: usize = 0x40;
+const T64_ENTRY_SIZE: usize = 0x20;
+const T64_NAME_PAD: u8 = 0x20;
+
+const P00_SIGNATURE: &'static [u8] = b"C64File\0";
+const P00_HEADER_SIZE: usize = 26;
+
+pub struct T64Entry {
+    pub name: Vec<u8>, // PETSCII, without padding
+    pub start: u16,
+    pub data: Vec<u8>, // PRG, with load address
+}
+
+pub fn is_t64(data: &[u8]) -> bool {
+    data.len() >= T64_HEADER_SIZE && &data[0..3] == b"C64" && !is_p00(data)
+}
+
+pub fn is_p00(data: &[u8]) -> bool {
+    data.len() >= P00_HEADER_SIZE && &data[0..8] == P00_SIGNATURE
+}
+
+// Programs in a T64 archive, in directory order
+pub fn read_t64(data: &[u8]) -> Result<Vec<T64Entry>, String> {
+    if !is_t64(data) {
+        return Err("not a T64 image".to_string());
+    }
+    let word = |ix: usize| data[ix] as usize | (data[ix + 1] as usize) << 8;
+    let max = word(0x22).max(1);
+
+    let mut entries = Vec::new();
+    for i in 0..max {
+        let e = T64_HEADER_SIZE + i * T64_ENTRY_SIZE;
+        if e + T64_ENTRY_SIZE > data.len() {
+            break;
+        }
+        if data[e] == 0 {
+            continue;
+        }
+        let start = word(e + 2) as u16;
+        let end = word(e + 4);
+        let offset = word(e + 8) | word(e + 10) << 16;
+        if offset >= data.len() {
+            return Err(format!("entry {} is outside the file", i + 1));
+        }
+
+        // Many archives carry a wrong end address, so never read past the
+        // end of the file
+        let len = end.wrapping_sub(start as usize).min(data.len() - offset);
+        let mut prg = vec![(start & 0xFF) as u8, (start >> 8) as u8];
+        prg.extend_from_slice(&data[offset..offset + len]);
+
+        let mut name = data[e + 0x10..e + 0x20].to_vec();
+        while name.last() == Some(&T64_NAME_PAD) || name.last() == Some(&NAME_PAD) {
+            name.pop();
+        }
+        entries.push(T64Entry {
+            name: name,
+            start: start,
+            data: prg,
+        });
+    }
+    Ok(entries)
+}
+
+// The C64 file name and PRG data of a P00 file
+pub fn read_p00(data: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    if !is_p00(data) {
+        return Err("not a P00 file".to_string());
+    }
+    let name: Vec<u8> = data[8..24]
+        .iter()
+        .cloned()
+        .take_while(|&c| c != 0)
+        .collect();
+    Ok((name, data[P00_HEADER_SIZE..].to_vec()))
+}
+
+// Split an entry selection ("file.t64:2") off a file name
+pub fn split_entry(filename: &str) -> (&str, Option<usize>) {
+    if let Some(ix) = filename.rfind(':') {
+        if let Ok(n) = filename[ix + 1..].parse::<usize>() {
+            return (&filename[..ix], Some(n));
+        }
+    }
+    (filename, None)
+}
+
+// Load a program in PRG form from a raw PRG, P00 or T64 file. A T64 entry
+// is chosen by number ("file.t64:2"), otherwise the first one is taken.
+pub fn load_program(filename: &str) -> Option<Vec<u8>> {
+    let (path, entry) = split_entry(filename);
+    let data = match load_file(path) {
+        Some(d) => d,
+        None => return None,
+    };
+
+    if is_p00(&data) {
+        return read_p00(&data).ok().map(|(_, prg)| prg);
+    }
+    if !is_t64(&data) {
+        return Some(data);
+    }
+
+    let mut entries = match read_t64(&data) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("Unable to read {}: {}", path, e);
+            return None;
+        }
+    };
+    if entries.len() > 1 && entry.is_none() {
+        for (i, e) in entries.iter().enumerate() {
+            println!(
+                "{:>3}: \"{}\" ${:04X}",
+                i + 1,
+                String::from_utf8_lossy(&e.name),
+                e.start
+            );
+        }
+        println!("Loading entry 1 (use {}:N to choose another)", path);
+    }
+    let ix = entry.unwrap_or(1);
+    if ix < 1 || ix > entries.len() {
+        println!("No entry {} in {}", ix, path);
+        return None;
+    }
+    Some(entries.swap_remove(ix - 1).data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A T64 archive with a directory slot per entry (an empty name leaves
+    // the slot unused) and the entries' offsets counted from the payload
+    fn t64(entries: &[(&[u8], u16, u16, usize)], payload: &[u8]) -> Vec<u8> {
+        let mut data = b"C64 tape image file".to_vec();
+        data.resize(T64_HEADER_SIZE, 0);
+        data[0x22] = entries.len() as u8;
+        data[0x24] = entries.len() as u8;
+        let base = T64_HEADER_SIZE + entries.len() * T64_ENTRY_SIZE;
+        for &(name, start, end, offset) in entries.iter() {
+            let mut e = vec![0; T64_ENTRY_SIZE];
+            if !name.is_empty() {
+                let offset = base + offset;
+                e[0] = 1;
+                e[1] = 0x82;
+                e[2..4].copy_from_slice(&[start as u8, (start >> 8) as u8]);
+                e[4..6].copy_from_slice(&[end as u8, (end >> 8) as u8]);
+                e[8..12].copy_from_slice(&[
+                    offset as u8,
+                    (offset >> 8) as u8,
+                    (offset >> 16) as u8,
+                    0,
+                ]);
+                for i in 0..16 {
+                    e[0x10 + i] = *name.get(i).unwrap_or(&T64_NAME_PAD);
+                }
+            }
+            data.extend_from_slice(&e);
+        }
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn t64_entries() {
+        let data = t64(
+            &[
+                (b"ONE", 0x0801, 0x0804, 0),
+                (b"", 0, 0, 0),
+                (b"TWO", 0xC000, 0xC002, 3),
+            ],
+            &[1, 2, 3, 4, 5],
+        );
+        assert!(is_t64(&data) && !is_p00(&data));
+        let entries = read_t64(&data).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, b"ONE");
+        assert_eq!(entries[0].data, vec![0x01, 0x08, 1, 2, 3]);
+        assert_eq!(entries[1].name, b"TWO");
+        assert_eq!(entries[1].start, 0xC000);
+        assert_eq!(entries[1].data, vec![0x00, 0xC0, 4, 5]);
+    }
+
+    #[test]
+    fn t64_bad_entries() {
+        // An end address past the end of the file is clamped to it
+        let data = t64(&[(b"BIG", 0x0801, 0xFFFF, 0)], &[1, 2, 3]);
+        let entries = read_t64(&data).unwrap();
+        assert_eq!(entries[0].data, vec![0x01, 0x08, 1, 2, 3]);
+
+        let data = t64(&[(b"GONE", 0x0801, 0x0810, 100)], &[1, 2, 3]);
+        assert!(read_t64(&data).is_err());
+    }
+
+    #[test]
+    fn p00() {
+        let mut data = P00_SIGNATURE.to_vec();
+        let mut name = b"HELLO".to_vec();
+        name.resize(16, 0);
+        data.extend_from_slice(&name);
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&[0x01, 0x08, 0xA9, 0x00]);
+
+        assert!(is_p00(&data) && !is_t64(&data));
+        let (name, prg) = read_p00(&data).unwrap();
+        assert_eq!(name, b"HELLO");
+        assert_eq!(prg, vec![0x01, 0x08, 0xA9, 0x00]);
+    }
+
+    #[test]
+    fn entry_numbers() {
+        assert_eq!(split_entry("game.t64:2"), ("game.t64", Some(2)));
+        assert_eq!(split_entry("game.t64"), ("game.t64", None));
+        assert_eq!(split_entry("c:game.t64"), ("c:game.t64", None));
+    }
+}
@@ -10,7 +10,51 @@ pub struct CPU {
     st: u8,
     sp: u8,
     brk: bool,
-    brkpt: u16,
+    calls: Vec<Call>,
+}
+
+// The stack page holds at most this many return addresses
+const MAX_CALLS: usize = 128;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CallKind {
+    Jsr,
+    Irq,
+    Brk,
+}
+
+impl CallKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CallKind::Jsr => "JSR",
+            CallKind::Irq => "IRQ",
+            CallKind::Brk => "BRK",
+        }
+    }
+}
+
+// An entry on the shadow call stack, kept for the monitor's backtrace:
+// where a subroutine was called or an interrupt taken, where it went and
+// where it returns to. sp is the stack pointer after the return address
+// (and for interrupts the status) was pushed.
+#[derive(Clone, Copy)]
+pub struct Call {
+    pub kind: CallKind,
+    pub from: u16,
+    pub target: u16,
+    pub ret: u16,
+    pub sp: u8,
+}
+
+impl Call {
+    // The address of the return address on the stack, and its value as
+    // pushed (JSR pushes the return address less one)
+    pub fn stacked_return(&self) -> (u16, u16) {
+        match self.kind {
+            CallKind::Jsr => (0x101 + self.sp as u16, self.ret.wrapping_sub(1)),
+            _ => (0x102 + self.sp as u16, self.ret),
+        }
+    }
 }
 
 pub enum Flags {
@@ -39,11 +83,11 @@ impl CPU {
             st: 0x20,
             sp: 0xFF,
             brk: false,
-            brkpt: 0xFFFF,
+            calls: Vec::new(),
         }
     }
 
-    pub fn get_pc(&mut self) -> u16 {
+    pub fn get_pc(&self) -> u16 {
         self.pc
     }
 
@@ -51,6 +95,46 @@ impl CPU {
         self.pc = pc;
     }
 
+    pub fn get_a(&self) -> u8 {
+        self.a
+    }
+
+    pub fn set_a(&mut self, a: u8) {
+        self.a = a;
+    }
+
+    pub fn get_x(&self) -> u8 {
+        self.x
+    }
+
+    pub fn set_x(&mut self, x: u8) {
+        self.x = x;
+    }
+
+    pub fn get_y(&self) -> u8 {
+        self.y
+    }
+
+    pub fn set_y(&mut self, y: u8) {
+        self.y = y;
+    }
+
+    pub fn get_sp(&self) -> u8 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: u8) {
+        self.sp = sp;
+    }
+
+    pub fn get_st(&self) -> u8 {
+        self.st
+    }
+
+    pub fn set_st(&mut self, st: u8) {
+        self.st = st;
+    }
+
     pub fn get_flag(&self, f: Flags) -> bool {
         (self.st & f as u8) > 0
     }
@@ -62,14 +146,27 @@ impl CPU {
         }
     }
 
-    pub fn set_breakpoint(&mut self, addr: u16) {
-        self.brkpt = addr;
+    // The shadow call stack, outermost call first
+    pub fn calls(&self) -> &[Call] {
+        &self.calls
+    }
+
+    // Leave a trapped KERNAL routine as if it had executed an RTS
+    pub fn return_from_trap(&mut self, mem: &mut MemIO) {
+        self.do_rts(mem)
     }
 
     pub fn trigger_irq(&mut self, mem: &mut MemIO) {
         self.do_irq(mem)
     }
 
+    pub fn reset(&mut self, mem: &mut MemIO) {
+        self.sp = 0xFF;
+        self.calls.clear();
+        self.set_flag(Flags::Interrupt, true);
+        self.pc = mem.read_word(0xFFFC);
+    }
+
     pub fn single_step(&mut self, mem: &mut MemIO, cycles: &mut u8) -> Result<bool, String> {
         let b = mem.read_byte(self.pc);
         let opcode = match get_opcode(b) {
@@ -154,7 +251,7 @@ impl CPU {
         let dobreak = self.brk;
         self.brk = false;
 
-        Ok(dobreak || (self.brkpt == self.pc))
+        Ok(dobreak)
     }
 
     fn fetch_operand(&self, mem: &mut MemIO, opcode: &Opcode) -> Operand {
@@ -223,6 +320,35 @@ impl CPU {
         Operand::Value(mem.read_byte(self.pc + 1))
     }
 
+    // Record a call once its return address is pushed and the PC set.
+    // Calls at or below the stack pointer are left over from code that
+    // dropped return addresses or reset the stack, so they go.
+    fn enter_call(&mut self, kind: CallKind, from: u16, ret: u16) {
+        let sp = self.sp;
+        while self.calls.last().map_or(false, |c| c.sp <= sp) {
+            self.calls.pop();
+        }
+        if self.calls.len() == MAX_CALLS {
+            self.calls.remove(0);
+        }
+        self.calls.push(Call {
+            kind: kind,
+            from: from,
+            target: self.pc,
+            ret: ret,
+            sp: sp,
+        });
+    }
+
+    // After an RTS or RTI, drop the calls whose return address has been
+    // pulled off the stack
+    fn leave_call(&mut self) {
+        let sp = self.sp;
+        while self.calls.last().map_or(false, |c| c.sp < sp) {
+            self.calls.pop();
+        }
+    }
+
     fn push_byte(&mut self, mem: &mut MemIO, val: u8) {
         mem.write_byte(0x100 + (self.sp as u16), val);
         self.sp = (((self.sp as i16) - 1) & 0xFF) as u8;
@@ -388,6 +514,7 @@ impl CPU {
 
     fn do_brk(&mut self, mem: &mut MemIO) {
         // self.brk = true;
+        let from = self.pc - 1;
         self.pc = self.pc + 1;
         let retaddr = self.pc;
         self.push_word(mem, retaddr);
@@ -395,7 +522,8 @@ impl CPU {
         let status = self.st;
         self.push_byte(mem, status);
         self.set_flag(Flags::Interrupt, true);
-        self.pc = mem.read_word(0xFFFE)
+        self.pc = mem.read_word(0xFFFE);
+        self.enter_call(CallKind::Brk, from, retaddr)
     }
 
     fn do_irq(&mut self, mem: &mut MemIO) {
@@ -407,6 +535,7 @@ impl CPU {
             self.push_byte(mem, status);
             self.set_flag(Flags::Interrupt, true);
             self.pc = mem.read_word(0xFFFE);
+            self.enter_call(CallKind::Irq, retaddr, retaddr);
         }
     }
 
@@ -493,7 +622,9 @@ impl CPU {
     fn do_jsr(&mut self, mem: &mut MemIO, addr: u16) {
         let val = self.pc - 1;
         self.push_word(mem, val);
-        self.pc = addr
+        self.pc = addr;
+        let (from, ret) = (val - 2, val + 1);
+        self.enter_call(CallKind::Jsr, from, ret)
     }
 
     fn do_lda(&mut self, val: u8) {
@@ -593,11 +724,13 @@ impl CPU {
     fn do_rti(&mut self, mem: &mut MemIO) {
         self.st = self.pop_byte(mem) | 0x20;
         self.pc = self.pop_word(mem);
+        self.leave_call()
     }
 
     fn do_rts(&mut self, mem: &mut MemIO) {
         let addr = self.pop_word(mem) + 1;
-        self.pc = addr
+        self.pc = addr;
+        self.leave_call()
     }
 
     fn do_sbc(&mut self, val: u8) {
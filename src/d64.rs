@@ -0,0 +1,286 @@
+use diskimage::*;
+
+pub const SECTOR_SIZE: usize = 256;
+pub const DIR_TRACK: u8 = 18;
+
+const SECTORS_35: usize = 683;
+const SECTORS_40: usize = 768;
+
+// File types as stored in the directory entry (bits 0-2)
+pub const FILE_DEL: u8 = 0;
+pub const FILE_SEQ: u8 = 1;
+pub const FILE_PRG: u8 = 2;
+pub const FILE_USR: u8 = 3;
+pub const FILE_REL: u8 = 4;
+pub const FILE_CBM: u8 = 5;
+pub const FILE_CLOSED: u8 = 0x80;
+pub const FILE_LOCKED: u8 = 0x40;
+
+// Shifted space, used to pad names on disk
+pub const NAME_PAD: u8 = 0xA0;
+
+// BAM layout (track 18, sector 0)
+const BAM_ENTRIES: usize = 0x04;
+const BAM_DISK_NAME: usize = 0x90;
+const BAM_DISK_ID: usize = 0xA2;
+const BAM_DOS_TYPE: usize = 0xA5;
+const BAM_SPEEDDOS: usize = 0xC0; // BAM for tracks 36-40
+
+// Sector interleave used by the 1541 DOS for files and the directory
+const FILE_INTERLEAVE: u8 = 10;
+const DIR_INTERLEAVE: u8 = 3;
+
+pub fn sectors_per_track(track: u8) -> u8 {
+    match track {
+        1...17 => 21,
+        18...24 => 19,
+        25...30 => 18,
+        _ => 17,
+    }
+}
+
+#[derive(Clone)]
+pub struct DirEntry {
+    pub name: Vec<u8>, // PETSCII, without padding
+    pub file_type: u8,
+    pub track: u8,
+    pub sector: u8,
+    pub blocks: u16,
+    // Location of the entry itself (directory sector and offset)
+    pub dir_track: u8,
+    pub dir_sector: u8,
+    pub dir_offset: usize,
+}
+
+impl DirEntry {
+    pub fn type_name(&self) -> &'static str {
+        match self.file_type & 0x07 {
+            FILE_DEL => "DEL",
+            FILE_SEQ => "SEQ",
+            FILE_PRG => "PRG",
+            FILE_USR => "USR",
+            FILE_REL => "REL",
+            FILE_CBM => "CBM",
+            _ => "???",
+        }
+    }
+}
+
+// Match a PETSCII name against a pattern with '*' and '?' wildcards
+pub fn name_matches(pattern: &[u8], name: &[u8]) -> bool {
+    for (ix, &p) in pattern.iter().enumerate() {
+        match p {
+            b'*' => return true,
+            b'?' if ix < name.len() => {}
+            _ if ix < name.len() && name[ix] == p => {}
+            _ => return false,
+        }
+    }
+    pattern.len() == name.len()
+}
+
+pub struct D64 {
+    data: Vec<u8>,
+    errors: Option<Vec<u8>>,
+    tracks: u8,
+}
+
+impl D64 {
+    pub fn from_bytes(mut data: Vec<u8>) -> Result<D64, String> {
+        let (tracks, sectors) = match data.len() {
+            n if n == SECTORS_35 * SECTOR_SIZE || n == SECTORS_35 * (SECTOR_SIZE + 1) => {
+                (35, SECTORS_35)
+            }
+            n if n == SECTORS_40 * SECTOR_SIZE || n == SECTORS_40 * (SECTOR_SIZE + 1) => {
+                (40, SECTORS_40)
+            }
+            n => return Err(format!("not a D64 image ({} bytes)", n)),
+        };
+
+        let errors = if data.len() > sectors * SECTOR_SIZE {
+            Some(data.split_off(sectors * SECTOR_SIZE))
+        } else {
+            None
+        };
+
+        Ok(D64 {
+            data: data,
+            errors: errors,
+            tracks: tracks,
+        })
+    }
+
+    // An unformatted image, all sectors zero
+    pub fn blank(tracks: u8) -> D64 {
+        let sectors = if tracks > 35 { SECTORS_40 } else { SECTORS_35 };
+        D64 {
+            data: vec![0; sectors * SECTOR_SIZE],
+            errors: None,
+            tracks: if tracks > 35 { 40 } else { 35 },
+        }
+    }
+
+    // Create a freshly formatted image
+    pub fn new(name: &[u8], id: &[u8], tracks: u8) -> D64 {
+        let mut d64 = D64::blank(tracks);
+        d64.format(name, id);
+        d64
+    }
+
+    fn offset(&self, track: u8, sector: u8) -> Option<usize> {
+        if track < 1 || track > self.tracks || sector >= sectors_per_track(track) {
+            return None;
+        }
+        let index: usize = (1..track).map(|t| sectors_per_track(t) as usize).sum();
+        Some(index + sector as usize)
+    }
+
+    fn bam_entry(&self, track: u8) -> Option<usize> {
+        let base = self.offset(DIR_TRACK, 0).unwrap() * SECTOR_SIZE;
+        match track {
+            1...35 => Some(base + BAM_ENTRIES + 4 * (track as usize - 1)),
+            36...40 if self.tracks == 40 => Some(base + BAM_SPEEDDOS + 4 * (track as usize - 36)),
+            _ => None,
+        }
+    }
+}
+
+impl DiskImage for D64 {
+    fn tracks(&self) -> u8 {
+        self.tracks
+    }
+
+    fn sectors(&self, track: u8) -> u8 {
+        sectors_per_track(track)
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Option<&[u8]> {
+        self.offset(track, sector)
+            .map(|ix| &self.data[ix * SECTOR_SIZE..(ix + 1) * SECTOR_SIZE])
+    }
+
+    fn write_sector(&mut self, track: u8, sector: u8, buf: &[u8]) -> bool {
+        match self.offset(track, sector) {
+            Some(ix) => {
+                let len = buf.len().min(SECTOR_SIZE);
+                self.data[ix * SECTOR_SIZE..ix * SECTOR_SIZE + len].copy_from_slice(&buf[..len]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        if let Some(ref errors) = self.errors {
+            data.extend_from_slice(errors);
+        }
+        data
+    }
+
+    // Error code stored for a sector in the error info block
+    fn sector_error(&self, track: u8, sector: u8) -> u8 {
+        match (&self.errors, self.offset(track, sector)) {
+            (&Some(ref errors), Some(ix)) if errors[ix] != 0 => errors[ix],
+            _ => 1,
+        }
+    }
+
+    fn format(&mut self, name: &[u8], id: &[u8]) {
+        for b in self.data.iter_mut() {
+            *b = 0;
+        }
+        self.errors = None;
+
+        let mut bam = [0; SECTOR_SIZE];
+        bam[0] = DIR_TRACK;
+        bam[1] = 1;
+        bam[2] = b'A';
+        for i in 0..0x1B {
+            bam[BAM_DISK_NAME + i] = NAME_PAD;
+        }
+        for (i, &c) in name.iter().take(16).enumerate() {
+            bam[BAM_DISK_NAME + i] = c;
+        }
+        for (i, &c) in id.iter().take(2).enumerate() {
+            bam[BAM_DISK_ID + i] = c;
+        }
+        bam[BAM_DOS_TYPE] = b'2';
+        bam[BAM_DOS_TYPE + 1] = b'A';
+        self.write_sector(DIR_TRACK, 0, &bam);
+
+        for t in 1..self.tracks + 1 {
+            for s in 0..sectors_per_track(t) {
+                self.set_free(t, s, true);
+            }
+        }
+
+        // Empty first directory sector
+        let mut dir = [0; SECTOR_SIZE];
+        dir[1] = 0xFF;
+        self.write_sector(DIR_TRACK, 1, &dir);
+        self.set_free(DIR_TRACK, 0, false);
+        self.set_free(DIR_TRACK, 1, false);
+    }
+
+    // Disk name and id from the BAM, without padding
+    fn header(&self) -> (Vec<u8>, Vec<u8>) {
+        let bam = self.read_sector(DIR_TRACK, 0).unwrap();
+        let name = trim_name(&bam[BAM_DISK_NAME..BAM_DISK_NAME + 16]);
+        let id = bam[BAM_DISK_ID..BAM_DISK_ID + 5].to_vec(); // id, pad, dos type
+        (name, id)
+    }
+
+    fn dir_start(&self) -> (u8, u8) {
+        (DIR_TRACK, 1)
+    }
+
+    fn is_free(&self, track: u8, sector: u8) -> bool {
+        match self.bam_entry(track) {
+            Some(e) => (self.data[e + 1 + (sector as usize / 8)] & (1 << (sector % 8))) > 0,
+            None => false,
+        }
+    }
+
+    fn set_free(&mut self, track: u8, sector: u8, free: bool) {
+        if let Some(e) = self.bam_entry(track) {
+            if self.is_free(track, sector) == free {
+                return;
+            }
+            let byte = e + 1 + (sector as usize / 8);
+            if free {
+                self.data[byte] |= 1 << (sector % 8);
+                self.data[e] += 1;
+            } else {
+                self.data[byte] &= !(1 << (sector % 8));
+                self.data[e] -= 1;
+            }
+        }
+    }
+
+    // Outwards from the directory track, alternating sides
+    fn alloc_tracks(&self) -> Vec<u8> {
+        let mut order = Vec::new();
+        for d in 1..self.tracks {
+            if DIR_TRACK > d {
+                order.push(DIR_TRACK - d);
+            }
+            if DIR_TRACK + d <= self.tracks {
+                order.push(DIR_TRACK + d);
+            }
+        }
+        order
+    }
+
+    fn interleave(&self) -> (u8, u8) {
+        (FILE_INTERLEAVE, DIR_INTERLEAVE)
+    }
+}
+
+pub fn trim_name(name: &[u8]) -> Vec<u8> {
+    let end = name
+        .iter()
+        .position(|&c| c == NAME_PAD)
+        .unwrap_or(name.len());
+    name[..end].to_vec()
+}
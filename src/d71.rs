@@ -0,0 +1,221 @@
+// 1571 double sided images: a 1541 disk on each side, with the BAM of the
+// second side split between the directory track and track 53
+use d64::*;
+use diskimage::*;
+
+const SIDE_SECTORS: usize = 683;
+const D71_SECTORS: usize = SIDE_SECTORS * 2;
+const SIDE_TRACKS: u8 = 35;
+
+// Second side BAM: free counts on the directory track, bitmaps on track 53
+const BAM_TRACK: u8 = DIR_TRACK + SIDE_TRACKS;
+const BAM_DOUBLE_SIDED: usize = 0x03;
+const BAM_SIDE1_FREE: usize = 0xDD;
+
+const FILE_INTERLEAVE: u8 = 6;
+const DIR_INTERLEAVE: u8 = 3;
+
+pub fn is_d71(data: &[u8]) -> bool {
+    data.len() == D71_SECTORS * SECTOR_SIZE || data.len() == D71_SECTORS * (SECTOR_SIZE + 1)
+}
+
+pub struct D71 {
+    side0: D64,
+    side1: Vec<u8>,
+    errors1: Option<Vec<u8>>,
+}
+
+impl D71 {
+    pub fn from_bytes(mut data: Vec<u8>) -> Result<D71, String> {
+        if !is_d71(&data) {
+            return Err(format!("not a D71 image ({} bytes)", data.len()));
+        }
+        let errors = if data.len() > D71_SECTORS * SECTOR_SIZE {
+            Some(data.split_off(D71_SECTORS * SECTOR_SIZE))
+        } else {
+            None
+        };
+        let side1 = data.split_off(SIDE_SECTORS * SECTOR_SIZE);
+        let errors1 = match errors {
+            Some(mut e) => {
+                let e1 = e.split_off(SIDE_SECTORS);
+                data.extend(e);
+                Some(e1)
+            }
+            None => None,
+        };
+
+        Ok(D71 {
+            side0: try!(D64::from_bytes(data)),
+            side1: side1,
+            errors1: errors1,
+        })
+    }
+
+    pub fn new(name: &[u8], id: &[u8]) -> D71 {
+        let mut d71 = D71 {
+            side0: D64::blank(SIDE_TRACKS),
+            side1: vec![0; SIDE_SECTORS * SECTOR_SIZE],
+            errors1: None,
+        };
+        d71.format(name, id);
+        d71
+    }
+
+    // Sector index on the second side
+    fn offset1(&self, track: u8, sector: u8) -> Option<usize> {
+        if track <= SIDE_TRACKS || track > SIDE_TRACKS * 2 || sector >= self.sectors(track) {
+            return None;
+        }
+        let index: usize = (1..track - SIDE_TRACKS)
+            .map(|t| sectors_per_track(t) as usize)
+            .sum();
+        Some(index + sector as usize)
+    }
+
+    fn bam_bits(&self, track: u8) -> usize {
+        self.offset1(BAM_TRACK, 0).unwrap() * SECTOR_SIZE + 3 * (track - SIDE_TRACKS - 1) as usize
+    }
+
+    fn bam_count(&mut self, track: u8, delta: i8) {
+        let mut bam = self.side0.read_sector(DIR_TRACK, 0).unwrap().to_vec();
+        let ix = BAM_SIDE1_FREE + (track - SIDE_TRACKS - 1) as usize;
+        bam[ix] = (bam[ix] as i8 + delta) as u8;
+        self.side0.write_sector(DIR_TRACK, 0, &bam);
+    }
+}
+
+impl DiskImage for D71 {
+    fn tracks(&self) -> u8 {
+        SIDE_TRACKS * 2
+    }
+
+    fn sectors(&self, track: u8) -> u8 {
+        if track > SIDE_TRACKS {
+            sectors_per_track(track - SIDE_TRACKS)
+        } else {
+            sectors_per_track(track)
+        }
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Option<&[u8]> {
+        if track <= SIDE_TRACKS {
+            return self.side0.read_sector(track, sector);
+        }
+        self.offset1(track, sector)
+            .map(|ix| &self.side1[ix * SECTOR_SIZE..(ix + 1) * SECTOR_SIZE])
+    }
+
+    fn write_sector(&mut self, track: u8, sector: u8, buf: &[u8]) -> bool {
+        if track <= SIDE_TRACKS {
+            return self.side0.write_sector(track, sector, buf);
+        }
+        match self.offset1(track, sector) {
+            Some(ix) => {
+                let len = buf.len().min(SECTOR_SIZE);
+                self.side1[ix * SECTOR_SIZE..ix * SECTOR_SIZE + len].copy_from_slice(&buf[..len]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Both sides' sectors, then the error bytes of both
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.side0.to_bytes();
+        let errors0 = data.split_off(SIDE_SECTORS * SECTOR_SIZE);
+        data.extend_from_slice(&self.side1);
+        if let Some(ref errors1) = self.errors1 {
+            data.extend(errors0);
+            data.extend_from_slice(errors1);
+        }
+        data
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> u8 {
+        if track <= SIDE_TRACKS {
+            return self.side0.sector_error(track, sector);
+        }
+        match (&self.errors1, self.offset1(track, sector)) {
+            (&Some(ref errors), Some(ix)) if errors[ix] != 0 => errors[ix],
+            _ => 1,
+        }
+    }
+
+    fn format(&mut self, name: &[u8], id: &[u8]) {
+        self.side0.format(name, id);
+        for b in self.side1.iter_mut() {
+            *b = 0;
+        }
+        self.errors1 = None;
+
+        let mut bam = self.side0.read_sector(DIR_TRACK, 0).unwrap().to_vec();
+        bam[BAM_DOUBLE_SIDED] = 0x80;
+        self.side0.write_sector(DIR_TRACK, 0, &bam);
+        for t in SIDE_TRACKS + 1..SIDE_TRACKS * 2 + 1 {
+            if t != BAM_TRACK {
+                for s in 0..self.sectors(t) {
+                    self.set_free(t, s, true);
+                }
+            }
+        }
+    }
+
+    fn header(&self) -> (Vec<u8>, Vec<u8>) {
+        self.side0.header()
+    }
+
+    fn dir_start(&self) -> (u8, u8) {
+        self.side0.dir_start()
+    }
+
+    fn is_free(&self, track: u8, sector: u8) -> bool {
+        if track <= SIDE_TRACKS {
+            return self.side0.is_free(track, sector);
+        }
+        if sector >= self.sectors(track) || track > SIDE_TRACKS * 2 {
+            return false;
+        }
+        let e = self.bam_bits(track);
+        (self.side1[e + (sector as usize / 8)] & (1 << (sector % 8))) > 0
+    }
+
+    fn set_free(&mut self, track: u8, sector: u8, free: bool) {
+        if track <= SIDE_TRACKS {
+            return self.side0.set_free(track, sector, free);
+        }
+        if sector >= self.sectors(track) || track > SIDE_TRACKS * 2 {
+            return;
+        }
+        if self.is_free(track, sector) == free {
+            return;
+        }
+        let byte = self.bam_bits(track) + (sector as usize / 8);
+        if free {
+            self.side1[byte] |= 1 << (sector % 8);
+            self.bam_count(track, 1);
+        } else {
+            self.side1[byte] &= !(1 << (sector % 8));
+            self.bam_count(track, -1);
+        }
+    }
+
+    // The first side from the directory track out, then the second side
+    // from the BAM track out
+    fn alloc_tracks(&self) -> Vec<u8> {
+        let mut order = self.side0.alloc_tracks();
+        for d in 1..SIDE_TRACKS {
+            if BAM_TRACK - d > SIDE_TRACKS {
+                order.push(BAM_TRACK - d);
+            }
+            if BAM_TRACK + d <= SIDE_TRACKS * 2 {
+                order.push(BAM_TRACK + d);
+            }
+        }
+        order
+    }
+
+    fn interleave(&self) -> (u8, u8) {
+        (FILE_INTERLEAVE, DIR_INTERLEAVE)
+    }
+}
@@ -0,0 +1,308 @@
+// 1581 3.5" images: 80 tracks of 40 sectors, with the header, BAM and
+// directory on track 40. Partitions of whole tracks with their own header,
+// BAM and directory work as subdirectories.
+use d64::*;
+use diskimage::*;
+
+const D81_TRACKS: u8 = 80;
+const D81_SECTORS: u8 = 40;
+const D81_SIZE: usize = D81_TRACKS as usize * D81_SECTORS as usize;
+const HEADER_TRACK: u8 = 40;
+
+// Header sector (track 40, sector 0)
+const HEADER_DISK_NAME: usize = 0x04;
+const HEADER_DISK_ID: usize = 0x16;
+const HEADER_DOS_TYPE: usize = 0x19;
+
+// BAM sectors (sectors 1 and 2, tracks 1-40 and 41-80)
+const BAM_DISK_ID: usize = 0x04;
+const BAM_IO_BYTE: usize = 0x06;
+const BAM_ENTRIES: usize = 0x10;
+const BAM_ENTRY_SIZE: usize = 6;
+
+// Header, two BAM sectors and the first directory sector
+const SYSTEM_SECTORS: u8 = 4;
+const DIR_SECTOR: u8 = 3;
+
+pub fn is_d81(data: &[u8]) -> bool {
+    data.len() == D81_SIZE * SECTOR_SIZE || data.len() == D81_SIZE * (SECTOR_SIZE + 1)
+}
+
+pub struct D81 {
+    data: Vec<u8>,
+    errors: Option<Vec<u8>>,
+    // First and last track of the partitions entered, innermost last
+    partitions: Vec<(u8, u8)>,
+}
+
+impl D81 {
+    pub fn from_bytes(mut data: Vec<u8>) -> Result<D81, String> {
+        if !is_d81(&data) {
+            return Err(format!("not a D81 image ({} bytes)", data.len()));
+        }
+        let errors = if data.len() > D81_SIZE * SECTOR_SIZE {
+            Some(data.split_off(D81_SIZE * SECTOR_SIZE))
+        } else {
+            None
+        };
+        Ok(D81 {
+            data: data,
+            errors: errors,
+            partitions: Vec::new(),
+        })
+    }
+
+    pub fn new(name: &[u8], id: &[u8]) -> D81 {
+        let mut d81 = D81 {
+            data: vec![0; D81_SIZE * SECTOR_SIZE],
+            errors: None,
+            partitions: Vec::new(),
+        };
+        d81.format(name, id);
+        d81
+    }
+
+    fn offset(&self, track: u8, sector: u8) -> Option<usize> {
+        if track < 1 || track > D81_TRACKS || sector >= D81_SECTORS {
+            return None;
+        }
+        Some((track as usize - 1) * D81_SECTORS as usize + sector as usize)
+    }
+
+    // Track holding the header of the current directory
+    fn root(&self) -> u8 {
+        self.partitions.last().map_or(HEADER_TRACK, |p| p.0)
+    }
+
+    fn bam_entry(&self, track: u8) -> Option<usize> {
+        if track < 1 || track > D81_TRACKS {
+            return None;
+        }
+        let sector = if track <= 40 { 1 } else { 2 };
+        let base = self.offset(self.root(), sector).unwrap() * SECTOR_SIZE;
+        Some(base + BAM_ENTRIES + BAM_ENTRY_SIZE * ((track as usize - 1) % 40))
+    }
+
+    // Write the header, BAM and empty directory of a directory whose files
+    // live on tracks first to last
+    fn write_header(&mut self, track: u8, first: u8, last: u8, name: &[u8], id: &[u8]) {
+        let mut header = [0; SECTOR_SIZE];
+        header[0] = track;
+        header[1] = DIR_SECTOR;
+        header[2] = b'D';
+        for i in HEADER_DISK_NAME..HEADER_DOS_TYPE + 4 {
+            header[i] = NAME_PAD;
+        }
+        for (i, &c) in name.iter().take(16).enumerate() {
+            header[HEADER_DISK_NAME + i] = c;
+        }
+        for (i, &c) in id.iter().take(2).enumerate() {
+            header[HEADER_DISK_ID + i] = c;
+        }
+        header[HEADER_DOS_TYPE] = b'3';
+        header[HEADER_DOS_TYPE + 1] = b'D';
+        self.write_sector(track, 0, &header);
+
+        for sector in 1..3 {
+            let mut bam = [0; SECTOR_SIZE];
+            if sector == 1 {
+                bam[0] = track;
+                bam[1] = 2;
+            } else {
+                bam[1] = 0xFF;
+            }
+            bam[2] = b'D';
+            bam[3] = !b'D';
+            bam[BAM_DISK_ID] = header[HEADER_DISK_ID];
+            bam[BAM_DISK_ID + 1] = header[HEADER_DISK_ID + 1];
+            bam[BAM_IO_BYTE] = 0xC0;
+            self.write_sector(track, sector, &bam);
+        }
+        for t in first..last + 1 {
+            for s in 0..D81_SECTORS {
+                self.set_free(t, s, true);
+            }
+        }
+        for s in 0..SYSTEM_SECTORS {
+            self.set_free(track, s, false);
+        }
+
+        let mut dir = [0; SECTOR_SIZE];
+        dir[1] = 0xFF;
+        self.write_sector(track, DIR_SECTOR, &dir);
+    }
+}
+
+impl DiskImage for D81 {
+    fn tracks(&self) -> u8 {
+        D81_TRACKS
+    }
+
+    fn sectors(&self, _track: u8) -> u8 {
+        D81_SECTORS
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Option<&[u8]> {
+        self.offset(track, sector)
+            .map(|ix| &self.data[ix * SECTOR_SIZE..(ix + 1) * SECTOR_SIZE])
+    }
+
+    fn write_sector(&mut self, track: u8, sector: u8, buf: &[u8]) -> bool {
+        match self.offset(track, sector) {
+            Some(ix) => {
+                let len = buf.len().min(SECTOR_SIZE);
+                self.data[ix * SECTOR_SIZE..ix * SECTOR_SIZE + len].copy_from_slice(&buf[..len]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        if let Some(ref errors) = self.errors {
+            data.extend_from_slice(errors);
+        }
+        data
+    }
+
+    fn sector_error(&self, track: u8, sector: u8) -> u8 {
+        match (&self.errors, self.offset(track, sector)) {
+            (&Some(ref errors), Some(ix)) if errors[ix] != 0 => errors[ix],
+            _ => 1,
+        }
+    }
+
+    // Format the whole disk, or only the current partition
+    fn format(&mut self, name: &[u8], id: &[u8]) {
+        let (first, last) = self.partitions.last().cloned().unwrap_or((1, D81_TRACKS));
+        let from = self.offset(first, 0).unwrap() * SECTOR_SIZE;
+        let to = self.offset(last, D81_SECTORS - 1).unwrap() * SECTOR_SIZE + SECTOR_SIZE;
+        for b in self.data[from..to].iter_mut() {
+            *b = 0;
+        }
+        if self.partitions.is_empty() {
+            self.errors = None;
+        }
+        let root = self.root();
+        self.write_header(root, first, last, name, id);
+    }
+
+    fn header(&self) -> (Vec<u8>, Vec<u8>) {
+        let header = self.read_sector(self.root(), 0).unwrap();
+        let name = trim_name(&header[HEADER_DISK_NAME..HEADER_DISK_NAME + 16]);
+        let id = header[HEADER_DISK_ID..HEADER_DISK_ID + 5].to_vec(); // id, pad, dos type
+        (name, id)
+    }
+
+    fn dir_start(&self) -> (u8, u8) {
+        (self.root(), DIR_SECTOR)
+    }
+
+    fn is_free(&self, track: u8, sector: u8) -> bool {
+        match self.bam_entry(track) {
+            Some(e) if sector < D81_SECTORS => {
+                (self.data[e + 1 + (sector as usize / 8)] & (1 << (sector % 8))) > 0
+            }
+            _ => false,
+        }
+    }
+
+    fn set_free(&mut self, track: u8, sector: u8, free: bool) {
+        if sector >= D81_SECTORS || self.is_free(track, sector) == free {
+            return;
+        }
+        if let Some(e) = self.bam_entry(track) {
+            let byte = e + 1 + (sector as usize / 8);
+            if free {
+                self.data[byte] |= 1 << (sector % 8);
+                self.data[e] += 1;
+            } else {
+                self.data[byte] &= !(1 << (sector % 8));
+                self.data[e] -= 1;
+            }
+        }
+    }
+
+    // Outwards from the header track, and inside a partition only its own
+    // tracks after the header track
+    fn alloc_tracks(&self) -> Vec<u8> {
+        let root = self.root();
+        let (first, last) = self.partitions.last().cloned().unwrap_or((1, D81_TRACKS));
+        let mut order = Vec::new();
+        for d in 1..D81_TRACKS {
+            if root > d {
+                order.push(root - d);
+            }
+            if root + d <= D81_TRACKS {
+                order.push(root + d);
+            }
+        }
+        order.retain(|&t| t >= first && t <= last);
+        order
+    }
+
+    fn interleave(&self) -> (u8, u8) {
+        (1, 1)
+    }
+
+    fn change_dir(&mut self, name: &[u8]) -> Result<(), String> {
+        if name.is_empty() || name == b"/" {
+            self.partitions.clear();
+            return Ok(());
+        }
+        let e = match self
+            .directory()
+            .into_iter()
+            .find(|e| (e.file_type & 0x07) == FILE_CBM && name_matches(name, &e.name))
+        {
+            Some(e) => e,
+            None => return Err("file not found".to_string()),
+        };
+
+        // Only partitions of whole tracks can hold a directory
+        let tracks = e.blocks / D81_SECTORS as u16;
+        if e.sector != 0 || e.blocks % D81_SECTORS as u16 != 0 || tracks < 3 {
+            return Err("illegal partition".to_string());
+        }
+        let last = e.track as u16 + tracks - 1;
+        if last > D81_TRACKS as u16 {
+            return Err("illegal partition".to_string());
+        }
+        self.partitions.push((e.track, last as u8));
+        Ok(())
+    }
+
+    // Reserve whole tracks for a partition and format it as a subdirectory
+    fn create_partition(&mut self, name: &[u8], track: u8, blocks: u16) -> Result<(), String> {
+        if self.find(name).is_some() {
+            return Err("file exists".to_string());
+        }
+        let tracks = blocks / D81_SECTORS as u16;
+        if track < 1
+            || blocks % D81_SECTORS as u16 != 0
+            || tracks < 3
+            || track as u16 + tracks - 1 > D81_TRACKS as u16
+        {
+            return Err("illegal partition".to_string());
+        }
+        let last = (track as u16 + tracks - 1) as u8;
+        for t in track..last + 1 {
+            if (0..D81_SECTORS).any(|s| !self.is_free(t, s)) {
+                return Err("illegal partition".to_string());
+            }
+        }
+        for t in track..last + 1 {
+            for s in 0..D81_SECTORS {
+                self.set_free(t, s, false);
+            }
+        }
+        try!(self.add_entry(name, FILE_CBM, (track, 0), blocks as usize));
+
+        let id = self.header().1;
+        self.partitions.push((track, last));
+        self.write_header(track, track, last, name, &id[..2]);
+        self.partitions.pop();
+        Ok(())
+    }
+}
@@ -1,94 +1,100 @@
-use memory::*;
+use memc64::*;
 use opcodes::*;
+use symbols::*;
 
-pub fn disassemble_step(mem: &MemIO, mut ip: &mut u16) -> Result<String, String> {
-    let mut result = String::new();
+// Instructions that take an extra cycle when indexing crosses a page
+const PAGE_CROSS_READS: &'static [&'static str] = &[
+    "ADC", "AND", "CMP", "EOR", "LDA", "LDX", "LDY", "ORA", "SBC",
+];
 
-    let s = try!(parse_opcode(mem, &mut ip));
-    result.push_str(&s);
-
-    Ok(result)
-}
-
-pub fn parse_opcode(mem: &MemIO, ip: &mut u16) -> Result<String, String> {
-    let b = mem.read_byte(*ip as u16);
+// Disassemble the instruction at ip and move ip past it. The line shows
+// the address, raw bytes, instruction and cycle count; operands are shown
+// as labels where the symbol table has one. Memory is read without side
+// effects on I/O registers.
+pub fn disassemble_step(mem: &MemC64, ip: &mut u16, symbols: &Symbols) -> String {
+    let addr = *ip;
+    let b = mem.peek(MemView::Cpu, addr);
     let opcode = match get_opcode(b) {
         Some(op) => op,
         None => {
-            return Err(format!(
-                "Invalid opcode {:02X} (IP = {:04X})!",
-                b, *ip as usize
-            ))
+            *ip = addr.wrapping_add(1);
+            return format!("${:04X}  {:02X}        .BYTE ${:02X}", addr, b, b);
         }
     };
 
-    let addr = *ip;
-    *ip += 1;
+    let bytes: Vec<u8> = (0..opcode.bytes as u16)
+        .map(|i| mem.peek(MemView::Cpu, addr.wrapping_add(i)))
+        .collect();
+    *ip = addr.wrapping_add(opcode.bytes as u16);
+    let (operand, comment) = parse_operand(mem, opcode, &bytes, *ip, symbols);
+
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    let cycles = match opcode.mode {
+        Mode::Relative => format!("{}+", opcode.cycles),
+        Mode::AbsoluteX | Mode::AbsoluteY | Mode::IndirectY
+            if PAGE_CROSS_READS.contains(&opcode.name) =>
+        {
+            format!("{}*", opcode.cycles)
+        }
+        _ => format!("{}", opcode.cycles),
+    };
+    let line = format!(
+        "${:04X}  {:<8}  {:<24}  ",
+        addr,
+        hex.join(" "),
+        format!("{}{}", opcode.name, operand)
+    );
+    match comment {
+        Some(c) => format!("{}{:<2}  ; {}", line, cycles, c),
+        None => format!("{}{}", line, cycles),
+    }
+}
 
+fn label16(symbols: &Symbols, addr: u16) -> String {
+    match symbols.name_at(addr) {
+        Some(name) => name.to_string(),
+        None => format!("${:04X}", addr),
+    }
+}
+
+fn label8(symbols: &Symbols, addr: u8) -> String {
+    match symbols.name_at(addr as u16) {
+        Some(name) => name.to_string(),
+        None => format!("${:02X}", addr),
+    }
+}
+
+// The operand text, and a comment resolving an indirect jump
+fn parse_operand(
+    mem: &MemC64,
+    opcode: &Opcode,
+    bytes: &[u8],
+    next: u16,
+    symbols: &Symbols,
+) -> (String, Option<String>) {
+    let word = || (bytes[1] as u16) | ((bytes[2] as u16) << 8);
     let s = match opcode.mode {
         Mode::Implied | Mode::Accumulator => "".to_string(),
-        Mode::Immediate => {
-            *ip += 1;
-            format!(" #${:02X}", mem.read_byte(*ip as u16 - 1))
-        }
-        Mode::Absolute => {
-            *ip += 2;
-            format!(
-                " ${:02X}{:02X}",
-                mem.read_byte(*ip as u16 - 1),
-                mem.read_byte(*ip as u16 - 2)
-            )
-        }
-        Mode::AbsoluteX => {
-            *ip += 2;
-            format!(
-                " ${:02X}{:02X}, X",
-                mem.read_byte(*ip as u16 - 1),
-                mem.read_byte(*ip as u16 - 2)
-            )
-        }
-        Mode::AbsoluteY => {
-            *ip += 2;
-            format!(
-                " ${:02X}{:02X}, Y",
-                mem.read_byte(*ip as u16 - 1),
-                mem.read_byte(*ip as u16 - 2)
-            )
-        }
+        Mode::Immediate => format!(" #${:02X}", bytes[1]),
+        Mode::Absolute => format!(" {}", label16(symbols, word())),
+        Mode::AbsoluteX => format!(" {}, X", label16(symbols, word())),
+        Mode::AbsoluteY => format!(" {}, Y", label16(symbols, word())),
         Mode::Indirect => {
-            *ip += 2;
-            format!(
-                " (${:02X}{:02X})",
-                mem.read_byte(*ip as u16 - 1),
-                mem.read_byte(*ip as u16 - 2)
-            )
-        }
-        Mode::IndirectX => {
-            *ip += 1;
-            format!(" (${:02X}, X)", mem.read_byte(*ip as u16 - 1))
-        }
-        Mode::IndirectY => {
-            *ip += 1;
-            format!(" (${:02X}), Y", mem.read_byte(*ip as u16 - 1))
-        }
-        Mode::ZeroPage => {
-            *ip += 1;
-            format!(" ${:02X}", mem.read_byte(*ip as u16 - 1))
-        }
-        Mode::ZeroPageX => {
-            *ip += 1;
-            format!(" ${:02X}, X", mem.read_byte(*ip as u16 - 1))
-        }
-        Mode::ZeroPageY => {
-            *ip += 1;
-            format!(" ${:02X}, Y", mem.read_byte(*ip as u16 - 1))
+            let ptr = word();
+            let target = (mem.peek(MemView::Cpu, ptr) as u16)
+                | ((mem.peek(MemView::Cpu, ptr.wrapping_add(1)) as u16) << 8);
+            let comment = format!("-> {}", label16(symbols, target));
+            return (format!(" ({})", label16(symbols, ptr)), Some(comment));
         }
+        Mode::IndirectX => format!(" ({}, X)", label8(symbols, bytes[1])),
+        Mode::IndirectY => format!(" ({}), Y", label8(symbols, bytes[1])),
+        Mode::ZeroPage => format!(" {}", label8(symbols, bytes[1])),
+        Mode::ZeroPageX => format!(" {}, X", label8(symbols, bytes[1])),
+        Mode::ZeroPageY => format!(" {}, Y", label8(symbols, bytes[1])),
         Mode::Relative => {
-            *ip += 1;
-            format!(" ${:02X}", mem.read_byte(*ip as u16 - 1))
+            let target = next.wrapping_add(bytes[1] as i8 as u16);
+            format!(" {}", label16(symbols, target))
         }
     };
-
-    let result = format!("${:04X} {}{}", addr, opcode.name, s);
-    Ok(result)
+    (s, None)
 }
@@ -0,0 +1,639 @@
+// Disk images of the CBM drives. Each format describes its geometry and BAM
+// layout; the DOS file operations on top of them are shared.
+use d64::*;
+use d71::*;
+use d81::*;
+use gcr::*;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use utils::*;
+
+pub trait DiskImage {
+    fn tracks(&self) -> u8;
+    fn sectors(&self, track: u8) -> u8;
+    fn read_sector(&self, track: u8, sector: u8) -> Option<&[u8]>;
+    fn write_sector(&mut self, track: u8, sector: u8, buf: &[u8]) -> bool;
+    // Contents of the image file
+    fn to_bytes(&self) -> Vec<u8>;
+
+    // Error code stored for a sector (1 means OK)
+    fn sector_error(&self, _track: u8, _sector: u8) -> u8 {
+        1
+    }
+
+    fn format(&mut self, name: &[u8], id: &[u8]);
+    // Disk name, and id followed by the DOS type, without padding
+    fn header(&self) -> (Vec<u8>, Vec<u8>);
+    // First sector of the directory
+    fn dir_start(&self) -> (u8, u8);
+    fn is_free(&self, track: u8, sector: u8) -> bool;
+    fn set_free(&mut self, track: u8, sector: u8, free: bool);
+    // Tracks available for files, in the order the DOS fills them
+    fn alloc_tracks(&self) -> Vec<u8>;
+    // Sector interleave for files and for the directory
+    fn interleave(&self) -> (u8, u8);
+
+    // Enter a partition (1581 subdirectory), or go back to the root with an
+    // empty name
+    fn change_dir(&mut self, _name: &[u8]) -> Result<(), String> {
+        Err("not supported".to_string())
+    }
+
+    fn create_partition(&mut self, _name: &[u8], _track: u8, _blocks: u16) -> Result<(), String> {
+        Err("not supported".to_string())
+    }
+
+    fn save(&self, filename: &str) -> Result<(), String> {
+        let mut file = try!(File::create(filename).map_err(|e| e.to_string()));
+        file.write_all(&self.to_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn total_sectors(&self) -> usize {
+        (1..self.tracks() + 1)
+            .map(|t| self.sectors(t) as usize)
+            .sum()
+    }
+
+    fn blocks_free(&self) -> u16 {
+        self.alloc_tracks()
+            .iter()
+            .map(|&t| (0..self.sectors(t)).filter(|&s| self.is_free(t, s)).count() as u16)
+            .sum()
+    }
+
+    fn directory(&self) -> Vec<DirEntry> {
+        let mut entries = Vec::new();
+        let (mut t, mut s) = self.dir_start();
+        let mut visited = 0;
+
+        while t != 0 && visited < self.total_sectors() {
+            let sector = match self.read_sector(t, s) {
+                Some(sector) => sector.to_vec(),
+                None => break,
+            };
+            for e in 0..8 {
+                let off = e * 32;
+                let file_type = sector[off + 2];
+                if file_type == 0 {
+                    continue;
+                }
+                entries.push(DirEntry {
+                    name: trim_name(&sector[off + 5..off + 21]),
+                    file_type: file_type,
+                    track: sector[off + 3],
+                    sector: sector[off + 4],
+                    blocks: sector[off + 30] as u16 | ((sector[off + 31] as u16) << 8),
+                    dir_track: t,
+                    dir_sector: s,
+                    dir_offset: off,
+                });
+            }
+            t = sector[0];
+            s = sector[1];
+            visited += 1;
+        }
+        entries
+    }
+
+    fn find(&self, pattern: &[u8]) -> Option<DirEntry> {
+        self.directory()
+            .into_iter()
+            .find(|e| (e.file_type & FILE_CLOSED) > 0 && name_matches(pattern, &e.name))
+    }
+
+    // Follow a sector chain and return the data it holds
+    fn read_chain(&self, track: u8, sector: u8) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        let (mut t, mut s) = (track, sector);
+        let mut visited = 0;
+
+        while t != 0 {
+            if self.sector_error(t, s) != 1 {
+                return Err(format!("read error on track {} sector {}", t, s));
+            }
+            let buf = match self.read_sector(t, s) {
+                Some(b) => b,
+                None => return Err(format!("illegal track {} or sector {}", t, s)),
+            };
+            if buf[0] == 0 {
+                let last = (buf[1] as usize).max(1);
+                data.extend_from_slice(&buf[2..last + 1]);
+            } else {
+                data.extend_from_slice(&buf[2..]);
+            }
+            t = buf[0];
+            s = buf[1];
+
+            visited += 1;
+            if visited > self.total_sectors() {
+                return Err("circular sector chain".to_string());
+            }
+        }
+        Ok(data)
+    }
+
+    // Find a free sector, continuing on the track of the previous one
+    fn allocate(&mut self, near: Option<(u8, u8)>) -> Option<(u8, u8)> {
+        let mut order: Vec<u8> = Vec::new();
+        if let Some((t, _)) = near {
+            order.push(t);
+        }
+        order.extend(self.alloc_tracks());
+
+        for &t in order.iter() {
+            let n = self.sectors(t);
+            let start = match near {
+                Some((nt, ns)) if nt == t => (ns + self.interleave().0) % n,
+                _ => 0,
+            };
+            for i in 0..n {
+                let s = (start + i) % n;
+                if self.is_free(t, s) {
+                    self.set_free(t, s, false);
+                    return Some((t, s));
+                }
+            }
+        }
+        None
+    }
+
+    fn free_chain(&mut self, track: u8, sector: u8) {
+        let (mut t, mut s) = (track, sector);
+        let mut visited = 0;
+        while t != 0 && visited < self.total_sectors() {
+            let (nt, ns) = match self.read_sector(t, s) {
+                Some(b) => (b[0], b[1]),
+                None => break,
+            };
+            self.set_free(t, s, true);
+            t = nt;
+            s = ns;
+            visited += 1;
+        }
+    }
+
+    // Find an empty directory slot, extending the directory if needed
+    fn free_dir_slot(&mut self) -> Option<(u8, u8, usize)> {
+        let (mut t, mut s) = self.dir_start();
+        loop {
+            let sector = match self.read_sector(t, s) {
+                Some(b) => b.to_vec(),
+                None => return None,
+            };
+            for e in 0..8 {
+                if sector[e * 32 + 2] == 0 {
+                    return Some((t, s, e * 32));
+                }
+            }
+            if sector[0] == 0 {
+                break;
+            }
+            t = sector[0];
+            s = sector[1];
+        }
+
+        // Link in a new directory sector on the directory track
+        let dir_track = self.dir_start().0;
+        let n = self.sectors(dir_track);
+        for i in 0..n {
+            let ns = (s + self.interleave().1 + i) % n;
+            if self.is_free(dir_track, ns) {
+                self.set_free(dir_track, ns, false);
+                let mut prev = self.read_sector(t, s).unwrap().to_vec();
+                prev[0] = dir_track;
+                prev[1] = ns;
+                self.write_sector(t, s, &prev);
+                let mut empty = [0; SECTOR_SIZE];
+                empty[1] = 0xFF;
+                self.write_sector(dir_track, ns, &empty);
+                return Some((dir_track, ns, 0));
+            }
+        }
+        None
+    }
+
+    // Write a directory entry into a free slot
+    fn add_entry(
+        &mut self,
+        name: &[u8],
+        file_type: u8,
+        start: (u8, u8),
+        blocks: usize,
+    ) -> Result<(), String> {
+        let slot = match self.free_dir_slot() {
+            Some(slot) => slot,
+            None => return Err("directory full".to_string()),
+        };
+        self.write_entry(slot, name, file_type, start, blocks);
+        Ok(())
+    }
+
+    fn write_entry(
+        &mut self,
+        slot: (u8, u8, usize),
+        name: &[u8],
+        file_type: u8,
+        start: (u8, u8),
+        blocks: usize,
+    ) {
+        let (dt, ds, off) = slot;
+        let mut dir = self.read_sector(dt, ds).unwrap().to_vec();
+        for i in 2..32 {
+            dir[off + i] = 0;
+        }
+        dir[off + 2] = file_type | FILE_CLOSED;
+        dir[off + 3] = start.0;
+        dir[off + 4] = start.1;
+        for i in 0..16 {
+            dir[off + 5 + i] = *name.get(i).unwrap_or(&NAME_PAD);
+        }
+        dir[off + 30] = (blocks & 0xFF) as u8;
+        dir[off + 31] = (blocks >> 8) as u8;
+        self.write_sector(dt, ds, &dir);
+    }
+
+    // Write a file, replacing any existing file with the same name. The old
+    // file stays until the new one is known to fit, and its sectors are
+    // only reused when the new file needs them.
+    fn write_file(&mut self, name: &[u8], file_type: u8, data: &[u8]) -> Result<(), String> {
+        let old = self.find(name);
+        if old
+            .as_ref()
+            .map_or(false, |e| (e.file_type & FILE_LOCKED) > 0)
+        {
+            return Err("file locked".to_string());
+        }
+
+        let blocks = ((data.len() + 253) / 254).max(1);
+        let free = self.blocks_free() as usize;
+        let old_blocks = old.as_ref().map_or(0, |e| e.blocks as usize);
+        if blocks > free + old_blocks {
+            return Err("disk full".to_string());
+        }
+        let mut old_freed = false;
+        if blocks > free {
+            if let Some(ref e) = old {
+                self.free_file(e);
+                old_freed = true;
+            }
+        }
+
+        let mut chain = Vec::new();
+        let mut prev = None;
+        for _ in 0..blocks {
+            match self.allocate(prev) {
+                Some(ts) => {
+                    chain.push(ts);
+                    prev = Some(ts);
+                }
+                None => return Err("disk full".to_string()),
+            }
+        }
+
+        for (ix, &(t, s)) in chain.iter().enumerate() {
+            let mut buf = [0; SECTOR_SIZE];
+            let chunk = &data[(ix * 254).min(data.len())..((ix + 1) * 254).min(data.len())];
+            buf[2..2 + chunk.len()].copy_from_slice(chunk);
+            if ix + 1 < chain.len() {
+                buf[0] = chain[ix + 1].0;
+                buf[1] = chain[ix + 1].1;
+            } else {
+                buf[0] = 0;
+                buf[1] = (chunk.len() + 1) as u8;
+            }
+            self.write_sector(t, s, &buf);
+        }
+
+        match old {
+            Some(e) => {
+                if !old_freed {
+                    self.free_file(&e);
+                }
+                let slot = (e.dir_track, e.dir_sector, e.dir_offset);
+                self.write_entry(slot, name, file_type, chain[0], blocks);
+                Ok(())
+            }
+            None => self.add_entry(name, file_type, chain[0], blocks),
+        }
+    }
+
+    // Release the sectors of a file in the BAM
+    fn free_file(&mut self, e: &DirEntry) {
+        if (e.file_type & 0x07) == FILE_CBM {
+            // A partition is a run of sectors, not a chain
+            let mut ts = (e.track, e.sector);
+            for _ in 0..e.blocks {
+                self.set_free(ts.0, ts.1, true);
+                ts.1 += 1;
+                if ts.1 >= self.sectors(ts.0) {
+                    ts = (ts.0 + 1, 0);
+                }
+            }
+        } else {
+            self.free_chain(e.track, e.sector);
+        }
+    }
+
+    // Scratch the files matching a pattern, returning how many went. As
+    // in DOS, locked files are skipped.
+    fn delete(&mut self, pattern: &[u8]) -> usize {
+        let matches: Vec<DirEntry> = self
+            .directory()
+            .into_iter()
+            .filter(|e| name_matches(pattern, &e.name) && (e.file_type & FILE_LOCKED) == 0)
+            .collect();
+
+        for e in matches.iter() {
+            self.free_file(e);
+            let mut dir = self
+                .read_sector(e.dir_track, e.dir_sector)
+                .unwrap()
+                .to_vec();
+            dir[e.dir_offset + 2] = 0;
+            self.write_sector(e.dir_track, e.dir_sector, &dir);
+        }
+        matches.len()
+    }
+
+    // Check the BAM against the sectors used by the files. Returns the
+    // problems found; with fix the BAM is corrected to match the files.
+    fn validate(&mut self, fix: bool) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut used = HashSet::new();
+        for e in self.directory() {
+            let name = String::from_utf8_lossy(&e.name).into_owned();
+            let mut sectors = Vec::new();
+            if (e.file_type & 0x07) == FILE_CBM {
+                let mut ts = (e.track, e.sector);
+                for _ in 0..e.blocks {
+                    sectors.push(ts);
+                    ts.1 += 1;
+                    if ts.1 >= self.sectors(ts.0) {
+                        ts = (ts.0 + 1, 0);
+                    }
+                }
+            } else {
+                let (mut t, mut s) = (e.track, e.sector);
+                while t != 0 {
+                    let next = match self.read_sector(t, s) {
+                        Some(b) => (b[0], b[1]),
+                        None => {
+                            problems.push(format!("{}: illegal track {} sector {}", name, t, s));
+                            break;
+                        }
+                    };
+                    if sectors.contains(&(t, s)) {
+                        problems.push(format!("{}: circular sector chain", name));
+                        break;
+                    }
+                    sectors.push((t, s));
+                    t = next.0;
+                    s = next.1;
+                }
+            }
+            for ts in sectors {
+                if !used.insert(ts) {
+                    problems.push(format!(
+                        "{}: track {} sector {} used twice",
+                        name, ts.0, ts.1
+                    ));
+                }
+            }
+        }
+
+        for t in self.alloc_tracks() {
+            for s in 0..self.sectors(t) {
+                let in_use = used.contains(&(t, s));
+                if in_use == !self.is_free(t, s) {
+                    continue;
+                }
+                problems.push(if in_use {
+                    format!("track {} sector {} is used but free in the BAM", t, s)
+                } else {
+                    format!("track {} sector {} is allocated but not used", t, s)
+                });
+                if fix {
+                    self.set_free(t, s, !in_use);
+                }
+            }
+        }
+        problems
+    }
+
+    fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<(), String> {
+        if self.find(new).is_some() {
+            return Err("file exists".to_string());
+        }
+        let e = match self.find(old) {
+            Some(e) => e,
+            None => return Err("file not found".to_string()),
+        };
+        let mut dir = self
+            .read_sector(e.dir_track, e.dir_sector)
+            .unwrap()
+            .to_vec();
+        for i in 0..16 {
+            dir[e.dir_offset + 5 + i] = *new.get(i).unwrap_or(&NAME_PAD);
+        }
+        self.write_sector(e.dir_track, e.dir_sector, &dir);
+        Ok(())
+    }
+}
+
+// Read a disk image of any supported format
+pub fn image_from_bytes(data: Vec<u8>) -> Result<Box<DiskImage>, String> {
+    if is_g64(&data) {
+        return G64::from_bytes(&data).map(|d| Box::new(d) as Box<DiskImage>);
+    }
+    if is_d71(&data) {
+        return D71::from_bytes(data).map(|d| Box::new(d) as Box<DiskImage>);
+    }
+    if is_d81(&data) {
+        return D81::from_bytes(data).map(|d| Box::new(d) as Box<DiskImage>);
+    }
+    D64::from_bytes(data).map(|d| Box::new(d) as Box<DiskImage>)
+}
+
+pub fn load_image(filename: &str) -> Option<Box<DiskImage>> {
+    match load_file(filename).map(image_from_bytes) {
+        Some(Ok(image)) => Some(image),
+        Some(Err(e)) => {
+            println!("Unable to read {}: {}", filename, e);
+            None
+        }
+        None => None,
+    }
+}
+
+// A freshly formatted image, its format chosen by the file extension
+pub fn new_image(filename: &str, name: &[u8], id: &[u8]) -> Box<DiskImage> {
+    let ext = Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "g64" => Box::new(G64::new(name, id)),
+        "d71" => Box::new(D71::new(name, id)),
+        "d81" => Box::new(D81::new(name, id)),
+        _ => Box::new(D64::new(name, id, 35)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed))
+            .collect()
+    }
+
+    // Files written survive saving and reading the image again (entering
+    // the partition dir, if any), and an unchanged image saves back to the
+    // same bytes
+    fn round_trip(
+        mut image: Box<DiskImage>,
+        dir: &[u8],
+        files: &[(&[u8], usize)],
+    ) -> Box<DiskImage> {
+        for (ix, &(name, len)) in files.iter().enumerate() {
+            image
+                .write_file(name, FILE_PRG, &test_data(len, ix as u8))
+                .unwrap();
+        }
+        let bytes = image.to_bytes();
+        let mut reread = image_from_bytes(bytes.clone()).unwrap();
+        assert!(reread.to_bytes() == bytes);
+        if !dir.is_empty() {
+            reread.change_dir(dir).unwrap();
+        }
+        assert_eq!(reread.header(), image.header());
+        assert_eq!(reread.blocks_free(), image.blocks_free());
+
+        for (ix, &(name, len)) in files.iter().enumerate() {
+            let e = reread.find(name).unwrap();
+            assert_eq!(e.blocks as usize, ((len + 253) / 254).max(1));
+            assert!(reread.read_chain(e.track, e.sector).unwrap() == test_data(len, ix as u8));
+        }
+        reread
+    }
+
+    #[test]
+    fn d64_round_trip() {
+        let image = Box::new(D64::new(b"TEST DISK", b"T1", 35));
+        assert_eq!(image.blocks_free(), 664);
+        round_trip(
+            image,
+            b"",
+            &[(b"FIRST", 1000), (b"SECOND", 20000), (b"EMPTY", 0)],
+        );
+
+        // Error bytes are kept
+        let mut data = D64::new(b"ERRORS", b"E1", 40).to_bytes();
+        data.extend(vec![1; 768]);
+        data[768 * 256 + 5] = 5;
+        let image = image_from_bytes(data.clone()).unwrap();
+        assert_eq!(image.sector_error(1, 5), 5);
+        assert!(image.to_bytes() == data);
+    }
+
+    #[test]
+    fn replace_file() {
+        let mut image: Box<DiskImage> = Box::new(D64::new(b"REPLACE", b"R1", 35));
+        image
+            .write_file(b"BIG", FILE_PRG, &test_data(600 * 254, 1))
+            .unwrap();
+        assert_eq!(image.blocks_free(), 64);
+
+        // Only fits in the space of the file it replaces
+        image
+            .write_file(b"BIG", FILE_PRG, &test_data(650 * 254, 2))
+            .unwrap();
+        assert_eq!(image.blocks_free(), 14);
+        assert_eq!(image.directory().len(), 1);
+
+        // Doesn't fit at all, so the old file is kept
+        assert!(image
+            .write_file(b"BIG", FILE_PRG, &test_data(700 * 254, 3))
+            .is_err());
+        let e = image.find(b"BIG").unwrap();
+        assert!(image.read_chain(e.track, e.sector).unwrap() == test_data(650 * 254, 2));
+        assert_eq!(image.blocks_free(), 14);
+        assert!(image.validate(false).is_empty());
+    }
+
+    #[test]
+    fn scratch_skips_locked() {
+        let mut image: Box<DiskImage> = Box::new(D64::new(b"SCRATCH", b"S1", 35));
+        for name in [&b"ONE"[..], b"OTHER", b"OPEN"].iter() {
+            image
+                .write_file(name, FILE_PRG, &test_data(300, 0))
+                .unwrap();
+        }
+        let e = image.find(b"OTHER").unwrap();
+        let mut dir = image
+            .read_sector(e.dir_track, e.dir_sector)
+            .unwrap()
+            .to_vec();
+        dir[e.dir_offset + 2] |= FILE_LOCKED;
+        image.write_sector(e.dir_track, e.dir_sector, &dir);
+
+        assert_eq!(image.delete(b"O*"), 2);
+        assert_eq!(image.directory().len(), 1);
+        assert!(image.find(b"OTHER").is_some());
+        assert!(image.validate(false).is_empty());
+    }
+
+    #[test]
+    fn d71_round_trip() {
+        let image = Box::new(D71::new(b"DOUBLE", b"71"));
+        assert_eq!(image.blocks_free(), 1328);
+
+        // Big enough to spill onto the second side
+        let image = round_trip(image, b"", &[(b"BIG", 700 * 254), (b"SMALL", 300)]);
+        let e = image.find(b"SMALL").unwrap();
+        assert!(e.track > 35);
+        assert!(!image.is_free(53, 0));
+        assert_eq!(image.blocks_free(), 1328 - 702);
+    }
+
+    #[test]
+    fn d81_round_trip() {
+        let mut image: Box<DiskImage> = Box::new(D81::new(b"THREE FIVE", b"81"));
+        assert_eq!(image.blocks_free(), 3160);
+        image.create_partition(b"SUBDIR", 60, 120).unwrap();
+        assert_eq!(image.blocks_free(), 3040);
+        assert!(image.create_partition(b"ZERO", 0, 0).is_err());
+        assert!(image.create_partition(b"SHORT", 1, 80).is_err());
+        assert!(image.create_partition(b"PAST END", 79, 120).is_err());
+
+        let mut image = round_trip(image, b"", &[(b"ROOT FILE", 5000)]);
+        assert_eq!(image.find(b"SUBDIR").unwrap().type_name(), "CBM");
+
+        image.change_dir(b"SUBDIR").unwrap();
+        assert_eq!(image.header().0, b"SUBDIR".to_vec());
+        assert_eq!(image.blocks_free(), 80);
+        let mut image = round_trip(image, b"SUBDIR", &[(b"INNER", 3000)]);
+        let e = image.find(b"INNER").unwrap();
+        assert!(e.track >= 60 && e.track <= 62);
+        assert!(image.find(b"ROOT FILE").is_none());
+        assert!(image.validate(true).is_empty());
+        assert_eq!(image.blocks_free(), 80 - 12);
+
+        image.change_dir(b"").unwrap();
+        assert!(image.find(b"ROOT FILE").is_some());
+        assert!(image.validate(false).is_empty());
+        assert!(image.change_dir(b"ROOT FILE").is_err());
+    }
+
+    #[test]
+    fn g64_round_trip() {
+        let image = Box::new(G64::new(b"GCR DISK", b"G1"));
+        let image = round_trip(image, b"", &[(b"GCR FILE", 4000)]);
+        assert_eq!(image.tracks(), 35);
+    }
+}
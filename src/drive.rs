@@ -0,0 +1,398 @@
+use d64::*;
+use diskimage::*;
+use serial::*;
+use std::collections::HashMap;
+
+pub const COMMAND_CHANNEL: u8 = 15;
+
+// Load address of a directory listing (start of BASIC on a PET, relocated
+// by the KERNAL on the C64)
+const LISTING_LOAD_ADDR: u16 = 0x0401;
+
+// A file name sent with OPEN, split into its parts:
+// [@][drive:]name[,type[,mode]]
+pub struct FileSpec {
+    pub name: Vec<u8>,
+    pub replace: bool,
+    pub file_type: Option<u8>,
+    pub write: bool,
+}
+
+impl FileSpec {
+    pub fn parse(raw: &[u8], sa: u8) -> FileSpec {
+        let mut name = raw;
+        let mut replace = false;
+        if name.first() == Some(&b'@') {
+            replace = true;
+            name = &name[1..];
+        }
+        if let Some(colon) = name.iter().position(|&c| c == b':') {
+            name = &name[colon + 1..];
+        }
+
+        let mut parts = name.split(|&c| c == b',');
+        let name = parts.next().unwrap_or(&[]).to_vec();
+        let mut file_type = None;
+        let mut write = sa == 1;
+        for part in parts {
+            match part.first() {
+                Some(&b'S') => file_type = Some(FILE_SEQ),
+                Some(&b'P') => file_type = Some(FILE_PRG),
+                Some(&b'U') => file_type = Some(FILE_USR),
+                Some(&b'W') | Some(&b'A') => write = true,
+                Some(&b'R') => write = false,
+                _ => {}
+            }
+        }
+
+        FileSpec {
+            name: name,
+            replace: replace,
+            file_type: file_type,
+            write: write,
+        }
+    }
+}
+
+pub struct ListingEntry {
+    pub name: Vec<u8>,
+    pub type_name: &'static str,
+    pub blocks: u16,
+    pub closed: bool,
+    pub locked: bool,
+}
+
+// Build a directory listing as a BASIC program, the way the DOS returns it
+// for LOAD"$",8
+pub fn basic_listing(header: &[u8], id: &[u8], entries: &[ListingEntry], free: u16) -> Vec<u8> {
+    let mut lines: Vec<(u16, Vec<u8>)> = Vec::new();
+
+    let mut text = vec![0x12, b'"'];
+    for i in 0..16 {
+        text.push(*header.get(i).unwrap_or(&b' '));
+    }
+    text.push(b'"');
+    text.push(b' ');
+    text.extend(id.iter().map(|&c| if c == NAME_PAD { b' ' } else { c }));
+    lines.push((0, text));
+
+    for e in entries.iter() {
+        let mut text = Vec::new();
+        let pad = match e.blocks {
+            0...9 => 3,
+            10...99 => 2,
+            _ => 1,
+        };
+        text.extend(vec![b' '; pad]);
+        text.push(b'"');
+        text.extend(e.name.iter());
+        text.push(b'"');
+        text.extend(vec![b' '; 16 - e.name.len().min(16)]);
+        text.push(if e.closed { b' ' } else { b'*' });
+        text.extend(e.type_name.bytes());
+        text.push(if e.locked { b'<' } else { b' ' });
+        lines.push((e.blocks, text));
+    }
+
+    let mut text = b"BLOCKS FREE.".to_vec();
+    text.extend(vec![b' '; 13]);
+    lines.push((free, text));
+
+    let mut prg = vec![
+        (LISTING_LOAD_ADDR & 0xFF) as u8,
+        (LISTING_LOAD_ADDR >> 8) as u8,
+    ];
+    let mut addr = LISTING_LOAD_ADDR;
+    for (number, text) in lines {
+        addr += 4 + text.len() as u16 + 1;
+        prg.push((addr & 0xFF) as u8);
+        prg.push((addr >> 8) as u8);
+        prg.push((number & 0xFF) as u8);
+        prg.push((number >> 8) as u8);
+        prg.extend(text);
+        prg.push(0);
+    }
+    prg.push(0);
+    prg.push(0);
+    prg
+}
+
+// Error channel of a drive: "NN, MESSAGE,TT,SS" read from channel 15
+pub struct DosStatus {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl DosStatus {
+    pub fn new() -> DosStatus {
+        let mut status = DosStatus {
+            data: Vec::new(),
+            pos: 0,
+        };
+        status.set(73, "CBM DOS V2.6 1541", 0, 0);
+        status
+    }
+
+    pub fn set(&mut self, code: u8, msg: &str, track: u8, sector: u8) {
+        self.data = format!("{:02},{},{:02},{:02}\r", code, msg, track, sector).into_bytes();
+        self.pos = 0;
+    }
+
+    pub fn ok(&mut self) {
+        self.set(0, " OK", 0, 0);
+    }
+
+    // Reading the whole message resets it to OK
+    pub fn read(&mut self) -> (u8, bool) {
+        let val = self.data[self.pos];
+        self.pos += 1;
+        let eoi = self.pos >= self.data.len();
+        if eoi {
+            self.ok();
+        }
+        (val, eoi)
+    }
+}
+
+// An open file: data to be read, or collected for writing on close
+pub struct Channel {
+    pub data: Vec<u8>,
+    pub pos: usize,
+    pub write: Option<FileSpec>,
+}
+
+impl Channel {
+    pub fn reader(data: Vec<u8>) -> Channel {
+        Channel {
+            data: data,
+            pos: 0,
+            write: None,
+        }
+    }
+
+    pub fn writer(spec: FileSpec) -> Channel {
+        Channel {
+            data: Vec::new(),
+            pos: 0,
+            write: Some(spec),
+        }
+    }
+
+    pub fn write(&mut self, val: u8) {
+        if self.write.is_some() {
+            self.data.push(val);
+        }
+    }
+
+    pub fn read(&mut self) -> Option<(u8, bool)> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+        let val = self.data[self.pos];
+        self.pos += 1;
+        Some((val, self.pos == self.data.len()))
+    }
+}
+
+// Drive serving a disk image over the serial bus at the DOS level
+pub struct VirtualDrive {
+    image: Box<DiskImage>,
+    path: String,
+    channels: HashMap<u8, Channel>,
+    command: Vec<u8>,
+    status: DosStatus,
+}
+
+impl VirtualDrive {
+    pub fn new(image: Box<DiskImage>, path: &str) -> VirtualDrive {
+        VirtualDrive {
+            image: image,
+            path: path.to_string(),
+            channels: HashMap::new(),
+            command: Vec::new(),
+            status: DosStatus::new(),
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.image.save(&self.path) {
+            println!("Unable to write {}: {}", self.path, e);
+        }
+    }
+
+    fn listing(&self) -> Vec<u8> {
+        let image = &*self.image;
+        let (header, id) = image.header();
+        let entries: Vec<ListingEntry> = image
+            .directory()
+            .iter()
+            .map(|e| ListingEntry {
+                name: e.name.clone(),
+                type_name: e.type_name(),
+                blocks: e.blocks,
+                closed: (e.file_type & FILE_CLOSED) > 0,
+                locked: (e.file_type & FILE_LOCKED) > 0,
+            })
+            .collect();
+        basic_listing(&header, &id, &entries, image.blocks_free())
+    }
+
+    fn execute(&mut self, cmd: &[u8]) {
+        let cmd: Vec<u8> = cmd.iter().cloned().filter(|&c| c != b'\r').collect();
+        if cmd.is_empty() {
+            return;
+        }
+
+        let arg = match cmd.iter().position(|&c| c == b':') {
+            Some(ix) => cmd[ix + 1..].to_vec(),
+            None => Vec::new(),
+        };
+        let result = match cmd[0] {
+            b'I' => Ok(false),
+            b'V' => Ok(!self.image.validate(true).is_empty()),
+            b'S' => {
+                let mut count = 0;
+                for pattern in arg.split(|&c| c == b',') {
+                    count += self.image.delete(pattern);
+                }
+                self.status.set(1, " FILES SCRATCHED", count as u8, 0);
+                self.flush();
+                return;
+            }
+            b'R' => match arg.iter().position(|&c| c == b'=') {
+                Some(eq) => self.image.rename(&arg[eq + 1..], &arg[..eq]).map(|_| true),
+                None => Err("syntax".to_string()),
+            },
+            b'N' => {
+                let mut parts = arg.split(|&c| c == b',');
+                let name = parts.next().unwrap_or(&[]).to_vec();
+                let id = match parts.next() {
+                    Some(id) => id.to_vec(),
+                    None => self.image.header().1[..2].to_vec(),
+                };
+                self.image.format(&name, &id);
+                Ok(true)
+            }
+            // 1581 partitions: "/:NAME" enters one, "/" goes back to the
+            // root and "/:NAME,<track><sector><size lo><size hi>,C" creates one
+            b'/' => {
+                let comma = arg.iter().position(|&c| c == b',').unwrap_or(arg.len());
+                let params = &arg[(comma + 1).min(arg.len())..];
+                if params.len() == 6 && params.ends_with(b",C") {
+                    let blocks = params[2] as u16 | (params[3] as u16) << 8;
+                    if params[1] != 0 {
+                        Err("illegal partition".to_string())
+                    } else {
+                        self.image
+                            .create_partition(&arg[..comma], params[0], blocks)
+                            .map(|_| true)
+                    }
+                } else {
+                    self.image.change_dir(&arg[..comma]).map(|_| false)
+                }
+            }
+            _ => Err("syntax".to_string()),
+        };
+
+        match result {
+            Ok(changed) => {
+                self.status.ok();
+                if changed {
+                    self.flush();
+                }
+            }
+            Err(ref e) if e == "syntax" => self.status.set(31, "SYNTAX ERROR", 0, 0),
+            Err(ref e) if e == "file exists" => self.status.set(63, "FILE EXISTS", 0, 0),
+            Err(ref e) if e == "illegal partition" => {
+                self.status.set(77, "SELECTED PARTITION ILLEGAL", 0, 0)
+            }
+            Err(ref e) if e == "not supported" => self.status.set(31, "SYNTAX ERROR", 0, 0),
+            Err(_) => self.status.set(62, "FILE NOT FOUND", 0, 0),
+        }
+    }
+}
+
+impl SerialDevice for VirtualDrive {
+    fn open(&mut self, sa: u8, name: &[u8]) {
+        if sa == COMMAND_CHANNEL {
+            return self.execute(name);
+        }
+
+        let spec = FileSpec::parse(name, sa);
+        if spec.write {
+            if !spec.replace && self.image.find(&spec.name).is_some() {
+                return self.status.set(63, "FILE EXISTS", 0, 0);
+            }
+            self.channels.insert(sa, Channel::writer(spec));
+            return self.status.ok();
+        }
+
+        let data = if spec.name == b"$" {
+            Some(self.listing())
+        } else {
+            match self.image.find(&spec.name) {
+                Some(e) => match self.image.read_chain(e.track, e.sector) {
+                    Ok(data) => Some(data),
+                    Err(_) => {
+                        self.status.set(23, "READ ERROR", e.track, e.sector);
+                        return;
+                    }
+                },
+                None => None,
+            }
+        };
+
+        match data {
+            Some(data) => {
+                self.channels.insert(sa, Channel::reader(data));
+                self.status.ok();
+            }
+            None => self.status.set(62, "FILE NOT FOUND", 0, 0),
+        }
+    }
+
+    fn close(&mut self, sa: u8) {
+        let channel = match self.channels.remove(&sa) {
+            Some(c) => c,
+            None => return,
+        };
+
+        if let Some(spec) = channel.write {
+            let file_type = spec
+                .file_type
+                .unwrap_or(if sa <= 1 { FILE_PRG } else { FILE_SEQ });
+            match self.image.write_file(&spec.name, file_type, &channel.data) {
+                Ok(()) => {
+                    self.status.ok();
+                    self.flush();
+                }
+                Err(_) => self.status.set(72, "DISK FULL", 0, 0),
+            }
+        }
+    }
+
+    fn write(&mut self, sa: u8, val: u8) {
+        if sa == COMMAND_CHANNEL {
+            self.command.push(val);
+        } else if let Some(channel) = self.channels.get_mut(&sa) {
+            channel.write(val);
+        }
+    }
+
+    fn unlisten(&mut self, sa: u8) {
+        if sa == COMMAND_CHANNEL {
+            let cmd = self.command.split_off(0);
+            self.execute(&cmd);
+        }
+    }
+
+    fn read(&mut self, sa: u8) -> Option<(u8, bool)> {
+        if sa == COMMAND_CHANNEL {
+            return Some(self.status.read());
+        }
+        match self.channels.get_mut(&sa) {
+            Some(channel) => channel.read(),
+            None => None,
+        }
+    }
+}
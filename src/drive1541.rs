@@ -0,0 +1,310 @@
+use cpu::*;
+use d64::*;
+use diskimage::*;
+use gcr::*;
+use iec::*;
+use memory::*;
+use std::fs::File;
+use std::io::prelude::*;
+use utils::*;
+use via::*;
+
+const DRIVE_RAM_SIZE: usize = 0x0800;
+const DRIVE_ROM_SIZE: usize = 0x4000;
+
+const VIA1_BASE: u16 = 0x1800;
+const VIA2_BASE: u16 = 0x1C00;
+
+// VIA1 port B: serial bus (inputs are inverted by the bus drivers)
+const IEC_DATA_IN: u8 = 0x01;
+const IEC_DATA_OUT: u8 = 0x02;
+const IEC_CLK_IN: u8 = 0x04;
+const IEC_CLK_OUT: u8 = 0x08;
+const IEC_ATN_ACK: u8 = 0x10;
+const IEC_DEVICE_SHIFT: u8 = 5;
+const IEC_ATN_IN: u8 = 0x80;
+
+// VIA2 port B: disk controller
+const DISK_STEPPER: u8 = 0x03;
+const DISK_MOTOR: u8 = 0x04;
+const DISK_WRITE_PROTECT: u8 = 0x10;
+const DISK_SYNC: u8 = 0x80;
+
+// Head starts over the directory track
+const START_HALFTRACK: usize = (DIR_TRACK as usize - 1) * 2;
+
+struct DriveMem {
+    ram: Vec<u8>,
+    rom: Vec<u8>,
+    via1: VIA,
+    via2: VIA,
+}
+
+impl MemIO for DriveMem {
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x17FF => self.ram[addr as usize % DRIVE_RAM_SIZE],
+            0x1800...0x1BFF => self.via1.read_register(addr - VIA1_BASE),
+            0x1C00...0x1FFF => self.via2.read_register(addr - VIA2_BASE),
+            0x8000...0xFFFF => self.rom[addr as usize % DRIVE_ROM_SIZE],
+            _ => (addr >> 8) as u8, // open bus
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        let lobyte = self.read_byte(addr) as u16;
+        let hibyte = self.read_byte(addr + 1) as u16;
+        (hibyte << 8) | lobyte
+    }
+
+    fn write_byte(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0x17FF => self.ram[addr as usize % DRIVE_RAM_SIZE] = val,
+            0x1800...0x1BFF => self.via1.write_register(addr - VIA1_BASE, val),
+            0x1C00...0x1FFF => self.via2.write_register(addr - VIA2_BASE, val),
+            _ => {}
+        }
+    }
+}
+
+// A 1541 floppy drive emulated at the hardware level: its own 6502 running
+// the DOS ROM, two VIAs for the serial bus and the disk controller, and a
+// GCR disk surface spinning under the head
+pub struct Drive1541 {
+    cpu: CPU,
+    mem: DriveMem,
+    device: u8,
+    disk: Option<GcrDisk>,
+    image: Option<D64>,
+    path: String,
+    dirty: bool,
+    halftrack: usize,
+    head: usize,
+    byte_clock: i32,
+    last_byte: u8,
+    motor: bool,
+    cycles: i32,
+    bus: IecLines,
+}
+
+impl Drive1541 {
+    pub fn new(rom: Vec<u8>, device: u8) -> Drive1541 {
+        let mut rom = rom;
+        rom.resize(DRIVE_ROM_SIZE, 0);
+        let mut drive = Drive1541 {
+            cpu: CPU::new(),
+            mem: DriveMem {
+                ram: vec![0; DRIVE_RAM_SIZE],
+                rom: rom,
+                via1: VIA::new(),
+                via2: VIA::new(),
+            },
+            device: device,
+            disk: None,
+            image: None,
+            path: String::new(),
+            dirty: false,
+            halftrack: START_HALFTRACK,
+            head: 0,
+            byte_clock: 0,
+            last_byte: 0,
+            motor: false,
+            cycles: 0,
+            bus: IecLines::released(),
+        };
+        drive.cpu.reset(&mut drive.mem);
+        drive
+    }
+
+    fn update_bus(&mut self) {
+        let atn = self.bus.atn;
+        let lines = self.bus.or(&self.lines(atn));
+        let mut pins = ((self.device - 8) & 0x03) << IEC_DEVICE_SHIFT;
+        if lines.data {
+            pins |= IEC_DATA_IN;
+        }
+        if lines.clk {
+            pins |= IEC_CLK_IN;
+        }
+        if atn {
+            pins |= IEC_ATN_IN;
+        }
+        self.mem.via1.set_pins_b(pins);
+        self.mem.via1.set_ca1(atn);
+    }
+
+    // Move the head when the stepper motor phase changes, and write back
+    // the disk when the spindle motor stops
+    fn update_head(&mut self) {
+        let pb = self.mem.via2.port_b();
+        let phase = (self.halftrack & 0x03) as u8;
+        match (pb & DISK_STEPPER).wrapping_sub(phase) & 0x03 {
+            1 if self.halftrack < HALFTRACKS - 1 => self.move_head(1),
+            3 if self.halftrack > 0 => self.move_head(-1),
+            _ => {}
+        }
+
+        let motor = (pb & DISK_MOTOR) > 0;
+        if self.motor && !motor {
+            self.flush();
+        }
+        self.motor = motor;
+    }
+
+    fn move_head(&mut self, step: isize) {
+        let old_len = self.track_len();
+        self.halftrack = (self.halftrack as isize + step) as usize;
+        let new_len = self.track_len();
+        self.head = if old_len > 0 {
+            self.head * new_len / old_len
+        } else {
+            0
+        };
+    }
+
+    fn track_len(&self) -> usize {
+        match self.disk {
+            Some(ref d) => d.track(self.halftrack).len(),
+            None => 0,
+        }
+    }
+
+    // Pass bytes under the head at the bit rate of the current track
+    fn rotate(&mut self, cycles: u8) {
+        let mut pins = DISK_WRITE_PROTECT | DISK_SYNC;
+        if self.motor && self.track_len() > 0 {
+            self.byte_clock -= cycles as i32;
+            while self.byte_clock <= 0 {
+                let zone = self.disk.as_ref().unwrap().speed(self.halftrack);
+                self.byte_clock += 32 - 2 * zone as i32;
+                self.next_byte();
+            }
+
+            // Two $FF bytes in a row are more than the ten 1 bits of a sync
+            let track = self.disk.as_ref().unwrap().track(self.halftrack);
+            let prev = track[(self.head + track.len() - 1) % track.len()];
+            if track[self.head] == 0xFF && prev == 0xFF {
+                pins &= !DISK_SYNC;
+            }
+        }
+        self.mem.via2.set_pins_b(pins);
+    }
+
+    fn next_byte(&mut self) {
+        // CB2 low puts the read/write head in write mode
+        let writing = self.mem.via2.cb2_output() == Some(false);
+        let out = self.mem.via2.port_a();
+        let halftrack = self.halftrack;
+        let track = self.disk.as_mut().unwrap().track_mut(halftrack);
+
+        self.head = (self.head + 1) % track.len();
+        let prev = self.last_byte;
+        if writing {
+            track[self.head] = out;
+            self.dirty = true;
+        }
+        self.last_byte = track[self.head];
+        self.mem.via2.set_pins_a(self.last_byte);
+
+        // No byte ready while inside a sync mark
+        if writing || !(self.last_byte == 0xFF && prev == 0xFF) {
+            self.mem.via2.set_flags(VIA_INT_CA1);
+            // CA2 enables the byte ready signal on the CPU's SO pin
+            if self.mem.via2.ca2_output() == Some(true) {
+                self.cpu.set_flag(Flags::Overflow, true);
+            }
+        }
+    }
+}
+
+impl IecDevice for Drive1541 {
+    // CLK and DATA as pulled low by the drive. DATA is also held low by the
+    // ATN acknowledge logic until the DOS answers ATN.
+    fn lines(&self, atn: bool) -> IecLines {
+        let pb = self.mem.via1.port_b();
+        IecLines {
+            atn: false,
+            clk: (pb & IEC_CLK_OUT) > 0,
+            data: (pb & IEC_DATA_OUT) > 0 || (atn != ((pb & IEC_ATN_ACK) > 0)),
+        }
+    }
+
+    // Run the drive for the given number of host cycles
+    fn clock(&mut self, others: &IecLines, cycles: u8) {
+        self.bus = *others;
+        self.cycles += cycles as i32;
+        while self.cycles > 0 {
+            self.update_bus();
+
+            let mut step_cycles: u8 = 0;
+            if let Err(e) = self.cpu.single_step(&mut self.mem, &mut step_cycles) {
+                println!("1541: {} - resetting drive", e);
+                self.cpu.reset(&mut self.mem);
+                step_cycles = 2;
+            }
+
+            self.mem.via1.clock(step_cycles);
+            self.mem.via2.clock(step_cycles);
+            self.update_head();
+            self.rotate(step_cycles);
+            if self.mem.via1.irq() || self.mem.via2.irq() {
+                self.cpu.trigger_irq(&mut self.mem);
+            }
+            self.cycles -= step_cycles as i32;
+        }
+    }
+
+    // Put a D64 or G64 image in the drive
+    fn insert(&mut self, filename: &str) -> bool {
+        self.eject();
+        let data = match load_file(filename) {
+            Some(d) => d,
+            None => return false,
+        };
+
+        let result = match GcrDisk::from_g64(&data) {
+            Ok(disk) => Ok((disk, None)),
+            Err(_) => D64::from_bytes(data).map(|d64| (GcrDisk::from_d64(&d64), Some(d64))),
+        };
+        match result {
+            Ok((disk, image)) => {
+                self.disk = Some(disk);
+                self.image = image;
+                self.path = filename.to_string();
+                true
+            }
+            Err(e) => {
+                println!("Unable to read {}: {}", filename, e);
+                false
+            }
+        }
+    }
+
+    fn eject(&mut self) {
+        self.flush();
+        self.disk = None;
+        self.image = None;
+    }
+
+    // Write changes made on the disk surface back to the image file
+    fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+
+        let disk = self.disk.as_ref().unwrap();
+        let result = match self.image {
+            Some(ref mut d64) => {
+                disk.decode_into(d64);
+                d64.save(&self.path)
+            }
+            None => File::create(&self.path)
+                .and_then(|mut f| f.write_all(&disk.to_g64()))
+                .map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
+            println!("Unable to write {}: {}", self.path, e);
+        }
+    }
+}
@@ -1,15 +1,36 @@
 use sdl2;
+use sdl2::clipboard::ClipboardUtil;
+use sdl2::event::Event;
 use sdl2::keyboard::Scancode;
 use sdl2::render::Renderer;
+use std::cmp::min;
 use std::fs;
 use std::io;
 use std::io::Write;
 use time::{Duration, PreciseTime};
 
+use asm::*;
+use autotype::*;
+use breakpoints::*;
+use container::*;
 use cpu::*;
 use dasm::*;
+use diskimage::*;
+use drive::*;
+use drive1541::*;
+use hostdrive::*;
+use keyboard::*;
 use memc64::*;
 use memory::*;
+use opcodes::*;
+use petscii::*;
+use ports::*;
+use printer::*;
+use serial::*;
+use sid::*;
+use sourcemap::*;
+use symbols::*;
+use tape::*;
 use utils::*;
 use vic::*;
 
@@ -20,17 +41,89 @@ pub const VIEWABLE_HEIGHT: u32 = 200;
 pub const WINDOW_WIDTH: u32 = VIEWABLE_WIDTH + (BORDER_WIDTH * 2);
 pub const WINDOW_HEIGHT: u32 = VIEWABLE_HEIGHT + (BORDER_HEIGHT * 2);
 
+// BASIC and KERNAL zero page pointers
+pub const BASIC_START: u16 = 0x002B;
+pub const BASIC_VARTAB: u16 = 0x002D;
+pub const BASIC_ARYTAB: u16 = 0x002F;
+pub const BASIC_STREND: u16 = 0x0031;
+pub const LOAD_END: u16 = 0x00AE;
+
+pub const PRINTER_DEVICE: u8 = 4;
+pub const DISK_DEVICE: u8 = 8;
+
+// KERNAL routine that waits for PLAY to be pressed before a tape load
+const KERNAL_PRESS_PLAY: u16 = 0xF817;
+
+const OPCODE_JSR: u8 = 0x20;
+const OPCODE_RTI: u8 = 0x40;
+const OPCODE_RTS: u8 = 0x60;
+
+// Instructions shown by the d command without an end address
+const DISASSEMBLE_LINES: usize = 20;
+
+// Source lines shown either side of the current one when breaking
+const SOURCE_CONTEXT: u32 = 2;
+
+// Watchpoint hits shown when breaking, the VIC can cause a lot at once
+const MAX_WATCH_HITS: usize = 10;
+
+pub struct Config {
+    pub sid_model: ChipModel,
+    pub digi_boost: bool,
+    pub port1: PortDevice,
+    pub port2: PortDevice,
+    pub joy_keys: [JoyKeys; 2],
+    pub keyboard: KeyboardMode,
+    pub keymap: Option<String>,
+    pub type_text: Option<String>,
+    pub type_mode: TypeMode,
+    pub autostart: Option<String>,
+    pub disk: Option<String>,
+    pub true_drive: bool,
+    pub host_dir: Option<String>,
+    pub host_device: u8,
+    pub tape: Option<String>,
+    pub printer: Option<String>,
+    pub symbols: Option<String>,
+}
+
+// Where a monitor command that runs the program stops
+enum RunTo {
+    // After this many instructions
+    Steps(u32),
+    // Back at the address after a JSR, with the stack no deeper
+    Return { pc: u16, sp: u8 },
+    // After an RTS or RTI that leaves the stack above this
+    Finish(u8),
+    Address(u16),
+    // At the start of a source line other than this one
+    Line(Option<(u32, u32)>),
+}
+
 pub struct C64 {
     cpu: CPU,
     vic: VIC,
+    sid: SID,
     mem: MemC64,
+    ports: ControlPorts,
+    symbolic: Option<SymbolicKeyboard>,
+    autotype: AutoType,
+    autostart: Option<String>,
+    serial: SerialTraps,
+    breakpoints: Breakpoints,
+    symbols: Symbols,
+    source: SourceMap,
+    run_to: Option<RunTo>,
+    true_drive: bool,
+    tape: Datasette,
+    clipboard: ClipboardUtil,
     sdl: sdl2::Sdl,
 }
 
 impl C64 {
-    pub fn new() -> C64 {
+    pub fn new(config: &Config) -> C64 {
         let cpu = CPU::new();
-        let mem = MemC64::new();
+        let mut mem = MemC64::new();
 
         let sdl = sdl2::init().unwrap();
         let video = sdl.video().unwrap();
@@ -44,14 +137,103 @@ impl C64 {
             .unwrap();
 
         let renderer: Renderer<'static> = window.renderer().build().unwrap();
+        let clipboard = video.clipboard();
         let vic = VIC::new(renderer, scale as u8);
 
-        C64 {
+        // Run without sound or game controllers where the host has none
+        let audio = match sdl.audio() {
+            Ok(a) => Some(a),
+            Err(e) => {
+                println!("Unable to initialize audio, SID output disabled: {}", e);
+                None
+            }
+        };
+        let sid = SID::new(audio.as_ref(), config.sid_model, config.digi_boost);
+
+        let gc = match sdl.game_controller() {
+            Ok(gc) => Some(gc),
+            Err(e) => {
+                println!("Unable to initialize game controllers: {}", e);
+                None
+            }
+        };
+        let ports = ControlPorts::new([config.port1, config.port2], config.joy_keys, gc.as_ref());
+
+        let symbolic = match (config.keyboard, &config.keymap) {
+            (KeyboardMode::Positional, _) => None,
+            (KeyboardMode::Symbolic, &Some(ref f)) => match SymbolicKeyboard::load(f) {
+                Some(k) => Some(k),
+                None => {
+                    println!("Using the built-in symbolic keymap");
+                    Some(SymbolicKeyboard::new())
+                }
+            },
+            (KeyboardMode::Symbolic, &None) => Some(SymbolicKeyboard::new()),
+        };
+
+        let mut autotype = AutoType::new(config.type_mode);
+        if let Some(ref text) = config.type_text {
+            autotype.type_text(&unescape(text), true);
+        }
+
+        let mut true_drive = false;
+        if config.true_drive {
+            if let Some(rom) = load_file("roms/1541") {
+                mem.iec()
+                    .attach(DISK_DEVICE, Box::new(Drive1541::new(rom, DISK_DEVICE)));
+                true_drive = true;
+            }
+        }
+
+        let mut c64 = C64 {
             cpu: cpu,
             vic: vic,
+            sid: sid,
             mem: mem,
+            ports: ports,
+            symbolic: symbolic,
+            autotype: autotype,
+            autostart: config.autostart.clone(),
+            serial: SerialTraps::new(),
+            breakpoints: Breakpoints::new(),
+            symbols: Symbols::new(),
+            source: SourceMap::new(),
+            run_to: None,
+            true_drive: true_drive,
+            tape: Datasette::new(),
+            clipboard: clipboard,
             sdl: sdl,
+        };
+        if let Some(ref f) = config.disk {
+            c64.attach_disk(f);
+        }
+        if let Some(ref dir) = config.host_dir {
+            println!(
+                "Host directory {} attached as device {}",
+                dir, config.host_device
+            );
+            c64.serial
+                .attach(config.host_device, Box::new(HostDrive::new(dir)));
+        }
+        if let Some(ref name) = config.printer {
+            if let Some(chargen) = load_file("roms/chargen") {
+                println!(
+                    "Printer attached as device {}, printing to {}",
+                    PRINTER_DEVICE, name
+                );
+                c64.serial
+                    .attach(PRINTER_DEVICE, Box::new(Printer::new(name, chargen)));
+            }
+        }
+        if let Some(ref f) = config.tape {
+            if c64.tape.insert(f) {
+                println!("Tape {} inserted", f);
+            }
         }
+        if let Some(ref f) = config.symbols {
+            c64.load_symbols(f);
+        }
+        c64
     }
 
     pub fn start(&mut self, start_addr: u16, debug: bool) {
@@ -71,7 +253,9 @@ impl C64 {
 
     fn execute(&mut self, debug: bool) {
         let mut event_pump = self.sdl.event_pump().unwrap();
+        let mouse = self.sdl.mouse();
         let mut break_set = debug;
+        self.ports.grab_mouse(&mouse, !break_set);
 
         'running: loop {
             let tick = PreciseTime::now();
@@ -81,44 +265,92 @@ impl C64 {
                 let mut step_cycles: u8 = 0;
                 break_set = self.do_single_step(&mut step_cycles);
                 self.vic.clock(&mut self.mem);
+                self.sid.clock(&mut self.mem, step_cycles);
+                self.mem.clock_cia1(step_cycles);
+                self.mem.clock_iec(step_cycles);
+                self.clock_tape(step_cycles);
+                // The tape IRQ reads and writes memory too
+                if self.check_watchpoints() {
+                    break_set = true;
+                }
             }
 
             // Pump events and trigger interrupt if we have not broken yet
             if !break_set {
-                event_pump.pump_events();
+                for event in event_pump.poll_iter() {
+                    match event {
+                        Event::KeyDown {
+                            scancode: Some(Scancode::F12),
+                            repeat: false,
+                            ..
+                        } => self.do_paste(),
+                        Event::KeyDown {
+                            scancode: Some(sc), ..
+                        } if self.ports.is_joystick_key(sc) => {}
+                        _ => {
+                            if let Some(ref mut kbd) = self.symbolic {
+                                kbd.handle_event(&event)
+                            }
+                        }
+                    }
+                }
                 if event_pump
                     .keyboard_state()
                     .is_scancode_pressed(Scancode::Escape)
                 {
                     println!("Breaking... ${:04X}", self.cpu.get_pc());
+                    self.ports.grab_mouse(&mouse, false);
                     break_set = true;
                 } else {
                     let keys: Vec<Scancode> =
                         event_pump.keyboard_state().pressed_scancodes().collect();
-                    self.mem.refresh(keys);
+                    let ports = self.ports.update(&mouse, &keys);
+                    self.mem.refresh_ports(ports);
+                    let mut matrix = match self.symbolic {
+                        Some(ref mut kbd) => kbd.matrix(),
+                        None => {
+                            let keys = keys
+                                .into_iter()
+                                .filter(|&sc| !self.ports.is_joystick_key(sc))
+                                .collect();
+                            kbd_matrix(&keys)
+                        }
+                    };
+                    if self.autostart.is_some() && basic_ready(&self.mem) {
+                        let f = self.autostart.take().unwrap();
+                        self.do_autostart(&f);
+                    }
+                    let typed = self.autotype.refresh(&mut self.mem);
+                    for i in 0..8 {
+                        matrix[i] |= typed[i];
+                    }
+                    self.mem.refresh(matrix);
+                    let pc = self.cpu.get_pc();
+                    self.mem.watchpoints().begin(pc, 0);
                     self.vic.refresh(&self.mem);
-                    self.cpu.trigger_irq(&mut self.mem);
+                    if self.mem.cia1_timer_a_irq_enabled() {
+                        self.cpu.trigger_irq(&mut self.mem);
+                    }
+                    if self.check_watchpoints() {
+                        self.ports.grab_mouse(&mouse, false);
+                        break_set = true;
+                    }
                 }
             }
 
             // Handle break state
             if break_set {
+                self.run_to = None;
                 println!("");
+                self.source.print_context(self.cpu.get_pc(), SOURCE_CONTEXT);
                 let mut ip = self.cpu.get_pc();
 
                 for index in 0..3 {
-                    match disassemble_step(&self.mem, &mut ip) {
-                        Ok(s) => {
-                            if index == 0 {
-                                println!("* {}", s)
-                            } else {
-                                println!("  {}", s)
-                            }
-                        }
-                        Err(e) => {
-                            println!("Error: {}", e);
-                            break;
-                        }
+                    let s = disassemble_step(&self.mem, &mut ip, &self.symbols);
+                    if index == 0 {
+                        println!("* {}", s)
+                    } else {
+                        println!("  {}", s)
                     }
                 }
 
@@ -134,12 +366,57 @@ impl C64 {
                     Some("x") => break 'running,
                     Some("g") => {
                         event_pump.pump_events();
+                        self.ports.grab_mouse(&mouse, true);
                         break_set = false
                     }
                     Some("l") => match iter.next() {
-                        Some(f) => self.do_load(f),
+                        Some(f) => {
+                            let _ = self.do_load(f);
+                        }
                         None => self.do_dir(),
                     },
+                    Some("s") if cmd.split_whitespace().count() > 1 => {
+                        match iter.next().and_then(|n| n.parse::<u32>().ok()) {
+                            Some(n) if n > 0 => {
+                                self.run_to = Some(RunTo::Steps(n));
+                                event_pump.pump_events();
+                                break_set = false
+                            }
+                            _ => println!("Usage: s [count]"),
+                        }
+                    }
+                    Some("n") => {
+                        let pc = self.cpu.get_pc();
+                        self.run_to = Some(if self.mem.peek(MemView::Cpu, pc) == OPCODE_JSR {
+                            RunTo::Return {
+                                pc: pc.wrapping_add(3),
+                                sp: self.cpu.get_sp(),
+                            }
+                        } else {
+                            RunTo::Steps(1)
+                        });
+                        event_pump.pump_events();
+                        break_set = false
+                    }
+                    Some("sl") => {
+                        self.run_to = Some(RunTo::Line(self.source.line_at(self.cpu.get_pc())));
+                        event_pump.pump_events();
+                        break_set = false
+                    }
+                    Some("finish") => {
+                        self.run_to = Some(RunTo::Finish(self.cpu.get_sp()));
+                        event_pump.pump_events();
+                        break_set = false
+                    }
+                    Some("until") => match iter.next().map(|a| (a, self.parse_addr(a))) {
+                        Some((_, Some(addr))) => {
+                            self.run_to = Some(RunTo::Address(addr));
+                            event_pump.pump_events();
+                            break_set = false
+                        }
+                        Some((a, None)) => println!("Invalid address: {}", a),
+                        None => println!("Usage: until <addr>"),
+                    },
                     Some("s") | None => {
                         let mut step_cycles: u8 = 0;
                         let _ = self.do_single_step(&mut step_cycles);
@@ -150,87 +427,848 @@ impl C64 {
                         );
                         println!("{}", self.cpu)
                     }
-                    Some("r") => println!("{}", self.cpu),
-                    Some("m") => match iter.next() {
-                        Some(a) => {
-                            let mut addr = i64::from_str_radix(a.trim(), 16).unwrap() as usize;
-                            for _ in 0..3 {
-                                self.mem.memdump(addr, 16);
-                                addr = addr + 16;
+                    Some("r") => {
+                        let args: Vec<&str> = iter.collect();
+                        self.do_registers(&args)
+                    }
+                    Some("iec") => match iter.next() {
+                        Some("on") => self.mem.iec().set_trace(true),
+                        Some("off") => self.mem.iec().set_trace(false),
+                        _ => println!("Usage: iec on|off"),
+                    },
+                    Some("disk") => match iter.next() {
+                        Some(f) => self.attach_disk(f),
+                        None => self.detach_disk(),
+                    },
+                    Some("tape") => match (iter.next(), iter.next()) {
+                        (Some("load"), Some(f)) => {
+                            if self.tape.insert(f) {
+                                println!("Tape {} inserted", f);
                             }
                         }
-                        None => {}
+                        (Some("play"), _) => self.tape.play(),
+                        (Some("stop"), _) => self.tape.stop(),
+                        (Some("rewind"), _) => self.tape.rewind(),
+                        (Some("record"), Some(f)) => self.tape.record(f),
+                        (None, _) => println!("{}", self.tape.status()),
+                        _ => println!("Usage: tape [load <file>|play|stop|rewind|record <file>]"),
                     },
-                    Some("p") => match iter.next() {
-                        Some(a) => {
-                            let addr = i64::from_str_radix(a.trim(), 16).unwrap() as u16;
-                            self.cpu.set_pc(addr)
+                    Some("type") => {
+                        let text = cmd.trim_right().splitn(2, ' ').nth(1).unwrap_or("");
+                        self.autotype.type_text(&unescape(text), false)
+                    }
+                    Some(c @ "m") | Some(c @ ">") | Some(c @ "f") | Some(c @ "t")
+                    | Some(c @ "c") | Some(c @ "h") => {
+                        let args = cmd
+                            .trim()
+                            .splitn(2, char::is_whitespace)
+                            .nth(1)
+                            .unwrap_or("");
+                        if let Err(e) = self.do_memory(c, args) {
+                            println!("{}", e);
                         }
+                    }
+                    Some("p") => match iter.next().map(|a| (a, self.parse_addr(a))) {
+                        Some((_, Some(addr))) => self.cpu.set_pc(addr),
+                        Some((a, None)) => println!("Invalid address: {}", a),
                         None => {}
                     },
-                    Some("b") => match iter.next() {
-                        Some(a) => {
-                            let addr = i64::from_str_radix(a.trim(), 16).unwrap() as u16;
-                            self.cpu.set_breakpoint(addr)
+                    Some("b") | Some("tb") if cmd.split_whitespace().count() == 1 => {
+                        self.breakpoints.print()
+                    }
+                    Some(c @ "b") | Some(c @ "tb") => {
+                        let args: Vec<&str> = iter.collect();
+                        self.do_set_breakpoint(&args, c == "tb")
+                    }
+                    Some("a") => {
+                        let args: Vec<&str> = iter.collect();
+                        self.do_assemble(&args)
+                    }
+                    Some("d") => {
+                        let args: Vec<&str> = iter.collect();
+                        self.do_disassemble(&args)
+                    }
+                    Some("sym") => match (iter.next(), iter.next(), iter.next()) {
+                        (None, _, _) => self.symbols.print(),
+                        (Some("add"), Some(a), Some(name)) => match self.parse_addr(a) {
+                            Some(addr) => self.symbols.add(addr, name),
+                            None => println!("Invalid address: {}", a),
+                        },
+                        (Some("load"), Some(f), _) => self.load_symbols(f),
+                        (Some("clear"), _, _) => {
+                            self.symbols.clear();
+                            self.source.clear();
                         }
-                        None => {}
+                        _ => println!("Usage: sym [add <addr> <name>|load <file>|clear]"),
                     },
+                    Some("bl") => self.breakpoints.print(),
+                    Some("bt") => self.do_backtrace(),
+                    Some("watch") => {
+                        let args: Vec<&str> = iter.collect();
+                        self.do_watch(&args)
+                    }
+                    Some("unwatch") => match iter.next().map(|a| a.parse::<usize>()) {
+                        Some(Ok(id)) => {
+                            if !self.mem.watchpoints().delete(id) {
+                                println!("No watchpoint {}", id);
+                            }
+                        }
+                        Some(Err(_)) => println!("Usage: unwatch [id]"),
+                        None => {
+                            self.mem.watchpoints().clear();
+                            println!("All watchpoints deleted");
+                        }
+                    },
+                    Some(c @ "del") | Some(c @ "enable") | Some(c @ "disable")
+                    | Some(c @ "ignore") | Some(c @ "cond") => {
+                        let args: Vec<&str> = iter.collect();
+                        self.do_edit_breakpoint(c, &args)
+                    }
                     Some(_) => {}
                 }
             }
         }
+
+        self.mem.iec().flush();
+        self.serial.flush();
+        self.tape.stop();
     }
 
     fn do_help(&self) {
         println!("Commands:");
         println!("(s)tep        - execute next instruction (single step)");
         println!("(g)o          - execute till next breakpoint");
-        println!("(r)eg         - dump registers");
-        println!("(m)em [addr]  - dump memory at addr");
+        println!("(s)tep count  - execute count instructions");
+        println!("n             - step over: run a JSR until it returns, else step");
+        println!("finish        - run until the current subroutine returns");
+        println!("sl            - step to the next source line (from a ca65 .dbg file)");
+        println!("until addr    - run until the PC reaches addr");
+        println!("                these all stop early at breakpoints");
+        println!("(r)eg [r=v ...] - set registers or flags, then show them, e.g.");
+        println!("                r A=FF X=0 PC=C000 C=1 (A X Y SP ST PC, C Z I D B V N)");
+        println!("(m)em [bank] addr [end] - dump memory (48 bytes without an end)");
+        println!("> [bank] addr data - write bytes: hex and \"text\" (PETSCII)");
+        println!("f [bank] start end data - fill a range with a repeated pattern");
+        println!("t [bank] start end dest - copy a range");
+        println!("c [bank] start end other - compare a range with another");
+        println!("h [bank] start end data - hunt for bytes or text in a range");
+        println!("                bank is cpu (the default), ram, rom, io or vic");
         println!("(p)c [addr]   - set the PC to addr");
-        println!("(b)p [addr]   - set breakpoint at addr");
-        println!("(l)oad [file] - load a PRG file");
+        println!("a addr [inst] - assemble at addr, then line by line (empty line ends)");
+        println!("d [start] [end] - disassemble (from the PC by default)");
+        println!("sym [cmd]     - symbols: add <addr> <name>, load <file>, clear");
+        println!("                (none lists them); labels, and file:line with a");
+        println!("                .dbg file, can be used in place of any address");
+        println!("(b)p [addr]   - set a breakpoint at addr (none lists them); add");
+        println!("                if <cond> to stop only when cond holds, e.g.");
+        println!("                b C000 if A==$FF && ($FB)>10 || C==1");
+        println!("tb addr       - set a temporary breakpoint, deleted when hit");
+        println!("bl            - list breakpoints");
+        println!("bt            - show the calls and interrupts that led here");
+        println!("del [id]      - delete a breakpoint (all without an id)");
+        println!("enable id     - enable a breakpoint");
+        println!("disable id    - disable a breakpoint");
+        println!("ignore id n   - don't stop at a breakpoint for its next n hits");
+        println!("cond id [c]   - set the condition of a breakpoint (none removes it)");
+        println!("watch [acc] start [end] - break on accesses to a range (none lists");
+        println!("                them); acc is any of r, w (default rw), x and");
+        println!("                v for VIC fetches");
+        println!("unwatch [id]  - delete a watchpoint (all without an id)");
+        println!("(l)oad [file] - load a PRG, P00 or T64 file (file.t64:N for entry N)");
+        println!("type [text]   - type text into the C64 (\\n for RETURN)");
+        println!("disk [file]   - insert a disk image in drive 8 (none to eject)");
+        println!("iec on|off    - trace serial bus line changes");
+        println!("tape [cmd]    - datasette: load <file>, play, stop, rewind, record <file>");
+        println!("                (no command shows the tape counter)");
         println!("e(x)it        - exit program");
     }
 
-    fn do_load(&mut self, filename: &str) {
+    // Load a program (PRG, P00 or T64 entry) to the address in its header.
+    // Returns the start and end (one past the last byte) addresses of the
+    // loaded data.
+    fn do_load(&mut self, filename: &str) -> Option<(u16, u16)> {
         println!("Loading: {}", filename);
-        if let Some(mut data) = load_file(&filename) {
-            assert!(data.len() > 2);
-            // We only load if the target is $0801 (start of BASIC)
-            if (data[0] == 0x01) && (data[1] == 0x08) {
-                let to_load: Vec<_> = data.drain(2..).collect();
-                self.mem.load_ram(&to_load, 0x0801);
-
-                // Fix up BASIC variable pointers
-                let last: u16 = (0x0801 + to_load.len() + 1) as u16;
-                let last_low = (last % 255) as u8;
-                let last_high = (last / 255) as u8;
-                self.mem.write_byte(0x002D, last_low);
-                self.mem.write_byte(0x002E, last_high);
-                self.mem.write_byte(0x002F, last_low);
-                self.mem.write_byte(0x0030, last_high);
-                println!("Load complete ({} bytes)", to_load.len());
+        let data = match load_program(&filename) {
+            Some(d) => d,
+            None => return None,
+        };
+        if data.len() < 3 {
+            println!("Unable to load - file is too short");
+            return None;
+        }
+
+        let start = (data[0] as u16) | ((data[1] as u16) << 8);
+        let mut to_load = data[2..].to_vec();
+        if start as usize + to_load.len() > 0x10000 {
+            println!("File extends past $FFFF - truncating");
+            to_load.truncate(0x10000 - start as usize);
+        }
+        self.mem.load_ram(&to_load, start);
+
+        // Leave the end address in $AE/$AF like the KERNAL LOAD does
+        let end = (start as usize + to_load.len()) as u16;
+        self.write_word(LOAD_END, end);
+
+        // Fix up BASIC variable pointers only when loading a BASIC program
+        if start == self.mem.read_word(BASIC_START) {
+            self.write_word(BASIC_VARTAB, end);
+            self.write_word(BASIC_ARYTAB, end);
+            self.write_word(BASIC_STREND, end);
+        }
+
+        println!(
+            "Load complete: ${:04X}-${:04X} ({} bytes)",
+            start,
+            end,
+            to_load.len()
+        );
+        Some((start, end))
+    }
+
+    // Load the program and start it with RUN or SYS to its load address
+    fn do_autostart(&mut self, filename: &str) {
+        if let Some((start, _)) = self.do_load(filename) {
+            let cmd = if start == self.mem.read_word(BASIC_START) {
+                "RUN\n".to_string()
             } else {
-                println!("Unable to load - file does not target BASIC ($0801)");
-                println!("Found target: ${:02X}{:02X}", data[1], data[0]);
-            }
+                format!("SYS {}\n", start)
+            };
+            self.autotype.type_text(&cmd, false);
+        }
+    }
+
+    fn write_word(&mut self, addr: u16, val: u16) {
+        self.mem.write_byte(addr, (val & 0xFF) as u8);
+        self.mem.write_byte(addr + 1, (val >> 8) as u8);
+    }
+
+    fn do_paste(&mut self) {
+        match self.clipboard.clipboard_text() {
+            Ok(text) => self.autotype.type_text(&text, false),
+            Err(e) => println!("Unable to read clipboard: {}", e),
         }
     }
 
     fn do_dir(&self) {
-        for path in fs::read_dir("./").unwrap() {
-            println!("{}", path.unwrap().path().display())
+        for path in dir_entries("./") {
+            println!("{}", path.display())
+        }
+    }
+
+    // Insert a disk image in drive 8. With the virtual drive a missing file
+    // gets a blank formatted image, written out on the first change.
+    fn attach_disk(&mut self, filename: &str) {
+        if self.true_drive {
+            if let Some(drive) = self.mem.iec().device_mut(DISK_DEVICE) {
+                if drive.insert(filename) {
+                    println!("Disk inserted: {}", filename);
+                }
+            }
+            return;
+        }
+
+        let image = if fs::metadata(filename).is_ok() {
+            match load_image(filename) {
+                Some(d) => d,
+                None => return,
+            }
+        } else {
+            println!("Creating blank disk: {}", filename);
+            new_image(filename, b"BLANK", b"00")
+        };
+        println!("Disk attached: {}", filename);
+        self.serial
+            .attach(DISK_DEVICE, Box::new(VirtualDrive::new(image, filename)));
+    }
+
+    fn detach_disk(&mut self) {
+        if self.true_drive {
+            if let Some(drive) = self.mem.iec().device_mut(DISK_DEVICE) {
+                drive.eject();
+            }
+        } else {
+            self.serial.detach(DISK_DEVICE);
+        }
+        println!("Disk detached");
+    }
+
+    // Run the datasette, its pulses raising the CIA1 FLAG interrupt
+    fn clock_tape(&mut self, cycles: u8) {
+        let (motor, write_line) = self.mem.cassette_outputs();
+        if self.tape.clock(cycles, motor, write_line) {
+            self.mem.cia1_flag();
+        }
+        self.mem.set_cassette_sense(self.tape.sense());
+        if self.mem.cia1_irq() {
+            self.cpu.trigger_irq(&mut self.mem);
+        }
+    }
+
+    // Split the arguments of a memory command into the view (the CPU's
+    // unless one is named first), count addresses and the rest of the line
+    fn mem_args<'a>(
+        &self,
+        args: &'a str,
+        count: usize,
+    ) -> Result<(MemView, Vec<u16>, &'a str), String> {
+        let mut rest = args.trim();
+        let mut view = MemView::Cpu;
+        let mut addrs = Vec::new();
+        let mut first = true;
+        while addrs.len() < count {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let word = &rest[..end];
+            if word.is_empty() {
+                return Err("Missing address".to_string());
+            }
+            match MemView::from_name(word) {
+                Some(v) if first => view = v,
+                _ => match self.parse_addr(word) {
+                    Some(addr) => addrs.push(addr),
+                    None => return Err(format!("Invalid address: {}", word)),
+                },
+            }
+            first = false;
+            rest = rest[end..].trim();
         }
+        Ok((view, addrs, rest))
+    }
+
+    // m, >, f, t, c and h: dump, edit, fill, transfer, compare and hunt
+    fn do_memory(&mut self, cmd: &str, args: &str) -> Result<(), String> {
+        let count = match cmd {
+            "m" | ">" => 1,
+            "f" | "h" => 2,
+            _ => 3,
+        };
+        let (view, addrs, rest) = try!(self.mem_args(args, count));
+        let start = addrs[0] as u32;
+        let end = match cmd {
+            "m" if rest.is_empty() => min(start + 47, 0xFFFF),
+            "m" => match self.parse_addr(rest) {
+                Some(end) => end as u32,
+                None => return Err(format!("Invalid address: {}", rest)),
+            },
+            ">" => start,
+            _ => addrs[1] as u32,
+        };
+        if end < start {
+            return Err("The end address is before the start".to_string());
+        }
+        let len = end - start + 1;
+        let range: Vec<u8> = (start..end + 1)
+            .map(|a| self.mem.peek(view, a as u16))
+            .collect();
+
+        match cmd {
+            "m" => {
+                for row in (start..end + 1).filter(|a| (a - start) % 16 == 0) {
+                    let bytes = &range
+                        [(row - start) as usize..min(row + 16, end + 1) as usize - start as usize];
+                    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                    let text: String = bytes
+                        .iter()
+                        .map(|&b| match petscii_to_char(b) {
+                            Some(c) if !c.is_control() => c,
+                            _ => '.',
+                        })
+                        .collect();
+                    println!("${:04X}: {:<47}  {}", row, hex.join(" "), text);
+                }
+            }
+            ">" | "f" => {
+                let data = try!(parse_bytes(rest));
+                if data.is_empty() {
+                    return Err(format!("Usage: {} [bank] <addr> <bytes or \"text\">", cmd));
+                }
+                let len = if cmd == ">" { data.len() as u32 } else { len };
+                for i in 0..len {
+                    let val = data[i as usize % data.len()];
+                    self.mem.poke(view, (start + i) as u16, val);
+                }
+            }
+            "t" => {
+                for (i, &val) in range.iter().enumerate() {
+                    self.mem.poke(view, addrs[2].wrapping_add(i as u16), val);
+                }
+            }
+            "c" => {
+                let mut differences = 0;
+                for (i, &val) in range.iter().enumerate() {
+                    let other = addrs[2].wrapping_add(i as u16);
+                    let val2 = self.mem.peek(view, other);
+                    if val != val2 {
+                        println!(
+                            "${:04X}: ${:02X}  ${:04X}: ${:02X}",
+                            start + i as u32,
+                            val,
+                            other,
+                            val2
+                        );
+                        differences += 1;
+                    }
+                }
+                println!("{} differences", differences);
+            }
+            _ => {
+                let pattern = try!(parse_bytes(rest));
+                if pattern.is_empty() {
+                    return Err("Usage: h [bank] <start> <end> <bytes or \"text\">".to_string());
+                }
+                let found: Vec<String> = range
+                    .windows(pattern.len())
+                    .enumerate()
+                    .filter(|&(_, w)| w == &pattern[..])
+                    .map(|(i, _)| format!("${:04X}", start + i as u32))
+                    .collect();
+                for line in found.chunks(8) {
+                    println!("{}", line.join(" "));
+                }
+                println!("{} found", found.len());
+            }
+        }
+        Ok(())
+    }
+
+    // r [reg=value ...]: set registers (A, X, Y, SP, ST, PC) and flags
+    // (C, Z, I, D, B, V, N), then show them
+    fn do_registers(&mut self, args: &[&str]) {
+        for arg in args {
+            let mut parts = arg.splitn(2, '=');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name.to_uppercase(), value),
+                _ => {
+                    println!("Usage: r [A|X|Y|SP|ST|PC|C|Z|I|D|B|V|N=value ...]");
+                    return;
+                }
+            };
+            if name == "PC" {
+                match self.parse_addr(value) {
+                    Some(addr) => self.cpu.set_pc(addr),
+                    None => println!("Invalid address: {}", value),
+                }
+                continue;
+            }
+            let val = match u8::from_str_radix(value, 16) {
+                Ok(val) => val,
+                Err(_) => {
+                    println!("Invalid value: {}", value);
+                    continue;
+                }
+            };
+            let flag = match name.as_str() {
+                "C" => Flags::Carry,
+                "Z" => Flags::Zero,
+                "I" => Flags::Interrupt,
+                "D" => Flags::Decimal,
+                "B" => Flags::Break,
+                "V" => Flags::Overflow,
+                "N" => Flags::Sign,
+                "A" => {
+                    self.cpu.set_a(val);
+                    continue;
+                }
+                "X" => {
+                    self.cpu.set_x(val);
+                    continue;
+                }
+                "Y" => {
+                    self.cpu.set_y(val);
+                    continue;
+                }
+                "SP" => {
+                    self.cpu.set_sp(val);
+                    continue;
+                }
+                "ST" | "P" => {
+                    self.cpu.set_st(val);
+                    continue;
+                }
+                _ => {
+                    println!("Unknown register: {}", name);
+                    continue;
+                }
+            };
+            if val > 1 {
+                println!("Flags are 0 or 1: {}", arg);
+                continue;
+            }
+            self.cpu.set_flag(flag, val == 1);
+        }
+        println!("{}", self.cpu);
+    }
+
+    // An address typed in the monitor: a label or a hex number
+    fn parse_addr(&self, s: &str) -> Option<u16> {
+        self.symbols
+            .lookup(s)
+            .or_else(|| self.source.find(s))
+            .or_else(|| u16::from_str_radix(s, 16).ok())
+    }
+
+    // Labels from any symbol file, and source lines from a ca65 .dbg file
+    fn load_symbols(&mut self, filename: &str) {
+        if let Some(n) = self.symbols.load(filename) {
+            println!("Loaded {} symbols from {}", n, filename);
+        }
+        let lines = self.source.load(filename);
+        if lines > 0 {
+            println!("Loaded {} source lines from {}", lines, filename);
+        }
+    }
+
+    // a <addr> [instruction]: assemble into memory, then keep prompting
+    // for instructions at the following addresses until an empty line
+    fn do_assemble(&mut self, args: &[&str]) {
+        let mut addr = match args.get(0).and_then(|a| self.parse_addr(a)) {
+            Some(addr) => addr,
+            None => {
+                println!("Usage: a <addr> [instruction]");
+                return;
+            }
+        };
+        let mut line = args[1..].join(" ");
+        loop {
+            if line.is_empty() {
+                print!("${:04X}  ", addr);
+                io::stdout().flush().ok().expect("Could not flush stdout");
+                io::stdin().read_line(&mut line).unwrap();
+                line = line.trim().to_string();
+                if line.is_empty() {
+                    break;
+                }
+            }
+            match assemble(&line, addr, &self.symbols) {
+                Ok(bytes) => {
+                    for (i, &b) in bytes.iter().enumerate() {
+                        self.mem.write_byte(addr.wrapping_add(i as u16), b);
+                    }
+                    let mut ip = addr;
+                    println!("{}", disassemble_step(&self.mem, &mut ip, &self.symbols));
+                    addr = ip;
+                }
+                Err(e) => println!("Error: {}", e),
+            }
+            line.clear();
+        }
+    }
+
+    // d [start] [end]: disassemble a range, or 20 instructions from start
+    // (the PC by default)
+    fn do_disassemble(&self, args: &[&str]) {
+        let addrs: Vec<Option<u16>> = args.iter().map(|a| self.parse_addr(a)).collect();
+        if args.len() > 2 || addrs.iter().any(|a| a.is_none()) {
+            println!("Usage: d [start] [end]");
+            return;
+        }
+        let mut ip = addrs.get(0).map_or(self.cpu.get_pc(), |a| a.unwrap());
+        let end = addrs.get(1).map(|a| a.unwrap());
+        let mut count = 0;
+        let mut source_line = None;
+        loop {
+            let addr = ip;
+            if let Some(name) = self.symbols.name_at(addr) {
+                println!("{}:", name);
+            }
+            let line = self.source.line_at(addr);
+            if line != source_line {
+                if let Some((file, n)) = line {
+                    let text = self.source.text(file, n).unwrap_or("").trim();
+                    println!("; {}  {}", self.source.describe(file, n), text);
+                }
+                source_line = line;
+            }
+            println!("{}", disassemble_step(&self.mem, &mut ip, &self.symbols));
+            count += 1;
+            let done = match end {
+                Some(end) => ip > end,
+                None => count >= DISASSEMBLE_LINES,
+            };
+            if done || ip < addr {
+                break;
+            }
+        }
+    }
+
+    // Where an address is, by label and source line
+    fn describe_addr(&self, addr: u16) -> String {
+        let mut s = format!("${:04X}", addr);
+        if let Some(name) = self.symbols.nearest(addr) {
+            s.push_str(&format!(" {}", name));
+        }
+        if let Some((file, line)) = self.source.line_at(addr) {
+            s.push_str(&format!(" ({})", self.source.describe(file, line)));
+        }
+        s
+    }
+
+    // bt: the shadow call stack, innermost first, checked against the
+    // return addresses actually on the stack page
+    fn do_backtrace(&self) {
+        println!("#0  {}", self.describe_addr(self.cpu.get_pc()));
+        let sp = self.cpu.get_sp();
+        let mut agree = true;
+        for (n, call) in self.cpu.calls().iter().rev().enumerate() {
+            println!(
+                "#{:<2} {}  {} {}",
+                n + 1,
+                self.describe_addr(call.from),
+                call.kind.name(),
+                self.describe_addr(call.target)
+            );
+            let (addr, expected) = call.stacked_return();
+            if call.sp < sp {
+                println!(
+                    "      return address was pulled off the stack (SP is ${:02X})",
+                    sp
+                );
+                agree = false;
+                continue;
+            }
+            let lo = self.mem.peek(MemView::Cpu, addr) as u16;
+            let hi = self.mem.peek(MemView::Cpu, addr.wrapping_add(1)) as u16;
+            let stacked = lo | (hi << 8);
+            if stacked != expected {
+                println!(
+                    "      stack has ${:04X} at ${:04X}, expected ${:04X}",
+                    stacked, addr, expected
+                );
+                agree = false;
+            }
+        }
+        if !agree {
+            println!(
+                "The call stack and the stack page disagree: the stack was changed or corrupted"
+            );
+        }
+    }
+
+    // b/tb <addr> [if <condition>]
+    fn do_set_breakpoint(&mut self, args: &[&str], temporary: bool) {
+        let addr = match self.parse_addr(args[0]) {
+            Some(addr) => addr,
+            None => {
+                println!("Invalid address: {}", args[0]);
+                return;
+            }
+        };
+        let condition = match args.get(1) {
+            Some(&"if") if args.len() > 2 => {
+                let text = args[2..].join(" ");
+                match Condition::parse(&text) {
+                    Ok(cond) => Some((text, cond)),
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        return;
+                    }
+                }
+            }
+            Some(_) => {
+                println!("Usage: b <addr> [if <condition>]");
+                return;
+            }
+            None => None,
+        };
+        let id = self.breakpoints.add(addr, temporary, condition);
+        match self.source.line_at(addr) {
+            Some((file, n)) => println!(
+                "Breakpoint {} at ${:04X} ({})",
+                id,
+                addr,
+                self.source.describe(file, n)
+            ),
+            None => println!("Breakpoint {} at ${:04X}", id, addr),
+        }
+    }
+
+    // del/enable/disable/ignore/cond <id> ...
+    fn do_edit_breakpoint(&mut self, cmd: &str, args: &[&str]) {
+        if cmd == "del" && args.is_empty() {
+            self.breakpoints.clear();
+            println!("All breakpoints deleted");
+            return;
+        }
+        let id = match args.get(0).and_then(|a| a.parse::<usize>().ok()) {
+            Some(id) => id,
+            None => {
+                println!("Usage: {} <id>", cmd);
+                return;
+            }
+        };
+        if cmd == "del" {
+            if !self.breakpoints.delete(id) {
+                println!("No breakpoint {}", id);
+            }
+            return;
+        }
+        let condition = if cmd == "cond" && args.len() > 1 {
+            let text = args[1..].join(" ");
+            match Condition::parse(&text) {
+                Ok(cond) => Some((text, cond)),
+                Err(e) => {
+                    println!("Error: {}", e);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        let count = args.get(1).and_then(|a| a.parse::<u32>().ok());
+        let b = match self.breakpoints.get_mut(id) {
+            Some(b) => b,
+            None => {
+                println!("No breakpoint {}", id);
+                return;
+            }
+        };
+        match cmd {
+            "enable" => b.enabled = true,
+            "disable" => b.enabled = false,
+            "ignore" => match count {
+                Some(n) => b.ignore = n,
+                None => println!("Usage: ignore <id> <count>"),
+            },
+            _ => b.condition = condition,
+        }
+    }
+
+    // Report the watchpoint hits since the last begin, if any
+    fn check_watchpoints(&mut self) -> bool {
+        let hits = self.mem.watchpoints().take_hits();
+        for h in hits.iter().take(MAX_WATCH_HITS) {
+            println!("{}", h.describe());
+        }
+        if hits.len() > MAX_WATCH_HITS {
+            println!("... and {} more", hits.len() - MAX_WATCH_HITS);
+        }
+        !hits.is_empty()
+    }
+
+    // watch [r|w|x|v...] <start> [end]
+    fn do_watch(&mut self, args: &[&str]) {
+        if args.is_empty() {
+            self.mem.watchpoints().print();
+            return;
+        }
+        // Access letters are never hex digits
+        let (access, range) = if args[0].chars().all(|c| "rwxv".contains(c)) {
+            let access = args[0].chars().fold(0, |acc, c| {
+                acc | match c {
+                    'r' => WATCH_READ,
+                    'w' => WATCH_WRITE,
+                    'x' => WATCH_EXECUTE,
+                    _ => WATCH_VIC,
+                }
+            });
+            (access, &args[1..])
+        } else {
+            (WATCH_READ | WATCH_WRITE, args)
+        };
+        let addrs: Vec<Option<u16>> = range.iter().map(|a| self.parse_addr(a)).collect();
+        let (start, end) = match (addrs.get(0), addrs.get(1), addrs.len()) {
+            (Some(&Some(start)), None, 1) => (start, start),
+            (Some(&Some(start)), Some(&Some(end)), 2) if end >= start => (start, end),
+            _ => {
+                println!("Usage: watch [r|w|x|v] <start> [end]");
+                return;
+            }
+        };
+        let id = self.mem.watchpoints().add(start, end, access);
+        println!("Watchpoint {} at ${:04X}-${:04X}", id, start, end);
     }
 
     fn do_single_step(&mut self, cycles: &mut u8) -> bool {
-        match self.cpu.single_step(&mut self.mem, cycles) {
-            Ok(val) => val,
-            Err(e) => {
-                println!("Single step error: {}", e);
-                true
+        let pc = self.cpu.get_pc();
+        let len = get_opcode(self.mem.peek(MemView::Cpu, pc)).map_or(1, |o| o.bytes);
+        self.mem.watchpoints().begin(pc, len);
+        self.mem.begin_iec(pc);
+        let returning = match self.run_to {
+            Some(RunTo::Finish(_)) => {
+                let op = self.mem.peek(MemView::Cpu, pc);
+                op == OPCODE_RTS || op == OPCODE_RTI
+            }
+            _ => false,
+        };
+
+        // Press PLAY when the KERNAL asks for it and a tape is waiting
+        if self.mem.kernal_mapped()
+            && self.cpu.get_pc() == KERNAL_PRESS_PLAY
+            && self.tape.is_inserted()
+            && !self.tape.sense()
+            && !self.tape.at_end()
+        {
+            println!("Pressing PLAY on tape");
+            self.tape.play();
+        }
+
+        // Serve the KERNAL serial routines for attached devices
+        if self.mem.kernal_mapped() && self.serial.trap(&mut self.cpu, &mut self.mem) {
+            *cycles = 6;
+        } else {
+            match self.cpu.single_step(&mut self.mem, cycles) {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(e) => {
+                    println!("Single step error: {}", e);
+                    return true;
+                }
+            }
+        }
+
+        // Before any monitor reads for breakpoint conditions
+        self.mem.watch_execute(self.cpu.get_pc());
+        if self.check_watchpoints() {
+            return true;
+        }
+
+        if let Some(id) = self.breakpoints.check(&self.cpu, &self.mem) {
+            println!("Breakpoint {} at ${:04X}", id, self.cpu.get_pc());
+            return true;
+        }
+
+        let (pc, sp) = (self.cpu.get_pc(), self.cpu.get_sp());
+        let stop = match self.run_to {
+            Some(RunTo::Steps(ref mut n)) => {
+                *n -= 1;
+                *n == 0
+            }
+            Some(RunTo::Return { pc: ret, sp: depth }) => pc == ret && sp >= depth,
+            Some(RunTo::Finish(depth)) => returning && sp > depth,
+            Some(RunTo::Address(addr)) => pc == addr,
+            Some(RunTo::Line(from)) => match self.source.line_starting(pc) {
+                Some(line) => Some(line) != from,
+                None => false,
+            },
+            None => false,
+        };
+        if stop {
+            println!("Stopped at ${:04X}", pc);
+            println!("{}", self.cpu);
+        }
+        stop
+    }
+}
+
+// Hex bytes and quoted strings (as PETSCII) given to the memory commands
+fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        if rest.starts_with('"') {
+            let end = match rest[1..].find('"') {
+                Some(ix) => ix + 1,
+                None => return Err("Missing closing quote".to_string()),
+            };
+            bytes.extend(str_to_petscii(&rest[1..end]));
+            rest = rest[end + 1..].trim();
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            match u8::from_str_radix(&rest[..end], 16) {
+                Ok(b) => bytes.push(b),
+                Err(_) => return Err(format!("Invalid byte: {}", &rest[..end])),
             }
+            rest = rest[end..].trim();
         }
     }
+    Ok(bytes)
 }
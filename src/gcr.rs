@@ -0,0 +1,461 @@
+use d64::*;
+use diskimage::*;
+
+// Number of half tracks on the disk surface (track 1 is half track 0)
+pub const HALFTRACKS: usize = 84;
+
+const G64_SIGNATURE: &'static [u8] = b"GCR-1541";
+const G64_MAX_TRACK: usize = 7928;
+
+const SYNC_LEN: usize = 5;
+const HEADER_GAP: usize = 9;
+const GAP_BYTE: u8 = 0x55;
+const HEADER_MARK: u8 = 0x08;
+const DATA_MARK: u8 = 0x07;
+
+// 4-bit nybble to 5-bit group code
+const GCR_ENCODE: [u8; 16] = [
+    0x0A, 0x0B, 0x12, 0x13, 0x0E, 0x0F, 0x16, 0x17, 0x09, 0x19, 0x1A, 0x1B, 0x0D, 0x1D, 0x1E, 0x15,
+];
+
+// Speed zone (bit rate) the DOS uses for a track
+pub fn speed_zone(track: u8) -> u8 {
+    match track {
+        1...17 => 3,
+        18...24 => 2,
+        25...30 => 1,
+        _ => 0,
+    }
+}
+
+// Bytes on a track written at the given speed zone
+fn track_size(zone: u8) -> usize {
+    [6250, 6666, 7142, 7692][zone as usize & 3]
+}
+
+fn gcr_decode_nybble(code: u8) -> Option<u8> {
+    GCR_ENCODE.iter().position(|&c| c == code).map(|n| n as u8)
+}
+
+// Encode bytes (a multiple of 4) into GCR, 5 bytes for every 4
+pub fn gcr_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() * 5 / 4);
+    for chunk in data.chunks(4) {
+        let mut bits: u64 = 0;
+        for &b in chunk.iter() {
+            bits = (bits << 10)
+                | ((GCR_ENCODE[(b >> 4) as usize] as u64) << 5)
+                | GCR_ENCODE[(b & 0x0F) as usize] as u64;
+        }
+        for i in 0..5 {
+            out.push((bits >> (32 - i * 8)) as u8);
+        }
+    }
+    out
+}
+
+// Decode GCR (a multiple of 5 bytes) back into bytes. Returns None on an
+// invalid group code.
+pub fn gcr_decode(gcr: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(gcr.len() * 4 / 5);
+    for chunk in gcr.chunks(5) {
+        if chunk.len() < 5 {
+            break;
+        }
+        let mut bits: u64 = 0;
+        for &b in chunk.iter() {
+            bits = (bits << 8) | b as u64;
+        }
+        for i in 0..4 {
+            let hi = gcr_decode_nybble(((bits >> (35 - i * 10)) & 0x1F) as u8);
+            let lo = gcr_decode_nybble(((bits >> (30 - i * 10)) & 0x1F) as u8);
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => out.push((hi << 4) | lo),
+                _ => return None,
+            }
+        }
+    }
+    Some(out)
+}
+
+pub fn is_g64(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[0..8] == G64_SIGNATURE
+}
+
+// The magnetic surface of a disk as GCR bit streams, one per half track
+#[derive(Clone)]
+pub struct GcrDisk {
+    tracks: Vec<Vec<u8>>,
+    speeds: Vec<u8>,
+}
+
+impl GcrDisk {
+    // Format the sectors of a D64 image onto the surface the way the
+    // 1541 DOS writes them. Error codes in the image are reproduced.
+    pub fn from_d64(d64: &D64) -> GcrDisk {
+        let mut tracks = vec![Vec::new(); HALFTRACKS];
+        let mut speeds = vec![0; HALFTRACKS];
+        for t in 1..d64.tracks() + 1 {
+            let ix = (t as usize - 1) * 2;
+            tracks[ix] = encode_track(d64, t);
+            speeds[ix] = speed_zone(t);
+        }
+
+        GcrDisk {
+            tracks: tracks,
+            speeds: speeds,
+        }
+    }
+
+    pub fn from_g64(data: &[u8]) -> Result<GcrDisk, String> {
+        if !is_g64(data) {
+            return Err("not a G64 image".to_string());
+        }
+        let halftracks = (data[9] as usize).min(HALFTRACKS);
+        let dword = |offset: usize| -> Result<usize, String> {
+            match data.get(offset..offset + 4) {
+                Some(b) => Ok(b[0] as usize
+                    | (b[1] as usize) << 8
+                    | (b[2] as usize) << 16
+                    | (b[3] as usize) << 24),
+                None => Err("truncated G64 image".to_string()),
+            }
+        };
+
+        let mut tracks = vec![Vec::new(); HALFTRACKS];
+        let mut speeds = vec![0; HALFTRACKS];
+        for ix in 0..halftracks {
+            let offset = try!(dword(12 + ix * 4));
+            if offset == 0 {
+                continue;
+            }
+            let len = match data.get(offset..offset + 2) {
+                Some(b) => b[0] as usize | (b[1] as usize) << 8,
+                None => return Err("truncated G64 image".to_string()),
+            };
+            tracks[ix] = match data.get(offset + 2..offset + 2 + len) {
+                Some(t) => t.to_vec(),
+                None => return Err("truncated G64 image".to_string()),
+            };
+
+            // Per-byte speed maps are not supported; use the first zone
+            let speed = try!(dword(12 + (halftracks + ix) * 4));
+            speeds[ix] = if speed < 4 {
+                speed as u8
+            } else {
+                speed_zone(ix as u8 / 2 + 1)
+            };
+        }
+
+        Ok(GcrDisk {
+            tracks: tracks,
+            speeds: speeds,
+        })
+    }
+
+    pub fn to_g64(&self) -> Vec<u8> {
+        let max = self
+            .tracks
+            .iter()
+            .map(|t| t.len())
+            .max()
+            .unwrap_or(0)
+            .max(G64_MAX_TRACK);
+        let mut out = G64_SIGNATURE.to_vec();
+        out.push(0);
+        out.push(HALFTRACKS as u8);
+        out.push((max & 0xFF) as u8);
+        out.push((max >> 8) as u8);
+
+        let mut offset = 12 + HALFTRACKS * 8;
+        let mut offsets = Vec::new();
+        for t in self.tracks.iter() {
+            if t.is_empty() {
+                offsets.push(0);
+            } else {
+                offsets.push(offset);
+                offset += 2 + max;
+            }
+        }
+        for &o in offsets.iter() {
+            for i in 0..4 {
+                out.push((o >> (i * 8)) as u8);
+            }
+        }
+        for &speed in self.speeds.iter() {
+            out.extend_from_slice(&[speed, 0, 0, 0]);
+        }
+        for t in self.tracks.iter().filter(|t| !t.is_empty()) {
+            out.push((t.len() & 0xFF) as u8);
+            out.push((t.len() >> 8) as u8);
+            out.extend_from_slice(t);
+            out.extend(vec![0; max - t.len()]);
+        }
+        out
+    }
+
+    // Decode the sectors found on the surface back into a D64 image
+    pub fn decode_into(&self, d64: &mut D64) {
+        for t in 1..d64.tracks() + 1 {
+            let track = &self.tracks[(t as usize - 1) * 2];
+            for (sector, data) in decode_track(track) {
+                d64.write_sector(t, sector, &data);
+            }
+        }
+    }
+
+    pub fn track(&self, halftrack: usize) -> &[u8] {
+        &self.tracks[halftrack]
+    }
+
+    pub fn track_mut(&mut self, halftrack: usize) -> &mut Vec<u8> {
+        &mut self.tracks[halftrack]
+    }
+
+    pub fn speed(&self, halftrack: usize) -> u8 {
+        self.speeds[halftrack]
+    }
+}
+
+// A G64 image seen at the DOS level. Sectors are decoded from the surface;
+// tracks with changed sectors are formatted again on saving, the others
+// keep their original bits (and any copy protection).
+pub struct G64 {
+    disk: GcrDisk,
+    d64: D64,
+    changed: Vec<bool>,
+}
+
+impl G64 {
+    pub fn from_bytes(data: &[u8]) -> Result<G64, String> {
+        let disk = try!(GcrDisk::from_g64(data));
+        let tracks = if disk.track((36 - 1) * 2).is_empty() {
+            35
+        } else {
+            40
+        };
+        let mut d64 = D64::blank(tracks);
+        disk.decode_into(&mut d64);
+        Ok(G64 {
+            disk: disk,
+            d64: d64,
+            changed: vec![false; tracks as usize + 1],
+        })
+    }
+
+    pub fn new(name: &[u8], id: &[u8]) -> G64 {
+        let d64 = D64::new(name, id, 35);
+        G64 {
+            disk: GcrDisk::from_d64(&d64),
+            d64: d64,
+            changed: vec![false; 36],
+        }
+    }
+}
+
+impl DiskImage for G64 {
+    fn tracks(&self) -> u8 {
+        self.d64.tracks()
+    }
+
+    fn sectors(&self, track: u8) -> u8 {
+        self.d64.sectors(track)
+    }
+
+    fn read_sector(&self, track: u8, sector: u8) -> Option<&[u8]> {
+        self.d64.read_sector(track, sector)
+    }
+
+    fn write_sector(&mut self, track: u8, sector: u8, buf: &[u8]) -> bool {
+        let written = self.d64.write_sector(track, sector, buf);
+        if written {
+            self.changed[track as usize] = true;
+        }
+        written
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut disk = self.disk.clone();
+        for t in 1..self.d64.tracks() + 1 {
+            if self.changed[t as usize] {
+                let ix = (t as usize - 1) * 2;
+                disk.tracks[ix] = encode_track(&self.d64, t);
+                disk.speeds[ix] = speed_zone(t);
+            }
+        }
+        disk.to_g64()
+    }
+
+    fn format(&mut self, name: &[u8], id: &[u8]) {
+        self.d64.format(name, id);
+        for c in self.changed.iter_mut() {
+            *c = true;
+        }
+    }
+
+    fn header(&self) -> (Vec<u8>, Vec<u8>) {
+        self.d64.header()
+    }
+
+    fn dir_start(&self) -> (u8, u8) {
+        self.d64.dir_start()
+    }
+
+    fn is_free(&self, track: u8, sector: u8) -> bool {
+        self.d64.is_free(track, sector)
+    }
+
+    fn set_free(&mut self, track: u8, sector: u8, free: bool) {
+        self.d64.set_free(track, sector, free);
+        self.changed[DIR_TRACK as usize] = true;
+    }
+
+    fn alloc_tracks(&self) -> Vec<u8> {
+        self.d64.alloc_tracks()
+    }
+
+    fn interleave(&self) -> (u8, u8) {
+        self.d64.interleave()
+    }
+}
+
+// A track as the DOS formats it, its sectors spread evenly around
+fn encode_track(d64: &D64, t: u8) -> Vec<u8> {
+    let (_, id) = d64.header();
+    let zone = speed_zone(t);
+    let sectors = sectors_per_track(t);
+    let mut track = Vec::new();
+    for s in 0..sectors {
+        encode_sector(&mut track, d64, t, s, id[0], id[1]);
+    }
+
+    // Spread what is left over as gaps between the sectors
+    let size = track_size(zone);
+    let gap = size.saturating_sub(track.len()) / sectors as usize;
+    let mut spaced = Vec::with_capacity(size);
+    let sector_len = track.len() / sectors as usize;
+    for s in 0..sectors as usize {
+        spaced.extend_from_slice(&track[s * sector_len..(s + 1) * sector_len]);
+        spaced.extend(vec![GAP_BYTE; gap]);
+    }
+    while spaced.len() < size {
+        spaced.push(GAP_BYTE);
+    }
+    spaced
+}
+
+fn encode_sector(out: &mut Vec<u8>, d64: &D64, track: u8, sector: u8, id1: u8, id2: u8) {
+    let error = d64.sector_error(track, sector);
+    let data = d64.read_sector(track, sector).unwrap();
+
+    // 21 READ ERROR: no sync mark at all
+    let sync = if error == 3 { 0x55 } else { 0xFF };
+    out.extend(vec![sync; SYNC_LEN]);
+
+    let mut header = vec![HEADER_MARK, 0, sector, track, id2, id1, 0x0F, 0x0F];
+    header[1] = sector ^ track ^ id2 ^ id1;
+    match error {
+        2 => header[0] = 0x00,     // 20 READ ERROR: header not found
+        9 => header[1] ^= 0xFF,    // 27 READ ERROR: header checksum
+        0x0B => header[4] ^= 0xFF, // 29 DISK ID MISMATCH
+        _ => {}
+    }
+    out.extend(gcr_encode(&header));
+    out.extend(vec![GAP_BYTE; HEADER_GAP]);
+
+    out.extend(vec![sync; SYNC_LEN]);
+    let mut block = vec![DATA_MARK];
+    block.extend_from_slice(data);
+    block.push(data.iter().fold(0, |acc, &b| acc ^ b));
+    block.push(0);
+    block.push(0);
+    match error {
+        4 => block[0] = 0x00,    // 22 READ ERROR: data block not found
+        5 => block[257] ^= 0xFF, // 23 READ ERROR: data checksum
+        _ => {}
+    }
+    out.extend(gcr_encode(&block));
+}
+
+// Find the sectors on a track by their header and data blocks
+fn decode_track(track: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut sectors = Vec::new();
+    let len = track.len();
+    if len == 0 {
+        return sectors;
+    }
+
+    // Start just past a sync mark so none is split across the wrap point
+    let start = (0..len).find(|&i| track[i] != 0xFF && track[(i + len - 1) % len] == 0xFF);
+    let start = match start {
+        Some(s) => s,
+        None => return sectors,
+    };
+    let byte = |i: usize| track[(start + i) % len];
+    let read = |from: usize, n: usize| (from..from + n).map(|i| byte(i)).collect::<Vec<u8>>();
+
+    let mut i = 0;
+    let mut header: Option<u8> = None;
+    while i < len {
+        // Bytes following a sync mark
+        if byte(i) != 0xFF && byte(i + len - 1) == 0xFF {
+            if let Some(block) = gcr_decode(&read(i, 10)) {
+                if block[0] == HEADER_MARK {
+                    header = Some(block[2]);
+                } else if block[0] == DATA_MARK {
+                    if let Some(sector) = header.take() {
+                        if let Some(block) = gcr_decode(&read(i, 325)) {
+                            sectors.push((sector, block[1..257].to_vec()));
+                        }
+                    }
+                }
+            }
+        }
+        i += 1;
+    }
+    sectors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_round_trip() {
+        let mut d64 = D64::new(b"GCR", b"AB", 35);
+        let data: Vec<u8> = (0..SECTOR_SIZE).map(|i| (i * 7) as u8).collect();
+        d64.write_sector(1, 3, &data);
+
+        let track = encode_track(&d64, 1);
+        assert_eq!(track.len(), track_size(speed_zone(1)));
+
+        // Every block after a sync mark carries a correct checksum
+        let (mut headers, mut blocks) = (0, 0);
+        for i in 1..track.len() {
+            if track[i] == 0xFF || track[i - 1] != 0xFF {
+                continue;
+            }
+            let block = gcr_decode(&track[i..i + 10]).unwrap();
+            if block[0] == HEADER_MARK {
+                assert_eq!(block[3], 1);
+                assert_eq!((block[4], block[5]), (b'B', b'A'));
+                assert_eq!(block[1], block[2] ^ block[3] ^ block[4] ^ block[5]);
+                headers += 1;
+            } else {
+                let block = gcr_decode(&track[i..i + 325]).unwrap();
+                assert_eq!(block[0], DATA_MARK);
+                assert_eq!(block[257], block[1..257].iter().fold(0, |acc, &b| acc ^ b));
+                blocks += 1;
+            }
+        }
+        assert_eq!((headers, blocks), (21, 21));
+
+        let sectors = decode_track(&track);
+        assert_eq!(sectors.len(), 21);
+        for (s, &(sector, ref block)) in sectors.iter().enumerate() {
+            assert_eq!(sector as usize, s);
+            assert!(&block[..] == d64.read_sector(1, sector).unwrap());
+        }
+        assert!(sectors[3].1 == data);
+    }
+}
@@ -0,0 +1,350 @@
+use container::*;
+use d64::*;
+use drive::*;
+use petscii::*;
+use serial::*;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use utils::*;
+
+// Free space reported in listings, that of an empty disk so programs do
+// not refuse to save
+const HOST_BLOCKS_FREE: u16 = 664;
+
+// Where the contents of a file come from
+#[derive(Clone, Copy, PartialEq)]
+enum Source {
+    Raw,
+    P00,
+    T64(usize),
+}
+
+struct HostFile {
+    path: PathBuf,
+    name: Vec<u8>, // PETSCII
+    file_type: u8,
+    blocks: u16,
+    source: Source,
+}
+
+impl HostFile {
+    fn data(&self) -> Option<Vec<u8>> {
+        let data = match load_file(&self.path.to_string_lossy()) {
+            Some(d) => d,
+            None => return None,
+        };
+        match self.source {
+            Source::Raw => Some(data),
+            Source::P00 => read_p00(&data).ok().map(|(_, prg)| prg),
+            Source::T64(ix) => read_t64(&data).ok().and_then(|mut e| {
+                if ix < e.len() {
+                    Some(e.swap_remove(ix).data)
+                } else {
+                    None
+                }
+            }),
+        }
+    }
+}
+
+fn blocks(len: usize) -> u16 {
+    ((len + 253) / 254) as u16
+}
+
+// Type of a PC64 file from its extension (.p00, .s01, .u02 ...)
+fn pc64_type(ext: &str) -> Option<u8> {
+    let b = ext.as_bytes();
+    if b.len() != 3 || !b[1..].iter().all(|&c| (c as char).is_digit(10)) {
+        return None;
+    }
+    match b[0] {
+        b'p' => Some(FILE_PRG),
+        b's' => Some(FILE_SEQ),
+        b'u' => Some(FILE_USR),
+        _ => None,
+    }
+}
+
+// Map a host file to CBM files. The extension gives the type; P00 files
+// carry their C64 name and a T64 archive shows each of its programs.
+fn host_files(path: &Path) -> Vec<HostFile> {
+    let meta = match fs::metadata(path) {
+        Ok(ref m) if m.is_file() => m.clone(),
+        _ => return Vec::new(),
+    };
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    let stem = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(s) => s,
+        None => return Vec::new(),
+    };
+    let file = |name: Vec<u8>, file_type: u8, len: usize, source: Source| HostFile {
+        path: path.to_path_buf(),
+        name: name,
+        file_type: file_type,
+        blocks: blocks(len),
+        source: source,
+    };
+
+    match ext.as_str() {
+        "" | "prg" => vec![file(
+            str_to_petscii(stem),
+            FILE_PRG,
+            meta.len() as usize,
+            Source::Raw,
+        )],
+        "seq" => vec![file(
+            str_to_petscii(stem),
+            FILE_SEQ,
+            meta.len() as usize,
+            Source::Raw,
+        )],
+        "usr" => vec![file(
+            str_to_petscii(stem),
+            FILE_USR,
+            meta.len() as usize,
+            Source::Raw,
+        )],
+        "t64" => match load_file(&path.to_string_lossy()).map(|d| read_t64(&d)) {
+            Some(Ok(entries)) => entries
+                .into_iter()
+                .enumerate()
+                .map(|(ix, e)| file(e.name, FILE_PRG, e.data.len(), Source::T64(ix)))
+                .collect(),
+            _ => Vec::new(),
+        },
+        _ => match (pc64_type(&ext), load_file(&path.to_string_lossy())) {
+            (Some(file_type), Some(data)) => match read_p00(&data) {
+                Ok((name, prg)) => vec![file(name, file_type, prg.len(), Source::P00)],
+                Err(_) => Vec::new(),
+            },
+            _ => Vec::new(),
+        },
+    }
+}
+
+fn type_extension(file_type: u8) -> &'static str {
+    match file_type {
+        FILE_SEQ => "seq",
+        FILE_USR => "usr",
+        _ => "prg",
+    }
+}
+
+// Host file name for a CBM name, or None if it can't be one
+fn host_name(name: &[u8], file_type: u8) -> Option<String> {
+    if name.is_empty() || name.iter().any(|&c| c == b'*' || c == b'?' || c == b'/') {
+        return None;
+    }
+    let stem: String = name.iter().filter_map(|&c| petscii_to_char(c)).collect();
+    Some(format!("{}.{}", stem, type_extension(file_type)))
+}
+
+// Drive mapped to a directory on the host, served at the DOS level
+pub struct HostDrive {
+    dir: String,
+    channels: HashMap<u8, Channel>,
+    command: Vec<u8>,
+    status: DosStatus,
+}
+
+impl HostDrive {
+    pub fn new(dir: &str) -> HostDrive {
+        HostDrive {
+            dir: dir.to_string(),
+            channels: HashMap::new(),
+            command: Vec::new(),
+            status: DosStatus::new(),
+        }
+    }
+
+    fn files(&self) -> Vec<HostFile> {
+        dir_entries(&self.dir)
+            .iter()
+            .flat_map(|p| host_files(p))
+            .collect()
+    }
+
+    fn find(&self, pattern: &[u8], file_type: Option<u8>) -> Option<HostFile> {
+        self.files().into_iter().find(|f| {
+            name_matches(pattern, &f.name) && file_type.map_or(true, |t| t == f.file_type)
+        })
+    }
+
+    fn listing(&self) -> Vec<u8> {
+        let header = match Path::new(&self.dir).canonicalize() {
+            Ok(p) => p
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map(str_to_petscii)
+                .unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        let entries: Vec<ListingEntry> = self
+            .files()
+            .into_iter()
+            .map(|f| ListingEntry {
+                name: f.name,
+                type_name: match f.file_type {
+                    FILE_SEQ => "SEQ",
+                    FILE_USR => "USR",
+                    _ => "PRG",
+                },
+                blocks: f.blocks,
+                closed: true,
+                locked: false,
+            })
+            .collect();
+        basic_listing(&header, b"HD 2A", &entries, HOST_BLOCKS_FREE)
+    }
+
+    fn execute(&mut self, cmd: &[u8]) {
+        let cmd: Vec<u8> = cmd.iter().cloned().filter(|&c| c != b'\r').collect();
+        if cmd.is_empty() {
+            return;
+        }
+
+        let arg = match cmd.iter().position(|&c| c == b':') {
+            Some(ix) => cmd[ix + 1..].to_vec(),
+            None => Vec::new(),
+        };
+        match cmd[0] {
+            b'I' | b'V' => self.status.ok(),
+            b'S' => {
+                let mut count = 0;
+                for pattern in arg.split(|&c| c == b',') {
+                    // Programs inside a T64 archive can't be scratched
+                    for f in self.files().iter().filter(|f| {
+                        name_matches(pattern, &f.name)
+                            && match f.source {
+                                Source::T64(_) => false,
+                                _ => true,
+                            }
+                    }) {
+                        if fs::remove_file(&f.path).is_ok() {
+                            count += 1;
+                        }
+                    }
+                }
+                self.status.set(1, " FILES SCRATCHED", count, 0);
+            }
+            b'R' => {
+                let eq = match arg.iter().position(|&c| c == b'=') {
+                    Some(eq) => eq,
+                    None => return self.status.set(31, "SYNTAX ERROR", 0, 0),
+                };
+                let old = match self.find(&arg[eq + 1..], None) {
+                    Some(f) => f,
+                    None => return self.status.set(62, "FILE NOT FOUND", 0, 0),
+                };
+                if self.find(&arg[..eq], None).is_some() {
+                    return self.status.set(63, "FILE EXISTS", 0, 0);
+                }
+                if old.source != Source::Raw {
+                    return self.status.set(26, "WRITE PROTECT ON", 0, 0);
+                }
+                match host_name(&arg[..eq], old.file_type) {
+                    Some(name) => match fs::rename(&old.path, Path::new(&self.dir).join(name)) {
+                        Ok(_) => self.status.ok(),
+                        Err(_) => self.status.set(26, "WRITE PROTECT ON", 0, 0),
+                    },
+                    None => self.status.set(33, "SYNTAX ERROR", 0, 0),
+                }
+            }
+            _ => self.status.set(31, "SYNTAX ERROR", 0, 0),
+        }
+    }
+}
+
+impl SerialDevice for HostDrive {
+    fn open(&mut self, sa: u8, name: &[u8]) {
+        if sa == COMMAND_CHANNEL {
+            return self.execute(name);
+        }
+
+        let spec = FileSpec::parse(name, sa);
+        if spec.write {
+            if host_name(&spec.name, FILE_PRG).is_none() {
+                return self.status.set(33, "SYNTAX ERROR", 0, 0);
+            }
+            if !spec.replace && self.find(&spec.name, None).is_some() {
+                return self.status.set(63, "FILE EXISTS", 0, 0);
+            }
+            self.channels.insert(sa, Channel::writer(spec));
+            return self.status.ok();
+        }
+
+        let data = if spec.name == b"$" {
+            Some(self.listing())
+        } else {
+            self.find(&spec.name, spec.file_type).and_then(|f| f.data())
+        };
+        match data {
+            Some(data) => {
+                self.channels.insert(sa, Channel::reader(data));
+                self.status.ok();
+            }
+            None => self.status.set(62, "FILE NOT FOUND", 0, 0),
+        }
+    }
+
+    fn close(&mut self, sa: u8) {
+        let channel = match self.channels.remove(&sa) {
+            Some(c) => c,
+            None => return,
+        };
+
+        if let Some(ref spec) = channel.write {
+            let file_type = spec
+                .file_type
+                .unwrap_or(if sa <= 1 { FILE_PRG } else { FILE_SEQ });
+
+            // Replacing may change the type, so remove the old file first
+            if let Some(old) = self.find(&spec.name, None) {
+                if old.source == Source::Raw {
+                    let _ = fs::remove_file(&old.path);
+                }
+            }
+            let path = Path::new(&self.dir).join(host_name(&spec.name, file_type).unwrap());
+            let result = File::create(&path).and_then(|mut f| f.write_all(&channel.data));
+            match result {
+                Ok(()) => self.status.ok(),
+                Err(e) => {
+                    println!("Couldn't write {}: {:?}", path.display(), e);
+                    self.status.set(25, "WRITE ERROR", 0, 0)
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, sa: u8, val: u8) {
+        if sa == COMMAND_CHANNEL {
+            self.command.push(val);
+        } else if let Some(channel) = self.channels.get_mut(&sa) {
+            channel.write(val);
+        }
+    }
+
+    fn unlisten(&mut self, sa: u8) {
+        if sa == COMMAND_CHANNEL {
+            let cmd = self.command.split_off(0);
+            self.execute(&cmd);
+        }
+    }
+
+    fn read(&mut self, sa: u8) -> Option<(u8, bool)> {
+        if sa == COMMAND_CHANNEL {
+            return Some(self.status.read());
+        }
+        match self.channels.get_mut(&sa) {
+            Some(channel) => channel.read(),
+            None => None,
+        }
+    }
+}
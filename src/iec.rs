@@ -0,0 +1,138 @@
+// Serial (IEC) bus: open collector ATN, CLK and DATA lines shared by the
+// computer and any number of peripherals. A line is low when anyone pulls it.
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct IecLines {
+    pub atn: bool,
+    pub clk: bool,
+    pub data: bool,
+}
+
+impl IecLines {
+    pub fn released() -> IecLines {
+        IecLines {
+            atn: false,
+            clk: false,
+            data: false,
+        }
+    }
+
+    // Wired-AND of two sets of drivers
+    pub fn or(&self, other: &IecLines) -> IecLines {
+        IecLines {
+            atn: self.atn || other.atn,
+            clk: self.clk || other.clk,
+            data: self.data || other.data,
+        }
+    }
+
+    fn pulled(&self) -> String {
+        format!(
+            "{}{}{}",
+            if self.atn { 'A' } else { '-' },
+            if self.clk { 'C' } else { '-' },
+            if self.data { 'D' } else { '-' }
+        )
+    }
+}
+
+// A peripheral emulated at the bus level
+pub trait IecDevice {
+    // Run the device for the given cycles, with the lines as pulled by
+    // everyone else on the bus
+    fn clock(&mut self, others: &IecLines, cycles: u8);
+    // Lines the device pulls low
+    fn lines(&self, atn: bool) -> IecLines;
+
+    // Media handling for devices that take disks or paper
+    fn insert(&mut self, _filename: &str) -> bool {
+        false
+    }
+    fn eject(&mut self) {}
+    fn flush(&mut self) {}
+}
+
+pub struct IecBus {
+    devices: Vec<(u8, Box<IecDevice>)>,
+    lines: IecLines,
+    cycle: u64,
+    trace: bool,
+}
+
+impl IecBus {
+    pub fn new() -> IecBus {
+        IecBus {
+            devices: Vec::new(),
+            lines: IecLines::released(),
+            cycle: 0,
+            trace: false,
+        }
+    }
+
+    pub fn attach(&mut self, device: u8, dev: Box<IecDevice>) {
+        self.detach(device);
+        self.devices.push((device, dev));
+    }
+
+    pub fn detach(&mut self, device: u8) {
+        self.devices.retain(|&(d, _)| d != device);
+    }
+
+    pub fn device_mut(&mut self, device: u8) -> Option<&mut Box<IecDevice>> {
+        self.devices
+            .iter_mut()
+            .find(|&&mut (d, _)| d == device)
+            .map(|&mut (_, ref mut dev)| dev)
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn flush(&mut self) {
+        for &mut (_, ref mut dev) in self.devices.iter_mut() {
+            dev.flush();
+        }
+    }
+
+    // Run the devices in step with the computer, given the lines the
+    // computer pulls. Returns the resulting line states.
+    pub fn clock(&mut self, host: IecLines, cycles: u8) -> IecLines {
+        let atn = host.atn;
+        for ix in 0..self.devices.len() {
+            let mut others = host;
+            for (jx, &(_, ref dev)) in self.devices.iter().enumerate() {
+                if jx != ix {
+                    others = others.or(&dev.lines(atn));
+                }
+            }
+            self.devices[ix].1.clock(&others, cycles);
+        }
+        self.cycle += cycles as u64;
+
+        let lines = self
+            .devices
+            .iter()
+            .fold(host, |acc, &(_, ref dev)| acc.or(&dev.lines(atn)));
+        if self.trace && lines != self.lines {
+            self.trace_lines(&host, &lines);
+        }
+        self.lines = lines;
+        lines
+    }
+
+    fn trace_lines(&self, host: &IecLines, lines: &IecLines) {
+        let mut drivers = format!("host:{}", host.pulled());
+        for &(d, ref dev) in self.devices.iter() {
+            drivers += &format!(" {}:{}", d, dev.lines(host.atn).pulled());
+        }
+        println!(
+            "IEC {:>10}  ATN={} CLK={} DATA={}  {}",
+            self.cycle,
+            if lines.atn { 0 } else { 1 },
+            if lines.clk { 0 } else { 1 },
+            if lines.data { 0 } else { 1 },
+            drivers
+        );
+    }
+}
@@ -1,4 +1,134 @@
-use sdl2::keyboard::Scancode;
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Scancode, LSHIFTMOD, RSHIFTMOD};
+use std::collections::HashMap;
+use utils::*;
+
+// Matrix position of the left SHIFT key (row 1, column 7)
+const LSHIFT_ROW: usize = 1;
+const LSHIFT_COL: u8 = 7;
+
+// Built-in symbolic keymap. Each line is "<host key> <row> <col> <shift>":
+// the host key is a single character, U+XXXX for any character, or an SDL key
+// name for keys that do not type anything. Row is the CIA1 port A bit and col
+// the port B bit of the C64 key. Shift is 0 to release C64 SHIFT, 1 to press
+// it and 2 to follow the host shift keys.
+static DEFAULT_KEYMAP: &'static str = "
+# Letters
+a 1 2 0
+b 3 4 0
+c 2 4 0
+d 2 2 0
+e 1 6 0
+f 2 5 0
+g 3 2 0
+h 3 5 0
+i 4 1 0
+j 4 2 0
+k 4 5 0
+l 5 2 0
+m 4 4 0
+n 4 7 0
+o 4 6 0
+p 5 1 0
+q 7 6 0
+r 2 1 0
+s 1 5 0
+t 2 6 0
+u 3 6 0
+v 3 7 0
+w 1 1 0
+x 2 7 0
+y 3 1 0
+z 1 4 0
+A 1 2 1
+B 3 4 1
+C 2 4 1
+D 2 2 1
+E 1 6 1
+F 2 5 1
+G 3 2 1
+H 3 5 1
+I 4 1 1
+J 4 2 1
+K 4 5 1
+L 5 2 1
+M 4 4 1
+N 4 7 1
+O 4 6 1
+P 5 1 1
+Q 7 6 1
+R 2 1 1
+S 1 5 1
+T 2 6 1
+U 3 6 1
+V 3 7 1
+W 1 1 1
+X 2 7 1
+Y 3 1 1
+Z 1 4 1
+# Digits and their shifted symbols
+1 7 0 0
+2 7 3 0
+3 1 0 0
+4 1 3 0
+5 2 0 0
+6 2 3 0
+7 3 0 0
+8 3 3 0
+9 4 0 0
+0 4 3 0
+! 7 0 1
+\" 7 3 1
+U+0023 1 0 1
+$ 1 3 1
+% 2 0 1
+& 2 3 1
+' 3 0 1
+( 3 3 1
+) 4 0 1
+# Punctuation
++ 5 0 0
+- 5 3 0
+. 5 4 0
+: 5 5 0
+@ 5 6 0
+, 5 7 0
+* 6 1 0
+; 6 2 0
+= 6 5 0
+^ 6 6 0
+/ 6 7 0
+_ 7 1 0
+< 5 7 1
+> 5 4 1
+[ 5 5 1
+] 6 2 1
+? 6 7 1
+£ 6 0 0
+\\ 6 0 0
+# Keys that do not type characters
+Space 7 4 2
+Return 0 1 2
+Backspace 0 0 2
+Delete 0 0 2
+Insert 0 0 1
+Right 0 2 0
+Left 0 2 1
+Down 0 7 0
+Up 0 7 1
+F1 0 4 0
+F2 0 4 1
+F3 0 5 0
+F4 0 5 1
+F5 0 6 0
+F6 0 6 1
+F7 0 3 0
+F8 0 3 1
+Home 6 3 2
+Tab 7 2 2
+Left Ctrl 7 5 2
+Pause 7 7 2
+";
 
 // Keyboard scan codes
 static KBD_ROWS: &'static [[Scancode; 8]; 8] = &[
@@ -92,23 +222,359 @@ static KBD_ROWS: &'static [[Scancode; 8]; 8] = &[
     ],
 ];
 
-pub fn kbd_scancode(keys: &Vec<Scancode>, col_mask: u8) -> u8 {
-    if keys.is_empty() {
-        return 0xFF; // No key pressed
+// PETSCII codes produced by each key, as (petscii, row, col, shifted)
+static PETSCII_KEYS: &'static [(u8, usize, u8, bool)] = &[
+    (0x14, 0, 0, false), // DEL
+    (0x94, 0, 0, true),  // INST
+    (0x0D, 0, 1, false), // RETURN
+    (0x1D, 0, 2, false), // CRSR right
+    (0x9D, 0, 2, true),  // CRSR left
+    (0x88, 0, 3, false), // F7
+    (0x8C, 0, 3, true),  // F8
+    (0x85, 0, 4, false), // F1
+    (0x89, 0, 4, true),  // F2
+    (0x86, 0, 5, false), // F3
+    (0x8A, 0, 5, true),  // F4
+    (0x87, 0, 6, false), // F5
+    (0x8B, 0, 6, true),  // F6
+    (0x11, 0, 7, false), // CRSR down
+    (0x91, 0, 7, true),  // CRSR up
+    (b'3', 1, 0, false),
+    (b'#', 1, 0, true),
+    (b'W', 1, 1, false),
+    (b'A', 1, 2, false),
+    (b'4', 1, 3, false),
+    (b'$', 1, 3, true),
+    (b'Z', 1, 4, false),
+    (b'S', 1, 5, false),
+    (b'E', 1, 6, false),
+    (b'5', 2, 0, false),
+    (b'%', 2, 0, true),
+    (b'R', 2, 1, false),
+    (b'D', 2, 2, false),
+    (b'6', 2, 3, false),
+    (b'&', 2, 3, true),
+    (b'C', 2, 4, false),
+    (b'F', 2, 5, false),
+    (b'T', 2, 6, false),
+    (b'X', 2, 7, false),
+    (b'7', 3, 0, false),
+    (b'\'', 3, 0, true),
+    (b'Y', 3, 1, false),
+    (b'G', 3, 2, false),
+    (b'8', 3, 3, false),
+    (b'(', 3, 3, true),
+    (b'B', 3, 4, false),
+    (b'H', 3, 5, false),
+    (b'U', 3, 6, false),
+    (b'V', 3, 7, false),
+    (b'9', 4, 0, false),
+    (b')', 4, 0, true),
+    (b'I', 4, 1, false),
+    (b'J', 4, 2, false),
+    (b'0', 4, 3, false),
+    (b'M', 4, 4, false),
+    (b'K', 4, 5, false),
+    (b'O', 4, 6, false),
+    (b'N', 4, 7, false),
+    (b'+', 5, 0, false),
+    (b'P', 5, 1, false),
+    (b'L', 5, 2, false),
+    (b'-', 5, 3, false),
+    (b'.', 5, 4, false),
+    (b'>', 5, 4, true),
+    (b':', 5, 5, false),
+    (b'[', 5, 5, true),
+    (b'@', 5, 6, false),
+    (b',', 5, 7, false),
+    (b'<', 5, 7, true),
+    (0x5C, 6, 0, false), // Pound
+    (b'*', 6, 1, false),
+    (b';', 6, 2, false),
+    (b']', 6, 2, true),
+    (0x13, 6, 3, false), // HOME
+    (0x93, 6, 3, true),  // CLR
+    (b'=', 6, 5, false),
+    (0x5E, 6, 6, false), // Up arrow
+    (b'/', 6, 7, false),
+    (b'?', 6, 7, true),
+    (b'1', 7, 0, false),
+    (b'!', 7, 0, true),
+    (0x5F, 7, 1, false), // Left arrow
+    (b'2', 7, 3, false),
+    (b'"', 7, 3, true),
+    (b' ', 7, 4, false),
+    (b'Q', 7, 6, false),
+];
+
+// Find the key (row, col, shifted) that types a PETSCII character
+pub fn petscii_key(p: u8) -> Option<(usize, u8, bool)> {
+    if p >= 0xC1 && p <= 0xDA {
+        // Shifted letters
+        return petscii_key(p - 0x80).map(|(row, col, _)| (row, col, true));
+    }
+    PETSCII_KEYS
+        .iter()
+        .find(|k| k.0 == p)
+        .map(|&(_, row, col, shift)| (row, col, shift))
+}
+
+// Matrix with a single key (and optionally SHIFT) held down
+pub fn kbd_key_matrix(row: usize, col: u8, shift: bool) -> [u8; 8] {
+    let mut matrix = [0; 8];
+    matrix[row] |= 1 << col;
+    if shift {
+        matrix[LSHIFT_ROW] |= 1 << LSHIFT_COL;
+    }
+    matrix
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyboardMode {
+    Positional,
+    Symbolic,
+}
+
+impl KeyboardMode {
+    pub fn from_str(s: &str) -> Option<KeyboardMode> {
+        match s {
+            "positional" => Some(KeyboardMode::Positional),
+            "symbolic" => Some(KeyboardMode::Symbolic),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ShiftMode {
+    Released,
+    Pressed,
+    Host,
+}
+
+#[derive(Clone, Copy)]
+struct KeyMapping {
+    row: usize,
+    col: u8,
+    shift: ShiftMode,
+}
+
+// Maps the characters typed on the host to C64 keys, pressing or releasing
+// the C64 SHIFT key as needed to produce the same character
+pub struct SymbolicKeyboard {
+    chars: HashMap<char, KeyMapping>,
+    keys: HashMap<Keycode, KeyMapping>,
+    pressed: Vec<(Scancode, KeyMapping)>,
+    released: Vec<Scancode>,
+    pending: Option<Scancode>,
+    host_shift: bool,
+}
+
+impl SymbolicKeyboard {
+    pub fn new() -> SymbolicKeyboard {
+        let mut kbd = SymbolicKeyboard {
+            chars: HashMap::new(),
+            keys: HashMap::new(),
+            pressed: Vec::new(),
+            released: Vec::new(),
+            pending: None,
+            host_shift: false,
+        };
+        kbd.parse_keymap(DEFAULT_KEYMAP).unwrap();
+        kbd
+    }
+
+    // Load a keymap file on top of the built-in symbolic keymap
+    pub fn load(filename: &str) -> Option<SymbolicKeyboard> {
+        let data = match load_file(filename) {
+            Some(d) => d,
+            None => return None,
+        };
+        let text = match String::from_utf8(data) {
+            Ok(t) => t,
+            Err(_) => {
+                println!("Keymap {} is not valid UTF-8", filename);
+                return None;
+            }
+        };
+
+        let mut kbd = SymbolicKeyboard::new();
+        match kbd.parse_keymap(&text) {
+            Ok(()) => Some(kbd),
+            Err(e) => {
+                println!("Error in keymap {}: {}", filename, e);
+                None
+            }
+        }
+    }
+
+    fn parse_keymap(&mut self, text: &str) -> Result<(), String> {
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("# ") || line == "#" {
+                continue;
+            }
+
+            // Key names may contain spaces ("Left Ctrl"), so take the
+            // numbers from the end of the line
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 {
+                return Err(format!(
+                    "line {}: expected <key> <row> <col> <shift>",
+                    lineno + 1
+                ));
+            }
+            let n = fields.len();
+            let parse = |s: &str, max: usize| match s.parse::<usize>() {
+                Ok(v) if v <= max => Ok(v),
+                _ => Err(format!("line {}: invalid value {}", lineno + 1, s)),
+            };
+            let mapping = KeyMapping {
+                row: try!(parse(fields[n - 3], 7)),
+                col: try!(parse(fields[n - 2], 7)) as u8,
+                shift: match try!(parse(fields[n - 1], 2)) {
+                    0 => ShiftMode::Released,
+                    1 => ShiftMode::Pressed,
+                    _ => ShiftMode::Host,
+                },
+            };
+
+            let host = fields[..n - 3].join(" ");
+            let host = host.as_str();
+            let mut host_chars = host.chars();
+            match (host_chars.next(), host_chars.next()) {
+                (Some(c), None) => {
+                    self.chars.insert(c, mapping);
+                }
+                _ if host.starts_with("U+") => match u32::from_str_radix(&host[2..], 16)
+                    .ok()
+                    .and_then(::std::char::from_u32)
+                {
+                    Some(c) => {
+                        self.chars.insert(c, mapping);
+                    }
+                    None => return Err(format!("line {}: invalid character {}", lineno + 1, host)),
+                },
+                _ => match Keycode::from_name(host) {
+                    Some(k) => {
+                        self.keys.insert(k, mapping);
+                    }
+                    None => return Err(format!("line {}: unknown key {}", lineno + 1, host)),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::KeyDown {
+                keycode: Some(k),
+                scancode: Some(sc),
+                keymod,
+                repeat: false,
+                ..
+            } => {
+                self.host_shift = keymod.intersects(LSHIFTMOD | RSHIFTMOD);
+                match self.keys.get(&k) {
+                    Some(&m) => self.press(sc, m),
+                    // Wait for the text input event to know the character
+                    None => self.pending = Some(sc),
+                }
+            }
+            Event::KeyUp {
+                scancode: Some(sc),
+                keymod,
+                ..
+            } => {
+                self.host_shift = keymod.intersects(LSHIFTMOD | RSHIFTMOD);
+                self.released.push(sc);
+                if self.pending == Some(sc) {
+                    self.pending = None;
+                }
+            }
+            Event::TextInput { ref text, .. } => {
+                if let Some(sc) = self.pending.take() {
+                    for c in text.chars() {
+                        if let Some(&m) = self.chars.get(&c) {
+                            self.press(sc, m);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
     }
 
-    //let col_mask = !self.io[0xDC00 - 0xD000];
-    let mut val = 0;
+    fn press(&mut self, sc: Scancode, m: KeyMapping) {
+        self.pressed.retain(|&(s, _)| s != sc);
+        self.released.retain(|&s| s != sc);
+        self.pressed.push((sc, m));
+    }
+
+    // Releases are applied after the matrix has been read once, so a key that
+    // was pressed and released between two refreshes is still seen by the C64
+    pub fn matrix(&mut self) -> [u8; 8] {
+        let mut matrix = [0; 8];
+        for &(_, m) in self.pressed.iter() {
+            matrix[m.row] |= 1 << m.col;
+        }
+
+        // The most recently pressed key decides the state of SHIFT
+        let shift = match self.pressed.last() {
+            Some(&(_, m)) => match m.shift {
+                ShiftMode::Released => false,
+                ShiftMode::Pressed => true,
+                ShiftMode::Host => self.host_shift,
+            },
+            None => false,
+        };
+        if shift {
+            matrix[LSHIFT_ROW] |= 1 << LSHIFT_COL;
+        } else {
+            matrix[LSHIFT_ROW] &= !(1 << LSHIFT_COL);
+        }
+
+        for sc in self.released.drain(..) {
+            self.pressed.retain(|&(s, _)| s != sc);
+        }
+        matrix
+    }
+}
+
+// Build the keyboard matrix for the pressed keys. Each entry is indexed by
+// the CIA1 port A bit driving the column and has a bit set for every port B
+// line that is connected through a pressed key.
+pub fn kbd_matrix(keys: &Vec<Scancode>) -> [u8; 8] {
+    let mut matrix = [0; 8];
     for sc in keys.iter() {
         for i in 0..8 {
-            if (col_mask & (1 << i)) > 0 {
-                match KBD_ROWS[i].iter().position(|&x| x == *sc) {
-                    Some(n) => val = val | (1 << n),
-                    None => (),
-                }
+            match KBD_ROWS[i].iter().position(|&x| x == *sc) {
+                Some(n) => matrix[i] |= 1 << n,
+                None => (),
             }
         }
     }
+    matrix
+}
 
-    !val
+// Resolve what CIA1 reads on port A and B given the levels both sides drive.
+// The lines are wired-AND, so a low line on one port pulls down every line on
+// the other port that it is connected to through a pressed key. This is also
+// how a joystick in port 1 shows up as key presses.
+pub fn kbd_resolve(matrix: &[u8; 8], pa: u8, pb: u8) -> (u8, u8) {
+    let mut pa_val = pa;
+    let mut pb_val = pb;
+    for i in 0..8 {
+        if matrix[i] == 0 {
+            continue;
+        }
+        if (pa & (1 << i)) == 0 {
+            pb_val &= !matrix[i];
+        }
+        if (!pb & matrix[i]) != 0 {
+            pa_val &= !(1 << i);
+        }
+    }
+    (pa_val, pb_val)
 }
@@ -0,0 +1,12 @@
+// Disk image handling, shared by the emulator and the c64disk tool, and
+// the 6502 assembler with its opcode table and symbols
+pub mod asm;
+pub mod d64;
+pub mod d71;
+pub mod d81;
+pub mod diskimage;
+pub mod gcr;
+pub mod opcodes;
+pub mod petscii;
+pub mod symbols;
+pub mod utils;
@@ -3,17 +3,34 @@ extern crate log;
 extern crate clap;
 extern crate env_logger;
 use clap::{App, Arg};
+extern crate retroc64;
 extern crate sdl2;
 extern crate time;
 
+// Disk images, the assembler and helpers from the library
+use retroc64::{asm, d64, diskimage, gcr, opcodes, petscii, symbols, utils};
+
+mod autotype;
+mod breakpoints;
+mod container;
 mod cpu;
 mod dasm;
+mod drive;
+mod drive1541;
 mod emu;
+mod hostdrive;
+mod iec;
 mod keyboard;
 mod memc64;
 mod memory;
-mod opcodes;
-mod utils;
+mod png;
+mod ports;
+mod printer;
+mod serial;
+mod sid;
+mod sourcemap;
+mod tape;
+mod via;
 mod vic;
 
 fn main() {
@@ -37,12 +54,228 @@ fn main() {
                 .help("Start address for Program Counter (hexadecimal) (default: FCE2)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("sid_model")
+                .long("sid")
+                .value_name("MODEL")
+                .help("SID chip revision to emulate (6581 or 8580) (default: 6581)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("digi_boost")
+                .long("digi-boost")
+                .help("Boost volume register samples on the 8580 SID"),
+        )
+        .arg(
+            Arg::with_name("port1")
+                .long("port1")
+                .value_name("DEVICE")
+                .help("Device in control port 1 (none, joystick, paddles or mouse) (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("port2")
+                .long("port2")
+                .value_name("DEVICE")
+                .help(
+                    "Device in control port 2 (none, joystick, paddles or mouse) (default: joystick)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("joy1_keys")
+                .long("joy1-keys")
+                .value_name("KEYS")
+                .help("Host keys for joystick 1: up,down,left,right,fire (SDL key names)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("joy2_keys")
+                .long("joy2-keys")
+                .value_name("KEYS")
+                .help("Host keys for joystick 2: up,down,left,right,fire (SDL key names)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keyboard")
+                .short("k")
+                .long("keyboard")
+                .value_name("MODE")
+                .help("Keyboard mapping (positional or symbolic) (default: positional)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keymap")
+                .long("keymap")
+                .value_name("FILE")
+                .help("Load a symbolic keymap file (implies --keyboard symbolic)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("autostart")
+                .short("a")
+                .long("autostart")
+                .value_name("FILE")
+                .help("Load and run a program once BASIC is ready")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("type")
+                .long("type")
+                .value_name("TEXT")
+                .help("Type text once BASIC is ready (\\n for RETURN)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("type_mode")
+                .long("type-mode")
+                .value_name("MODE")
+                .help("How text is typed (buffer or matrix) (default: buffer)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("disk")
+                .short("8")
+                .long("disk")
+                .value_name("FILE")
+                .help("Attach a D64 disk image as drive 8 (a G64 image with --true-drive)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("host_dir")
+                .long("host-dir")
+                .value_name("DIR")
+                .help("Serve a host directory as a disk drive")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("host_device")
+                .long("host-device")
+                .value_name("DEVICE")
+                .help("Device number of the host directory drive (8-30) (default: 8)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("printer")
+                .long("printer")
+                .value_name("NAME")
+                .help("Attach a printer as device 4, printing to NAME.txt and NAME.png")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("symbols")
+                .long("symbols")
+                .value_name("FILE")
+                .help("Load monitor labels from a VICE, ca65 .dbg or KickAssembler .sym file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tape")
+                .long("tape")
+                .value_name("FILE")
+                .help("Insert a TAP file in the datasette")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("true_drive")
+                .long("true-drive")
+                .help("Emulate the 1541 hardware (needs roms/1541) instead of trapping the KERNAL"),
+        )
         .get_matches();
 
     let s_addr = matches.value_of("start_addr").unwrap_or("FCE2");
     let start_addr = i64::from_str_radix(s_addr.trim(), 16).unwrap() as u16;
     let debug = matches.is_present("debug");
 
-    let mut c64 = emu::C64::new();
+    let s_model = matches.value_of("sid_model").unwrap_or("6581");
+    let sid_model = match sid::ChipModel::from_str(s_model) {
+        Some(m) => m,
+        None => {
+            println!("Unknown SID model: {}", s_model);
+            return;
+        }
+    };
+
+    let mut port_devices = [ports::PortDevice::None; 2];
+    for (ix, &(name, default)) in [("port1", "none"), ("port2", "joystick")]
+        .iter()
+        .enumerate()
+    {
+        let s_device = matches.value_of(name).unwrap_or(default);
+        port_devices[ix] = match ports::PortDevice::from_str(s_device) {
+            Some(d) => d,
+            None => {
+                println!("Unknown control port device: {}", s_device);
+                return;
+            }
+        };
+    }
+
+    let mut joy_keys = Vec::new();
+    for name in ["joy1_keys", "joy2_keys"].iter() {
+        let s_keys = matches.value_of(name).unwrap_or(ports::DEFAULT_JOY_KEYS);
+        match ports::JoyKeys::from_str(s_keys) {
+            Some(k) => joy_keys.push(k),
+            None => {
+                println!("Invalid joystick keys: {}", s_keys);
+                return;
+            }
+        }
+    }
+
+    let keymap = matches.value_of("keymap").map(|f| f.to_string());
+    let default_mode = if keymap.is_some() {
+        "symbolic"
+    } else {
+        "positional"
+    };
+    let s_mode = matches.value_of("keyboard").unwrap_or(default_mode);
+    let keyboard = match keyboard::KeyboardMode::from_str(s_mode) {
+        Some(m) => m,
+        None => {
+            println!("Unknown keyboard mode: {}", s_mode);
+            return;
+        }
+    };
+
+    let s_type_mode = matches.value_of("type_mode").unwrap_or("buffer");
+    let type_mode = match autotype::TypeMode::from_str(s_type_mode) {
+        Some(m) => m,
+        None => {
+            println!("Unknown type mode: {}", s_type_mode);
+            return;
+        }
+    };
+
+    let s_device = matches.value_of("host_device").unwrap_or("8");
+    let host_device = match s_device.parse::<u8>() {
+        Ok(d) if d >= 8 && d <= 30 => d,
+        _ => {
+            println!("Invalid host drive device number: {}", s_device);
+            return;
+        }
+    };
+
+    let config = emu::Config {
+        sid_model: sid_model,
+        digi_boost: matches.is_present("digi_boost"),
+        port1: port_devices[0],
+        port2: port_devices[1],
+        joy_keys: [joy_keys[0], joy_keys[1]],
+        keyboard: keyboard,
+        keymap: keymap,
+        type_text: matches.value_of("type").map(|t| t.to_string()),
+        type_mode: type_mode,
+        autostart: matches.value_of("autostart").map(|f| f.to_string()),
+        disk: matches.value_of("disk").map(|f| f.to_string()),
+        true_drive: matches.is_present("true_drive"),
+        host_dir: matches.value_of("host_dir").map(|d| d.to_string()),
+        host_device: host_device,
+        tape: matches.value_of("tape").map(|f| f.to_string()),
+        printer: matches.value_of("printer").map(|f| f.to_string()),
+        symbols: matches.value_of("symbols").map(|f| f.to_string()),
+    };
+
+    let mut c64 = emu::C64::new(&config);
     c64.start(start_addr, debug);
 }
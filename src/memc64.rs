@@ -1,23 +1,87 @@
+use breakpoints::*;
+use iec::*;
 use keyboard::*;
 use memory::*;
-use sdl2::keyboard::Scancode;
-use utils::*;
+use opcodes::*;
+use ports::*;
+use std::cell::{Cell, RefCell};
 use vic::*;
 
-//pub const CIA1_ICR: u16 = 0xDC0D;
+pub const CIA1_DATA_PORT_A: u16 = 0xDC00;
+pub const CIA1_DATA_PORT_B: u16 = 0xDC01;
+pub const CIA1_DATA_DIRECTION_A: u16 = 0xDC02;
+pub const CIA1_DATA_DIRECTION_B: u16 = 0xDC03;
+pub const CIA1_TIMER_B_LO: u16 = 0xDC06;
+pub const CIA1_TIMER_B_HI: u16 = 0xDC07;
+pub const CIA1_ICR: u16 = 0xDC0D;
+pub const CIA1_CRB: u16 = 0xDC0F;
 pub const CIA2_DATA_PORT_A: u16 = 0xDD00;
+pub const CIA2_DATA_DIRECTION_A: u16 = 0xDD02;
 pub const DATA_DIRECTION_PORT: u16 = 0;
 pub const IO_PORT: u16 = 1;
 
 const LORAM: u8 = 0;
 const HIRAM: u8 = 1;
 const CHAREN: u8 = 2;
+const CASSETTE_WRITE: u8 = 3;
+const CASSETTE_SENSE: u8 = 4;
+const CASSETTE_MOTOR: u8 = 5;
+
+const CIA_ICR_TIMER_A: u8 = 0x01;
+const CIA_ICR_TIMER_B: u8 = 0x02;
+const CIA_ICR_FLAG: u8 = 0x10;
+const CIA_ICR_IRQ: u8 = 0x80;
+const CIA_CR_START: u8 = 0x01;
+const CIA_CR_ONE_SHOT: u8 = 0x08;
+const CIA_CR_LOAD: u8 = 0x10;
+
+// CIA2 port A serial bus bits
+pub const IEC_ATN_OUT: u8 = 0x08;
+pub const IEC_CLK_OUT: u8 = 0x10;
+pub const IEC_DATA_OUT: u8 = 0x20;
+pub const IEC_CLK_IN: u8 = 0x40;
+pub const IEC_DATA_IN: u8 = 0x80;
+
+// Views of memory for the monitor: what the CPU sees, one of the
+// underlying banks (RAM wherever the bank has nothing), or the 16K the VIC
+// sees, from address 0
+#[derive(Clone, Copy, PartialEq)]
+pub enum MemView {
+    Cpu,
+    Ram,
+    Rom,
+    Io,
+    Vic,
+}
+
+impl MemView {
+    pub fn from_name(name: &str) -> Option<MemView> {
+        match name {
+            "cpu" => Some(MemView::Cpu),
+            "ram" => Some(MemView::Ram),
+            "rom" => Some(MemView::Rom),
+            "io" => Some(MemView::Io),
+            "vic" => Some(MemView::Vic),
+            _ => None,
+        }
+    }
+}
 
 pub struct MemC64 {
     rom: Vec<u8>,
     ram: Vec<u8>,
     io: Vec<u8>,
-    keys: Vec<Scancode>,
+    keys: [u8; 8],
+    ports: [PortState; 2],
+    cia1_timer_b: i32,
+    cia1_icr: Cell<u8>,
+    cia1_icr_mask: u8,
+    iec: RefCell<IecBus>,
+    iec_lines: Cell<u8>,
+    iec_access: u8,
+    iec_synced: Cell<u8>,
+    cassette_sense: bool,
+    watchpoints: Watchpoints,
 }
 
 impl MemC64 {
@@ -25,7 +89,7 @@ impl MemC64 {
         let rom = vec![0; 64 * 1024];
         let io = vec![0; 4 * 1024];
         let mut ram = vec![0; 64 * 1024];
-        let keys = Vec::new();
+        let keys = [0; 8];
 
         ram[DATA_DIRECTION_PORT as usize] = 0b101111;
         ram[IO_PORT as usize] = 0b111;
@@ -35,6 +99,16 @@ impl MemC64 {
             ram: ram,
             io: io,
             keys: keys,
+            ports: [PortState::new(), PortState::new()],
+            cia1_timer_b: 0xFFFF,
+            cia1_icr: Cell::new(0),
+            cia1_icr_mask: 0,
+            iec: RefCell::new(IecBus::new()),
+            iec_lines: Cell::new(IEC_CLK_IN | IEC_DATA_IN),
+            iec_access: 0,
+            iec_synced: Cell::new(0),
+            cassette_sense: false,
+            watchpoints: Watchpoints::new(),
         }
     }
 
@@ -52,20 +126,326 @@ impl MemC64 {
         }
     }
 
-    pub fn refresh(&mut self, keys: Vec<Scancode>) {
+    pub fn watchpoints(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    // Record an execute watchpoint hit for the instruction at pc
+    pub fn watch_execute(&self, pc: u16) {
+        if self.watchpoints.watches(pc, WATCH_EXECUTE) {
+            let val = self.cpu_read(pc);
+            self.watchpoints
+                .record(pc, WATCH_EXECUTE, val, val, self.cpu_bank(pc));
+        }
+    }
+
+    // Read memory for the monitor, without side effects on I/O registers
+    pub fn peek(&self, view: MemView, addr: u16) -> u8 {
+        let bank = match view {
+            MemView::Cpu => self.cpu_bank(addr),
+            MemView::Ram => Bank::Ram,
+            MemView::Rom => match addr {
+                0xA000...0xBFFF => Bank::BasicRom,
+                0xD000...0xDFFF => Bank::CharRom,
+                0xE000...0xFFFF => Bank::KernalRom,
+                _ => Bank::Ram,
+            },
+            MemView::Io => match addr {
+                0xD000...0xDFFF => Bank::Io,
+                _ => Bank::Ram,
+            },
+            MemView::Vic => return self.vic_read_byte(addr & 0x3FFF),
+        };
+        match bank {
+            Bank::Io if addr == CIA1_ICR => self.cia1_icr.get(),
+            Bank::Io => self.read_register(addr),
+            Bank::Ram if view == MemView::Cpu && addr <= IO_PORT => self.cpu_read(addr),
+            Bank::Ram => self.ram[addr as usize],
+            _ => self.rom[addr as usize],
+        }
+    }
+
+    // Write memory for the monitor. ROM can be patched through the rom view.
+    pub fn poke(&mut self, view: MemView, addr: u16, val: u8) {
+        match view {
+            MemView::Cpu => self.cpu_write(addr, val),
+            MemView::Rom if addr >= 0xA000 && (addr < 0xC000 || addr >= 0xD000) => {
+                self.rom[addr as usize] = val
+            }
+            MemView::Io if addr >= 0xD000 && addr < 0xE000 => self.write_register(addr, val),
+            MemView::Vic => {
+                let bank = 3 - (self.read_register(CIA2_DATA_PORT_A) & 0x03) as u16;
+                self.ram[((addr & 0x3FFF) + bank * 0x4000) as usize] = val;
+            }
+            _ => self.ram[addr as usize] = val,
+        }
+    }
+
+    // Which bank the CPU sees at an address
+    fn cpu_bank(&self, addr: u16) -> Bank {
+        let port = self.ram[IO_PORT as usize];
+        match addr {
+            0xA000...0xBFFF if (port & (1 << LORAM)) > 0 => Bank::BasicRom,
+            0xD000...0xDFFF if (port & (1 << CHAREN)) > 0 => Bank::Io,
+            0xD000...0xDFFF => Bank::CharRom,
+            0xE000...0xFFFF if (port & (1 << HIRAM)) > 0 => Bank::KernalRom,
+            _ => Bank::Ram,
+        }
+    }
+
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            IO_PORT => {
+                debug!(
+                    "Read IO_PORT (${}): ${:02X}",
+                    IO_PORT, self.ram[IO_PORT as usize]
+                );
+                // The cassette sense line is an input, low while a button is down
+                let mut val = self.ram[IO_PORT as usize];
+                if (self.ram[DATA_DIRECTION_PORT as usize] & (1 << CASSETTE_SENSE)) == 0 {
+                    val &= !(1 << CASSETTE_SENSE);
+                    if !self.cassette_sense {
+                        val |= 1 << CASSETTE_SENSE;
+                    }
+                }
+                val
+            }
+            DATA_DIRECTION_PORT => {
+                debug!(
+                    "Read DATA_DIRECTION_PORT (${}): ${:02X}",
+                    DATA_DIRECTION_PORT, self.ram[DATA_DIRECTION_PORT as usize]
+                );
+                self.ram[DATA_DIRECTION_PORT as usize]
+            }
+            0xA000...0xBFFF => {
+                if (self.ram[IO_PORT as usize] & (1 << LORAM)) > 0 {
+                    self.rom[addr as usize]
+                } else {
+                    self.ram[addr as usize]
+                }
+            }
+            0xD000...0xDFFF => {
+                if (self.ram[IO_PORT as usize] & (1 << CHAREN)) > 0 {
+                    if addr == CIA2_DATA_PORT_A {
+                        self.sync_iec();
+                    }
+                    self.read_register(addr)
+                } else {
+                    self.rom[addr as usize]
+                }
+            }
+            0xE000...0xFFFF => {
+                if (self.ram[IO_PORT as usize] & (1 << HIRAM)) > 0 {
+                    self.rom[addr as usize]
+                } else {
+                    self.ram[addr as usize]
+                }
+            }
+            _ => self.ram[addr as usize],
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            IO_PORT => {
+                debug!("Write IO_PORT (${}): ${:02X}", IO_PORT, val);
+                self.ram[IO_PORT as usize] = val;
+            }
+            DATA_DIRECTION_PORT => {
+                debug!(
+                    "Write DATA_DIRECTION_PORT (${}): ${:02X}",
+                    DATA_DIRECTION_PORT, val
+                );
+                self.ram[DATA_DIRECTION_PORT as usize] = val;
+            }
+            0xD000...0xDFFF => {
+                if (self.ram[IO_PORT as usize] & (1 << CHAREN)) > 0 {
+                    if addr == CIA2_DATA_PORT_A || addr == CIA2_DATA_DIRECTION_A {
+                        self.sync_iec();
+                    }
+                    self.write_register(addr, val);
+                } else {
+                    self.ram[addr as usize] = val;
+                }
+            }
+            _ => self.ram[addr as usize] = val,
+        }
+    }
+
+    // Update the keyboard matrix (see kbd_matrix)
+    pub fn refresh(&mut self, keys: [u8; 8]) {
         self.keys = keys;
     }
 
+    pub fn refresh_ports(&mut self, ports: [PortState; 2]) {
+        self.ports = ports;
+    }
+
+    pub fn kernal_mapped(&self) -> bool {
+        (self.ram[IO_PORT as usize] & (1 << HIRAM)) > 0
+    }
+
+    // Serial bus lines pulled low through CIA2 port A bits 3-5
+    pub fn iec_outputs(&self) -> IecLines {
+        let out = self.io[(CIA2_DATA_PORT_A - 0xD000) as usize]
+            & self.io[(CIA2_DATA_DIRECTION_A - 0xD000) as usize];
+        IecLines {
+            atn: (out & IEC_ATN_OUT) > 0,
+            clk: (out & IEC_CLK_OUT) > 0,
+            data: (out & IEC_DATA_OUT) > 0,
+        }
+    }
+
+    pub fn iec(&mut self) -> &mut IecBus {
+        self.iec.get_mut()
+    }
+
+    // Start an instruction. Its CIA2 port A access, if any, is taken to
+    // fall on its last cycle.
+    pub fn begin_iec(&mut self, pc: u16) {
+        let op = self.peek(MemView::Cpu, pc);
+        self.iec_access = get_opcode(op).map_or(0, |o| o.cycles - 1);
+        self.iec_synced.set(0);
+    }
+
+    // Run the serial bus devices up to the CIA2 port A access of the
+    // current instruction, so the drive sees writes and reads happen on the
+    // right cycle
+    fn sync_iec(&self) {
+        let due = self.iec_access.saturating_sub(self.iec_synced.get());
+        if due > 0 {
+            let lines = self.iec.borrow_mut().clock(self.iec_outputs(), due);
+            self.set_iec_inputs(&lines);
+            self.iec_synced.set(self.iec_access);
+        }
+    }
+
+    // Run the serial bus devices for the rest of the instruction
+    pub fn clock_iec(&mut self, cycles: u8) {
+        let due = cycles.saturating_sub(self.iec_synced.get());
+        let host = self.iec_outputs();
+        let lines = self.iec.get_mut().clock(host, due);
+        self.set_iec_inputs(&lines);
+        self.iec_access = 0;
+        self.iec_synced.set(0);
+    }
+
+    // Update the CLK and DATA levels read back on CIA2 port A bits 6-7
+    fn set_iec_inputs(&self, lines: &IecLines) {
+        self.iec_lines.set(
+            (if lines.clk { 0 } else { IEC_CLK_IN }) | (if lines.data { 0 } else { IEC_DATA_IN }),
+        );
+    }
+
+    // Cassette motor (on when the port bit is low) and write line levels
+    pub fn cassette_outputs(&self) -> (bool, bool) {
+        let out = self.ram[IO_PORT as usize] & self.ram[DATA_DIRECTION_PORT as usize];
+        (
+            (out & (1 << CASSETTE_MOTOR)) == 0,
+            (out & (1 << CASSETTE_WRITE)) > 0,
+        )
+    }
+
+    // Whether a datasette button is held down (sense line pulled low)
+    pub fn set_cassette_sense(&mut self, pressed: bool) {
+        self.cassette_sense = pressed;
+    }
+
+    // A pulse from the datasette read line on the CIA1 FLAG pin
+    pub fn cia1_flag(&mut self) {
+        self.cia1_icr.set(self.cia1_icr.get() | CIA_ICR_FLAG);
+    }
+
+    // CIA1 IRQ line: any interrupt source enabled in the mask has fired
+    pub fn cia1_irq(&self) -> bool {
+        (self.cia1_icr.get() & self.cia1_icr_mask) > 0
+    }
+
+    // Timer A is not emulated; its IRQ is raised once per frame if enabled
+    pub fn cia1_timer_a_irq_enabled(&self) -> bool {
+        (self.cia1_icr_mask & CIA_ICR_TIMER_A) > 0
+    }
+
+    // Count CIA1 timer B down. The KERNAL uses it to time serial bus
+    // handshakes.
+    pub fn clock_cia1(&mut self, cycles: u8) {
+        let crb = self.io[(CIA1_CRB - 0xD000) as usize];
+        if (crb & CIA_CR_START) == 0 {
+            return;
+        }
+        self.cia1_timer_b -= cycles as i32;
+        if self.cia1_timer_b < 0 {
+            self.cia1_icr.set(self.cia1_icr.get() | CIA_ICR_TIMER_B);
+            self.cia1_timer_b += self.cia1_timer_b_latch() as i32 + 1;
+            if (crb & CIA_CR_ONE_SHOT) > 0 {
+                self.io[(CIA1_CRB - 0xD000) as usize] &= !CIA_CR_START;
+            }
+        }
+    }
+
+    fn cia1_timer_b_latch(&self) -> u16 {
+        (self.io[(CIA1_TIMER_B_LO - 0xD000) as usize] as u16)
+            | ((self.io[(CIA1_TIMER_B_HI - 0xD000) as usize] as u16) << 8)
+    }
+
     pub fn read_register(&self, addr: u16) -> u8 {
         match addr {
-            0xDC01 => kbd_scancode(&self.keys, !self.io[0xDC00 - 0xD000]),
+            CIA1_DATA_PORT_A => self.cia1_ports().0,
+            CIA1_DATA_PORT_B => self.cia1_ports().1,
+            CIA1_TIMER_B_LO => (self.cia1_timer_b & 0xFF) as u8,
+            CIA1_TIMER_B_HI => ((self.cia1_timer_b >> 8) & 0xFF) as u8,
+            CIA1_ICR => {
+                let icr = self.cia1_icr.replace(0);
+                if (icr & self.cia1_icr_mask) > 0 {
+                    icr | CIA_ICR_IRQ
+                } else {
+                    icr
+                }
+            }
+            CIA2_DATA_PORT_A => {
+                let ddr = self.io[(CIA2_DATA_DIRECTION_A - 0xD000) as usize];
+                (self.io[(CIA2_DATA_PORT_A - 0xD000) as usize] & ddr)
+                    | (!ddr & (self.iec_lines.get() | 0x3F))
+            }
             0xD000...0xDFFF => self.io[addr as usize - 0xD000],
             _ => panic!("Register read out-of-bounds: ${:04X}", addr),
         }
     }
 
+    fn cia1_ports(&self) -> (u8, u8) {
+        // Lines not set as outputs float high. Control port 2 is wired to
+        // port A and control port 1 to port B; both can only pull lines low.
+        let pa = (self.io[(CIA1_DATA_PORT_A - 0xD000) as usize]
+            | !self.io[(CIA1_DATA_DIRECTION_A - 0xD000) as usize])
+            & self.ports[1].lines;
+        let pb = (self.io[(CIA1_DATA_PORT_B - 0xD000) as usize]
+            | !self.io[(CIA1_DATA_DIRECTION_B - 0xD000) as usize])
+            & self.ports[0].lines;
+        kbd_resolve(&self.keys, pa, pb)
+    }
+
     pub fn write_register(&mut self, addr: u16, val: u8) {
         match addr {
+            CIA1_TIMER_B_HI => {
+                // Writing the latch high byte loads a stopped timer
+                self.io[addr as usize - 0xD000] = val;
+                if (self.io[(CIA1_CRB - 0xD000) as usize] & CIA_CR_START) == 0 {
+                    self.cia1_timer_b = self.cia1_timer_b_latch() as i32;
+                }
+            }
+            CIA1_ICR => {
+                if (val & CIA_ICR_IRQ) > 0 {
+                    self.cia1_icr_mask |= val & 0x1F;
+                } else {
+                    self.cia1_icr_mask &= !val;
+                }
+            }
+            CIA1_CRB => {
+                self.io[addr as usize - 0xD000] = val & !CIA_CR_LOAD;
+                if (val & CIA_CR_LOAD) > 0 {
+                    self.cia1_timer_b = self.cia1_timer_b_latch() as i32;
+                }
+            }
             0xD000...0xDFFF => self.io[addr as usize - 0xD000] = val,
             _ => panic!("Register write out-of-bounds: ${:04X}", addr),
         }
@@ -93,6 +473,38 @@ impl CiaIO for MemC64 {
     }
 }
 
+pub trait SidIO {
+    fn sid_read_register(&self, addr: u16) -> u8;
+    fn sid_write_register(&mut self, addr: u16, val: u8);
+    fn sid_pot_inputs(&self) -> (u8, u8);
+}
+
+impl SidIO for MemC64 {
+    fn sid_read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xD400...0xD41C => self.read_register(addr),
+            _ => panic!("SID out-of-range register read: ${:04X}", addr),
+        }
+    }
+
+    fn sid_write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xD400...0xD41C => self.write_register(addr, val),
+            _ => panic!("SID out-of-range register write: ${:04X}", addr),
+        }
+    }
+
+    fn sid_pot_inputs(&self) -> (u8, u8) {
+        // CIA1 port A bits 6-7 drive the analog switch that selects which
+        // control port is connected to the POT lines
+        match self.io[(CIA1_DATA_PORT_A - 0xD000) as usize] >> 6 {
+            0b01 | 0b11 => (self.ports[0].pot_x, self.ports[0].pot_y),
+            0b10 => (self.ports[1].pot_x, self.ports[1].pot_y),
+            _ => (0xFF, 0xFF),
+        }
+    }
+}
+
 pub trait VicIO {
     fn vic_read_byte(&self, addr: u16) -> u8;
     fn vic_read_register(&self, addr: u16) -> u8;
@@ -104,10 +516,16 @@ impl VicIO for MemC64 {
     fn vic_read_byte(&self, addr: u16) -> u8 {
         assert!(addr < (16 * 1024)); // VIC only sees 16K
         let bank = 3 - (self.read_register(CIA2_DATA_PORT_A) & 0x03) as u16;
-        let val = match addr {
-            0x1000...0x1FFF if (bank == 0) || (bank == 2) => self.rom[(addr + 0xC000) as usize], // Char ROM
-            _ => self.ram[(addr + (bank * 0x4000)) as usize],
+        let (val, mapped) = match addr {
+            0x1000...0x1FFF if (bank == 0) || (bank == 2) => {
+                (self.rom[(addr + 0xC000) as usize], Bank::CharRom) // Char ROM
+            }
+            _ => (self.ram[(addr + (bank * 0x4000)) as usize], Bank::Ram),
         };
+        if !self.watchpoints.is_empty() {
+            self.watchpoints
+                .record(addr + (bank * 0x4000), WATCH_VIC, val, val, mapped);
+        }
         // println!(
         //     "VIC read byte: addr: ${:04X} bank: {:02X} full addr: ${:04X} = ${:02X}",
         //     addr,
@@ -121,6 +539,10 @@ impl VicIO for MemC64 {
     fn vic_read_vm(&self, addr: u16) -> (u8, u8) {
         let bank = 3 - (self.read_register(CIA2_DATA_PORT_A) & 0x03) as u16;
         let ch = self.ram[(addr + (bank * 0x4000)) as usize];
+        if !self.watchpoints.is_empty() {
+            self.watchpoints
+                .record(addr + (bank * 0x4000), WATCH_VIC, ch, ch, Bank::Ram);
+        }
         let clr_ix = addr % 0x0400;
         let clr = self.io[(VIC_COLOR_RAM - 0xD000 + clr_ix) as usize] & 0x0F;
 
@@ -143,53 +565,13 @@ impl VicIO for MemC64 {
 }
 
 impl MemIO for MemC64 {
-    fn memdump(&self, from: usize, bytes: usize) {
-        let mut buf = vec![0; bytes];
-        for i in 0..bytes {
-            buf[i] = self.read_byte((from + i) as u16);
-        }
-        hexdump(&buf, 0, bytes)
-    }
-
     fn read_byte(&self, addr: u16) -> u8 {
-        match addr {
-            IO_PORT => {
-                debug!(
-                    "Read IO_PORT (${}): ${:02X}",
-                    IO_PORT, self.ram[IO_PORT as usize]
-                );
-                self.ram[IO_PORT as usize]
-            }
-            DATA_DIRECTION_PORT => {
-                debug!(
-                    "Read DATA_DIRECTION_PORT (${}): ${:02X}",
-                    DATA_DIRECTION_PORT, self.ram[DATA_DIRECTION_PORT as usize]
-                );
-                self.ram[DATA_DIRECTION_PORT as usize]
-            }
-            0xA000...0xBFFF => {
-                if (self.ram[IO_PORT as usize] & (1 << LORAM)) > 0 {
-                    self.rom[addr as usize]
-                } else {
-                    self.ram[addr as usize]
-                }
-            }
-            0xD000...0xDFFF => {
-                if (self.ram[IO_PORT as usize] & (1 << CHAREN)) > 0 {
-                    self.read_register(addr)
-                } else {
-                    self.rom[addr as usize]
-                }
-            }
-            0xE000...0xFFFF => {
-                if (self.ram[IO_PORT as usize] & (1 << HIRAM)) > 0 {
-                    self.rom[addr as usize]
-                } else {
-                    self.ram[addr as usize]
-                }
-            }
-            _ => self.ram[addr as usize],
+        let val = self.cpu_read(addr);
+        if !self.watchpoints.is_empty() {
+            self.watchpoints
+                .record(addr, WATCH_READ, val, val, self.cpu_bank(addr));
         }
+        val
     }
 
     fn read_word(&self, addr: u16) -> u16 {
@@ -199,26 +581,16 @@ impl MemIO for MemC64 {
     }
 
     fn write_byte(&mut self, addr: u16, val: u8) {
-        match addr {
-            IO_PORT => {
-                debug!("Write IO_PORT (${}): ${:02X}", IO_PORT, val);
-                self.ram[IO_PORT as usize] = val;
-            }
-            DATA_DIRECTION_PORT => {
-                debug!(
-                    "Write DATA_DIRECTION_PORT (${}): ${:02X}",
-                    DATA_DIRECTION_PORT, val
-                );
-                self.ram[DATA_DIRECTION_PORT as usize] = val;
-            }
-            0xD000...0xDFFF => {
-                if (self.ram[IO_PORT as usize] & (1 << CHAREN)) > 0 {
-                    self.write_register(addr, val);
-                } else {
-                    self.ram[addr as usize] = val;
-                }
-            }
-            _ => self.ram[addr as usize] = val,
+        if self.watchpoints.is_empty() {
+            self.cpu_write(addr, val);
+            return;
         }
+        // Writes to ROM go to the RAM underneath
+        let (bank, old) = match self.cpu_bank(addr) {
+            Bank::Io => (Bank::Io, self.io[addr as usize - 0xD000]),
+            _ => (Bank::Ram, self.ram[addr as usize]),
+        };
+        self.cpu_write(addr, val);
+        self.watchpoints.record(addr, WATCH_WRITE, old, val, bank);
     }
 }
@@ -1,3 +1,4 @@
+#[derive(Clone, Copy, PartialEq)]
 pub enum Mode {
     Implied,
     Immediate,
@@ -29,6 +30,14 @@ pub fn get_opcode<'a>(op: u8) -> Option<&'a Opcode> {
     }
 }
 
+pub fn find_opcode<'a>(name: &str, mode: Mode) -> Option<&'a Opcode> {
+    OPCODES.iter().find(|o| o.name == name && o.mode == mode)
+}
+
+pub fn is_mnemonic(name: &str) -> bool {
+    OPCODES.iter().any(|o| o.name == name)
+}
+
 static OPCODES: [Opcode; 151] = [
     Opcode {
         code: 0x00,
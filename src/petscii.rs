@@ -0,0 +1,153 @@
+// Conversion between host text and PETSCII (unshifted/uppercase character set)
+
+pub const PETSCII_RETURN: u8 = 0x0D;
+pub const PETSCII_POUND: u8 = 0x5C;
+
+// Convert a host character to PETSCII. Letters of either case map to the
+// unshifted letters so that typed BASIC keywords come out right.
+pub fn char_to_petscii(c: char) -> Option<u8> {
+    match c {
+        'a'...'z' => Some(c as u8 - b'a' + 0x41),
+        'A'...'Z' | '0'...'9' | ' ' => Some(c as u8),
+        '!' | '"' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '*' | '+' | ',' | '-' | '.'
+        | '/' | ':' | ';' | '<' | '=' | '>' | '?' | '@' | '[' | ']' => Some(c as u8),
+        '\n' | '\r' => Some(PETSCII_RETURN),
+        '£' | '\\' => Some(PETSCII_POUND),
+        '^' | '↑' => Some(0x5E),
+        '_' | '←' => Some(0x5F),
+        _ => None,
+    }
+}
+
+// Convert a PETSCII character back to host text. Unshifted letters become
+// lowercase and shifted letters uppercase.
+pub fn petscii_to_char(p: u8) -> Option<char> {
+    match p {
+        0x41...0x5A => Some((p - 0x41 + b'a') as char),
+        0xC1...0xDA => Some((p - 0xC1 + b'A') as char),
+        0x20...0x40 | 0x5B | 0x5D => Some(p as char),
+        PETSCII_POUND => Some('£'),
+        0x5E => Some('↑'),
+        0x5F => Some('←'),
+        _ => None,
+    }
+}
+
+// Convert UTF-8 text to PETSCII, dropping characters with no equivalent.
+// A CR LF line break is one RETURN.
+pub fn str_to_petscii(s: &str) -> Vec<u8> {
+    s.replace("\r\n", "\n")
+        .chars()
+        .filter_map(char_to_petscii)
+        .collect()
+}
+
+// Expand the escapes accepted on the command line and in the monitor
+// ("\n" for RETURN, "\\" for a backslash and "\"" for a quote)
+pub fn unescape(s: &str) -> String {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some(e) => result.push(e),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+// Graphics characters $A0-$BF and $C0-$DF (or $60-$7F) as Unicode, the
+// latter being letters in the lowercase character set
+const GRAPHICS_A0: [char; 32] = [
+    '\u{A0}',
+    '▌',
+    '▄',
+    '▔',
+    '▁',
+    '▏',
+    '▒',
+    '▕',
+    '\u{1FB8F}',
+    '◤',
+    '\u{1FB87}',
+    '├',
+    '▗',
+    '└',
+    '┐',
+    '▂',
+    '┌',
+    '┴',
+    '┬',
+    '┤',
+    '▎',
+    '▍',
+    '\u{1FB88}',
+    '\u{1FB82}',
+    '\u{1FB83}',
+    '▃',
+    '\u{1FB7F}',
+    '▖',
+    '▝',
+    '┘',
+    '▘',
+    '▚',
+];
+const GRAPHICS_C0: [char; 32] = [
+    '─',
+    '♠',
+    '\u{1FB72}',
+    '\u{1FB78}',
+    '\u{1FB77}',
+    '\u{1FB76}',
+    '\u{1FB7A}',
+    '\u{1FB71}',
+    '\u{1FB74}',
+    '╮',
+    '╰',
+    '╯',
+    '\u{1FB7C}',
+    '╲',
+    '╱',
+    '\u{1FB7D}',
+    '\u{1FB7E}',
+    '●',
+    '\u{1FB7B}',
+    '♥',
+    '\u{1FB70}',
+    '╭',
+    '╳',
+    '○',
+    '♣',
+    '\u{1FB75}',
+    '♦',
+    '┼',
+    '\u{1FB8C}',
+    '│',
+    'π',
+    '◥',
+];
+
+// Convert a printable PETSCII character to Unicode, in the uppercase and
+// graphics or in the lowercase character set
+pub fn petscii_to_unicode(p: u8, lowercase: bool) -> Option<char> {
+    match p {
+        0x41...0x5A if lowercase => Some((p - 0x41 + b'a') as char),
+        0x41...0x5A => Some(p as char),
+        0x60...0x7F => petscii_to_unicode(p + 0x60, lowercase),
+        0xC1...0xDA if lowercase => Some((p - 0xC1 + b'A') as char),
+        0xDE if lowercase => Some('\u{1FB95}'),
+        0xDF if lowercase => Some('\u{1FB98}'),
+        0xA9 if lowercase => Some('\u{1FB99}'),
+        0xBA if lowercase => Some('✓'),
+        0xA0...0xBF => Some(GRAPHICS_A0[(p - 0xA0) as usize]),
+        0xC0...0xDF => Some(GRAPHICS_C0[(p - 0xC0) as usize]),
+        0xE0...0xFE => petscii_to_unicode(p - 0x40, lowercase),
+        0xFF => petscii_to_unicode(0xDE, lowercase),
+        _ => petscii_to_char(p),
+    }
+}
@@ -0,0 +1,95 @@
+// Minimal PNG writer for black and white images. The image data is stored
+// in uncompressed deflate blocks, so only the checksums need computing.
+
+const PNG_SIGNATURE: &'static [u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const STORED_BLOCK_MAX: usize = 0xFFFF;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if (crc & 1) > 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &x in data {
+        a = (a + x as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn push_u32(out: &mut Vec<u8>, val: u32) {
+    out.extend_from_slice(&[
+        (val >> 24) as u8,
+        (val >> 16) as u8,
+        (val >> 8) as u8,
+        val as u8,
+    ]);
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    push_u32(out, data.len() as u32);
+    let mut body = kind.to_vec();
+    body.extend_from_slice(data);
+    out.extend_from_slice(&body);
+    push_u32(out, crc32(&body));
+}
+
+// zlib stream holding the data in stored blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(STORED_BLOCK_MAX).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(if last { 1 } else { 0 });
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(block);
+    }
+    push_u32(&mut out, adler32(data));
+    out
+}
+
+// Encode a 1 bit image; pixels are true where black
+pub fn encode_png(width: usize, height: usize, pixels: &[bool]) -> Vec<u8> {
+    let mut out = PNG_SIGNATURE.to_vec();
+
+    let mut header = Vec::new();
+    push_u32(&mut header, width as u32);
+    push_u32(&mut header, height as u32);
+    header.extend_from_slice(&[1, 0, 0, 0, 0]); // 1 bit grayscale, no interlace
+    chunk(&mut out, b"IHDR", &header);
+
+    // Each row starts with filter type 0; a set bit is white
+    let row_bytes = (width + 7) / 8;
+    let mut raw = Vec::with_capacity(height * (row_bytes + 1));
+    for y in 0..height {
+        raw.push(0);
+        for bx in 0..row_bytes {
+            let mut byte = 0;
+            for bit in 0..8 {
+                let x = bx * 8 + bit;
+                if x >= width || !pixels[y * width + x] {
+                    byte |= 0x80 >> bit;
+                }
+            }
+            raw.push(byte);
+        }
+    }
+    chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
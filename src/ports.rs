@@ -0,0 +1,256 @@
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::keyboard::Scancode;
+use sdl2::mouse::MouseUtil;
+use sdl2::GameControllerSubsystem;
+
+// Control port lines (active low, as seen on CIA1 port A/B bits 0-4)
+pub const PORT_UP: u8 = 0x01;
+pub const PORT_DOWN: u8 = 0x02;
+pub const PORT_LEFT: u8 = 0x04;
+pub const PORT_RIGHT: u8 = 0x08;
+pub const PORT_FIRE: u8 = 0x10;
+
+// Mouse movement is scaled down so that a normal hand movement maps to a
+// sensible distance on the C64 side
+const MOUSE_DIVISOR: i32 = 2;
+
+// Analog stick deflection needed to register as a joystick direction
+const AXIS_THRESHOLD: i16 = 16384;
+
+// Default joystick keys: numeric keypad for directions and right Ctrl for fire
+pub const DEFAULT_JOY_KEYS: &'static str = "Keypad 8,Keypad 2,Keypad 4,Keypad 6,Right Ctrl";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PortDevice {
+    None,
+    Paddles,
+    Mouse1351,
+    Joystick,
+}
+
+impl PortDevice {
+    pub fn from_str(s: &str) -> Option<PortDevice> {
+        match s {
+            "none" => Some(PortDevice::None),
+            "paddles" => Some(PortDevice::Paddles),
+            "mouse" => Some(PortDevice::Mouse1351),
+            "joystick" => Some(PortDevice::Joystick),
+            _ => None,
+        }
+    }
+
+    fn uses_mouse(&self) -> bool {
+        match *self {
+            PortDevice::Paddles | PortDevice::Mouse1351 => true,
+            PortDevice::None | PortDevice::Joystick => false,
+        }
+    }
+}
+
+// Host keys that drive a joystick, in the order up, down, left, right, fire
+#[derive(Clone, Copy)]
+pub struct JoyKeys {
+    keys: [Scancode; 5],
+}
+
+impl JoyKeys {
+    // Parse a comma separated list of SDL key names, e.g. "Keypad 8,...,Right Ctrl"
+    pub fn from_str(s: &str) -> Option<JoyKeys> {
+        let names: Vec<&str> = s.split(',').map(|n| n.trim()).collect();
+        if names.len() != 5 {
+            return None;
+        }
+
+        let mut keys = Vec::new();
+        for name in names.iter() {
+            match Scancode::from_name(name) {
+                Some(sc) => keys.push(sc),
+                None => return None,
+            }
+        }
+        Some(JoyKeys {
+            keys: [keys[0], keys[1], keys[2], keys[3], keys[4]],
+        })
+    }
+
+    fn lines(&self, pressed: &Vec<Scancode>) -> u8 {
+        let mut lines = 0xFF;
+        for (ix, sc) in self.keys.iter().enumerate() {
+            if pressed.contains(sc) {
+                lines &= !(1 << ix);
+            }
+        }
+        lines
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PortState {
+    pub lines: u8, // Digital lines, active low (bit 0-4)
+    pub pot_x: u8,
+    pub pot_y: u8,
+}
+
+impl PortState {
+    pub fn new() -> PortState {
+        PortState {
+            lines: 0xFF,
+            pot_x: 0xFF,
+            pot_y: 0xFF,
+        }
+    }
+}
+
+pub struct ControlPorts {
+    devices: [PortDevice; 2],
+    joy_keys: [JoyKeys; 2],
+    controllers: [Option<GameController>; 2],
+    // Host mouse motion, scaled down by MOUSE_DIVISOR only when read so
+    // that slow movements still add up
+    mouse_x: i32,
+    mouse_y: i32,
+}
+
+impl ControlPorts {
+    pub fn new(
+        devices: [PortDevice; 2],
+        joy_keys: [JoyKeys; 2],
+        gc: Option<&GameControllerSubsystem>,
+    ) -> ControlPorts {
+        // Hand out attached game controllers to the joystick ports in order
+        let mut controllers = [None, None];
+        let mut next_id = 0;
+        let num_joysticks = gc.map_or(0, |gc| gc.num_joysticks().unwrap_or(0));
+        for (ix, device) in devices.iter().enumerate() {
+            let gc = match gc {
+                Some(gc) if *device == PortDevice::Joystick => gc,
+                _ => continue,
+            };
+            while next_id < num_joysticks && controllers[ix].is_none() {
+                if gc.is_game_controller(next_id) {
+                    match gc.open(next_id) {
+                        Ok(c) => {
+                            println!("Control port {}: {}", ix + 1, c.name());
+                            controllers[ix] = Some(c);
+                        }
+                        Err(e) => println!("Unable to open game controller: {:?}", e),
+                    }
+                }
+                next_id += 1;
+            }
+        }
+
+        ControlPorts {
+            devices: devices,
+            joy_keys: joy_keys,
+            controllers: controllers,
+            mouse_x: 128 * MOUSE_DIVISOR,
+            mouse_y: 128 * MOUSE_DIVISOR,
+        }
+    }
+
+    // True if the key is consumed by a joystick and should not reach the keyboard
+    pub fn is_joystick_key(&self, sc: Scancode) -> bool {
+        self.devices
+            .iter()
+            .enumerate()
+            .any(|(ix, d)| *d == PortDevice::Joystick && self.joy_keys[ix].keys.contains(&sc))
+    }
+
+    pub fn grab_mouse(&self, mouse: &MouseUtil, grab: bool) {
+        if self.devices.iter().any(|d| d.uses_mouse()) {
+            mouse.set_relative_mouse_mode(grab);
+        }
+    }
+
+    // Sample the host input devices and return the state of control port 1 and 2
+    pub fn update(&mut self, mouse: &MouseUtil, keys: &Vec<Scancode>) -> [PortState; 2] {
+        let mut ports = [PortState::new(), PortState::new()];
+        for ix in 0..2 {
+            if self.devices[ix] == PortDevice::Joystick {
+                ports[ix].lines = self.joy_keys[ix].lines(keys) & self.controller_lines(ix);
+            }
+        }
+
+        if !self.devices.iter().any(|d| d.uses_mouse()) {
+            return ports;
+        }
+
+        let (buttons, dx, dy) = mouse.relative_mouse_state();
+        self.mouse_x += dx;
+        self.mouse_y += dy;
+        let max = 255 * MOUSE_DIVISOR + MOUSE_DIVISOR - 1;
+
+        for (ix, device) in self.devices.iter().enumerate() {
+            let port = &mut ports[ix];
+            match *device {
+                PortDevice::None | PortDevice::Joystick => {}
+                PortDevice::Paddles => {
+                    // Paddle resistance grows to the left, so invert the axis.
+                    // Host X drives paddle A and host Y drives paddle B.
+                    self.mouse_x = clamp(self.mouse_x, 0, max);
+                    self.mouse_y = clamp(self.mouse_y, 0, max);
+                    port.pot_x = 255 - (self.mouse_x / MOUSE_DIVISOR) as u8;
+                    port.pot_y = 255 - (self.mouse_y / MOUSE_DIVISOR) as u8;
+                    if buttons.left() {
+                        port.lines &= !PORT_LEFT; // Paddle A fire
+                    }
+                    if buttons.right() {
+                        port.lines &= !PORT_RIGHT; // Paddle B fire
+                    }
+                }
+                PortDevice::Mouse1351 => {
+                    // The 1351 reports position modulo 64 in bits 1-6 of the
+                    // POT registers. Bit 0 is noise and Y grows upwards.
+                    let x = self.mouse_x.div_euclid(MOUSE_DIVISOR);
+                    let y = (-self.mouse_y).div_euclid(MOUSE_DIVISOR);
+                    port.pot_x = ((x & 0x3F) << 1) as u8;
+                    port.pot_y = ((y & 0x3F) << 1) as u8;
+                    if buttons.left() {
+                        port.lines &= !PORT_FIRE;
+                    }
+                    if buttons.right() {
+                        port.lines &= !PORT_UP;
+                    }
+                }
+            }
+        }
+
+        ports
+    }
+
+    fn controller_lines(&self, ix: usize) -> u8 {
+        let c = match self.controllers[ix] {
+            Some(ref c) => c,
+            None => return 0xFF,
+        };
+
+        let mut lines = 0xFF;
+        if c.button(Button::DPadUp) || c.axis(Axis::LeftY) < -AXIS_THRESHOLD {
+            lines &= !PORT_UP;
+        }
+        if c.button(Button::DPadDown) || c.axis(Axis::LeftY) > AXIS_THRESHOLD {
+            lines &= !PORT_DOWN;
+        }
+        if c.button(Button::DPadLeft) || c.axis(Axis::LeftX) < -AXIS_THRESHOLD {
+            lines &= !PORT_LEFT;
+        }
+        if c.button(Button::DPadRight) || c.axis(Axis::LeftX) > AXIS_THRESHOLD {
+            lines &= !PORT_RIGHT;
+        }
+        if c.button(Button::A) || c.button(Button::B) {
+            lines &= !PORT_FIRE;
+        }
+        lines
+    }
+}
+
+fn clamp(v: i32, min: i32, max: i32) -> i32 {
+    if v < min {
+        min
+    } else if v > max {
+        max
+    } else {
+        v
+    }
+}
@@ -0,0 +1,316 @@
+// Commodore MPS-801/803 dot matrix printer on the serial bus. Output is
+// kept as dots on paper, saved as PNG pages, and as UTF-8 text.
+use petscii::*;
+use png::*;
+use serial::*;
+use std::fs::File;
+use std::io::prelude::*;
+
+// Paper: 80 columns of 8 dot wide characters, 66 lines of 9 dots per page
+const COLUMNS: usize = 80;
+const CHAR_WIDTH: usize = 8;
+const PAGE_WIDTH: usize = COLUMNS * CHAR_WIDTH;
+const TEXT_LINE: usize = 9;
+const PAGE_HEIGHT: usize = 66 * TEXT_LINE;
+// Bit image lines are 7 dots apart, so they join up
+const GRAPHIC_LINE: usize = 7;
+
+// Secondary address 7 selects the lowercase character set
+const SA_LOWERCASE: u8 = 7;
+
+// Control codes
+const PRINTER_LF: u8 = 0x0A;
+const PRINTER_FF: u8 = 0x0C;
+const PRINTER_CR: u8 = 0x0D;
+const PRINTER_BIT_IMAGE: u8 = 0x08;
+const PRINTER_DOUBLE_WIDTH: u8 = 0x0E;
+const PRINTER_STANDARD: u8 = 0x0F;
+const PRINTER_TAB: u8 = 0x10;
+const PRINTER_LOWERCASE: u8 = 0x11;
+const PRINTER_REVERSE_ON: u8 = 0x12;
+const PRINTER_REPEAT: u8 = 0x1A;
+const PRINTER_ESC: u8 = 0x1B;
+const PRINTER_UPPERCASE: u8 = 0x91;
+const PRINTER_REVERSE_OFF: u8 = 0x92;
+
+// Bytes still expected by a multi-byte control sequence
+enum Pending {
+    None,
+    Tab(Vec<u8>),
+    Escape,
+    DotAddress(Vec<u8>),
+    RepeatCount,
+    RepeatData(u8),
+}
+
+pub struct Printer {
+    path: String,
+    chargen: Vec<u8>,
+    pages: Vec<Vec<bool>>,
+    text: String,
+    x: usize,
+    y: usize,
+    column: usize,
+    sa: u8,
+    lowercase: bool,
+    reverse: bool,
+    double: bool,
+    bit_image: bool,
+    pending: Pending,
+    dirty: bool,
+}
+
+// Offset of a PETSCII character in the character ROM
+fn glyph_index(p: u8) -> Option<usize> {
+    let code = match p {
+        0x20...0x3F => p,
+        0x40...0x5F => p - 0x40,
+        0x60...0x7F => p - 0x20,
+        0xA0...0xBF => p - 0x40,
+        0xC0...0xFE => p - 0x80,
+        0xFF => 0x5E,
+        _ => return None,
+    };
+    Some(code as usize * 8)
+}
+
+impl Printer {
+    // Output goes to <path>.txt and <path>.png (<path>-2.png and on for
+    // further pages). Characters are drawn from the character ROM.
+    pub fn new(path: &str, chargen: Vec<u8>) -> Printer {
+        Printer {
+            path: path.to_string(),
+            chargen: chargen,
+            pages: vec![vec![false; PAGE_WIDTH * PAGE_HEIGHT]],
+            text: String::new(),
+            x: 0,
+            y: 0,
+            column: 0,
+            sa: 0,
+            lowercase: false,
+            reverse: false,
+            double: false,
+            bit_image: false,
+            pending: Pending::None,
+            dirty: false,
+        }
+    }
+
+    fn dot(&mut self, x: usize, y: usize) {
+        if x < PAGE_WIDTH && y < PAGE_HEIGHT {
+            self.pages.last_mut().unwrap()[y * PAGE_WIDTH + x] = true;
+        }
+    }
+
+    fn line_feed(&mut self) {
+        self.y += if self.bit_image {
+            GRAPHIC_LINE
+        } else {
+            TEXT_LINE
+        };
+        if self.y + TEXT_LINE > PAGE_HEIGHT {
+            self.form_feed();
+        }
+    }
+
+    fn carriage_return(&mut self) {
+        self.x = 0;
+        self.column = 0;
+        self.text.push('\n');
+    }
+
+    fn form_feed(&mut self) {
+        self.pages.push(vec![false; PAGE_WIDTH * PAGE_HEIGHT]);
+        self.y = 0;
+    }
+
+    // A 7 dot column of bit image data, bit 0 at the top
+    fn column_dots(&mut self, val: u8) {
+        if self.x >= PAGE_WIDTH {
+            return;
+        }
+        for bit in 0..7 {
+            if (val & (1 << bit)) > 0 {
+                let (x, y) = (self.x, self.y + bit);
+                self.dot(x, y);
+            }
+        }
+        self.x += 1;
+    }
+
+    fn print_char(&mut self, p: u8) {
+        let width = if self.double { 2 } else { 1 };
+        if self.x + CHAR_WIDTH * width > PAGE_WIDTH {
+            self.carriage_return();
+            self.line_feed();
+        }
+
+        let base = glyph_index(p).map(|ix| ix + if self.lowercase { 0x800 } else { 0 });
+        for row in 0..8 {
+            let bits = match base {
+                Some(ix) if ix + row < self.chargen.len() => self.chargen[ix + row],
+                _ => 0,
+            };
+            let bits = if self.reverse { !bits } else { bits };
+            for col in 0..CHAR_WIDTH * width {
+                if (bits & (0x80 >> (col / width))) > 0 {
+                    let (x, y) = (self.x + col, self.y + row);
+                    self.dot(x, y);
+                }
+            }
+        }
+        self.x += CHAR_WIDTH * width;
+
+        if let Some(c) = petscii_to_unicode(p, self.lowercase) {
+            self.text.push(c);
+            if self.double {
+                self.text.push(' ');
+            }
+        }
+        self.column += width;
+    }
+
+    // Move to a character column (or dot position) on the current line
+    fn tab_to(&mut self, x: usize) {
+        let x = x.min(PAGE_WIDTH);
+        let column = x / CHAR_WIDTH;
+        while self.column < column {
+            self.text.push(' ');
+            self.column += 1;
+        }
+        self.x = x;
+    }
+
+    fn print(&mut self, val: u8) {
+        match ::std::mem::replace(&mut self.pending, Pending::None) {
+            Pending::Tab(mut digits) => {
+                digits.push(val);
+                if digits.len() < 2 {
+                    self.pending = Pending::Tab(digits);
+                } else {
+                    let n = (digits[0].wrapping_sub(b'0') % 10) * 10
+                        + digits[1].wrapping_sub(b'0') % 10;
+                    self.tab_to(n as usize * CHAR_WIDTH);
+                }
+                return;
+            }
+            Pending::Escape => {
+                if val == PRINTER_TAB {
+                    self.pending = Pending::DotAddress(Vec::new());
+                }
+                return;
+            }
+            Pending::DotAddress(mut pos) => {
+                pos.push(val);
+                if pos.len() < 2 {
+                    self.pending = Pending::DotAddress(pos);
+                } else {
+                    self.tab_to(((pos[0] as usize & 0x01) << 8) | pos[1] as usize);
+                }
+                return;
+            }
+            Pending::RepeatCount => {
+                self.pending = Pending::RepeatData(val);
+                return;
+            }
+            Pending::RepeatData(count) => {
+                for _ in 0..count {
+                    self.column_dots(val);
+                }
+                return;
+            }
+            Pending::None => {}
+        }
+
+        match val {
+            // Every byte with the top bit set is a dot column in bit image
+            // mode, even the ones that are control codes otherwise
+            0x80...0xFF if self.bit_image => self.column_dots(val),
+            PRINTER_CR => {
+                self.carriage_return();
+                self.line_feed();
+                self.reverse = false;
+            }
+            PRINTER_LF => {
+                self.text.push('\n');
+                self.line_feed();
+            }
+            PRINTER_FF => {
+                self.text.push('\u{C}');
+                self.x = 0;
+                self.column = 0;
+                self.form_feed();
+            }
+            PRINTER_BIT_IMAGE => self.bit_image = true,
+            PRINTER_STANDARD => {
+                self.bit_image = false;
+                self.double = false;
+            }
+            PRINTER_DOUBLE_WIDTH => self.double = true,
+            PRINTER_TAB => self.pending = Pending::Tab(Vec::new()),
+            PRINTER_ESC => self.pending = Pending::Escape,
+            PRINTER_REPEAT => self.pending = Pending::RepeatCount,
+            PRINTER_LOWERCASE => self.lowercase = true,
+            PRINTER_UPPERCASE => self.lowercase = false,
+            PRINTER_REVERSE_ON => self.reverse = true,
+            PRINTER_REVERSE_OFF => self.reverse = false,
+            0x20...0x7F | 0xA0...0xFF => self.print_char(val),
+            _ => {}
+        }
+    }
+
+    fn page_path(&self, page: usize) -> String {
+        if page == 0 {
+            format!("{}.png", self.path)
+        } else {
+            format!("{}-{}.png", self.path, page + 1)
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn open(&mut self, sa: u8, _name: &[u8]) {
+        self.sa = sa;
+        self.lowercase = sa == SA_LOWERCASE;
+    }
+
+    fn close(&mut self, _sa: u8) {
+        self.flush();
+    }
+
+    fn write(&mut self, sa: u8, val: u8) {
+        // The secondary address picks the character set for the data
+        if sa != self.sa {
+            self.open(sa, &[]);
+        }
+        self.dirty = true;
+        self.print(val);
+    }
+
+    fn unlisten(&mut self, _sa: u8) {}
+
+    fn read(&mut self, _sa: u8) -> Option<(u8, bool)> {
+        None
+    }
+
+    // Write out the text and the pages printed so far
+    fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+
+        let mut files = vec![(format!("{}.txt", self.path), self.text.clone().into_bytes())];
+        for (ix, page) in self.pages.iter().enumerate() {
+            files.push((
+                self.page_path(ix),
+                encode_png(PAGE_WIDTH, PAGE_HEIGHT, page),
+            ));
+        }
+        for (path, data) in files {
+            if let Err(e) = File::create(&path).and_then(|mut f| f.write_all(&data)) {
+                println!("Couldn't write {}: {:?}", path, e);
+            }
+        }
+    }
+}
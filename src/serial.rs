@@ -0,0 +1,180 @@
+use cpu::*;
+use memory::*;
+
+// KERNAL serial bus routines (targets of the $FFxx jump table entries)
+pub const KERNAL_TALK: u16 = 0xED09;
+pub const KERNAL_LISTEN: u16 = 0xED0C;
+pub const KERNAL_SECOND: u16 = 0xEDB9;
+pub const KERNAL_TKSA: u16 = 0xEDC7;
+pub const KERNAL_CIOUT: u16 = 0xEDDD;
+pub const KERNAL_UNTLK: u16 = 0xEDEF;
+pub const KERNAL_UNLSN: u16 = 0xEDFE;
+pub const KERNAL_ACPTR: u16 = 0xEE13;
+
+// KERNAL I/O status word
+pub const STATUS: u16 = 0x0090;
+pub const STATUS_TIMEOUT_READ: u8 = 0x02;
+pub const STATUS_EOI: u8 = 0x40;
+
+// Secondary address commands sent under ATN
+const SA_CLOSE: u8 = 0xE0;
+const SA_OPEN: u8 = 0xF0;
+
+// A device on the serial bus, driven at the level of the KERNAL routines:
+// OPEN/CLOSE of a channel, and bytes sent to or received from a channel
+pub trait SerialDevice {
+    fn open(&mut self, sa: u8, name: &[u8]);
+    fn close(&mut self, sa: u8);
+    fn write(&mut self, sa: u8, val: u8);
+    fn unlisten(&mut self, sa: u8);
+    // Returns the next byte and whether it is the last one (EOI)
+    fn read(&mut self, sa: u8) -> Option<(u8, bool)>;
+    // Write out anything still buffered, on exit
+    fn flush(&mut self) {}
+}
+
+// Serves serial bus devices by trapping the KERNAL serial routines, so no
+// actual bus protocol needs to be emulated
+pub struct SerialTraps {
+    devices: Vec<(u8, Box<SerialDevice>)>,
+    listener: Option<usize>,
+    talker: Option<usize>,
+    sa: u8,
+    opening: bool,
+    name: Vec<u8>,
+}
+
+impl SerialTraps {
+    pub fn new() -> SerialTraps {
+        SerialTraps {
+            devices: Vec::new(),
+            listener: None,
+            talker: None,
+            sa: 0,
+            opening: false,
+            name: Vec::new(),
+        }
+    }
+
+    pub fn attach(&mut self, device: u8, dev: Box<SerialDevice>) {
+        self.detach(device);
+        self.devices.push((device, dev));
+    }
+
+    pub fn detach(&mut self, device: u8) {
+        self.devices.retain(|&(d, _)| d != device);
+        self.listener = None;
+        self.talker = None;
+    }
+
+    pub fn flush(&mut self) {
+        for &mut (_, ref mut dev) in self.devices.iter_mut() {
+            dev.flush();
+        }
+    }
+
+    fn find(&self, device: u8) -> Option<usize> {
+        self.devices.iter().position(|&(d, _)| d == device)
+    }
+
+    // Handle the KERNAL routine at the PC, if it is one we trap and it is
+    // addressed to one of our devices. Returns true if the trap was taken.
+    pub fn trap(&mut self, cpu: &mut CPU, mem: &mut MemIO) -> bool {
+        let a = cpu.get_a();
+        match cpu.get_pc() {
+            KERNAL_LISTEN => {
+                self.listener = self.find(a & 0x1F);
+                if self.listener.is_none() {
+                    return false;
+                }
+                mem.write_byte(STATUS, 0);
+            }
+            KERNAL_TALK => {
+                self.talker = self.find(a & 0x1F);
+                if self.talker.is_none() {
+                    return false;
+                }
+                mem.write_byte(STATUS, 0);
+            }
+            KERNAL_SECOND => {
+                let ix = match self.listener {
+                    Some(ix) => ix,
+                    None => return false,
+                };
+                self.sa = a & 0x0F;
+                self.opening = false;
+                match a & 0xF0 {
+                    SA_OPEN => {
+                        self.opening = true;
+                        self.name.clear();
+                    }
+                    SA_CLOSE => self.devices[ix].1.close(a & 0x0F),
+                    _ => {}
+                }
+            }
+            KERNAL_TKSA => {
+                if self.talker.is_none() {
+                    return false;
+                }
+                self.sa = a & 0x0F;
+            }
+            KERNAL_CIOUT => {
+                let ix = match self.listener {
+                    Some(ix) => ix,
+                    None => return false,
+                };
+                if self.opening {
+                    self.name.push(a);
+                } else {
+                    self.devices[ix].1.write(self.sa, a);
+                }
+                cpu.set_flag(Flags::Carry, false);
+            }
+            KERNAL_UNLSN => {
+                let ix = match self.listener.take() {
+                    Some(ix) => ix,
+                    None => return false,
+                };
+                if self.opening {
+                    self.opening = false;
+                    self.devices[ix].1.open(self.sa, &self.name);
+                } else {
+                    self.devices[ix].1.unlisten(self.sa);
+                }
+            }
+            KERNAL_ACPTR => {
+                let ix = match self.talker {
+                    Some(ix) => ix,
+                    None => return false,
+                };
+                let status = mem.read_byte(STATUS);
+                let val = match self.devices[ix].1.read(self.sa) {
+                    Some((val, eoi)) => {
+                        if eoi {
+                            mem.write_byte(STATUS, status | STATUS_EOI);
+                        }
+                        val
+                    }
+                    None => {
+                        mem.write_byte(STATUS, status | STATUS_TIMEOUT_READ);
+                        0
+                    }
+                };
+                // ACPTR leaves the flags set for the byte read
+                cpu.set_a(val);
+                cpu.set_flag(Flags::Zero, val == 0);
+                cpu.set_flag(Flags::Sign, val > 127);
+                cpu.set_flag(Flags::Carry, false);
+            }
+            KERNAL_UNTLK => {
+                if self.talker.take().is_none() {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+
+        cpu.return_from_trap(mem);
+        true
+    }
+}
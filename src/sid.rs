@@ -0,0 +1,166 @@
+use memc64::*;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+pub const SID_MODE_VOLUME: u16 = 0xD418;
+pub const SID_POTX: u16 = 0xD419;
+pub const SID_POTY: u16 = 0xD41A;
+
+pub const SID_CLOCK_HZ: u32 = 1_022_727; // NTSC
+pub const SAMPLE_RATE: i32 = 44100;
+
+// Keep at most ~100 ms of audio queued so we never drift behind the emulation
+const MAX_QUEUED_SAMPLES: usize = 4410;
+
+// DC offset seen at the audio output per step of the master volume. The 6581
+// mixer has a large DC bias so writing to $D418 produces an audible "click";
+// on the 8580 it is almost gone, which is what the digi boost hack works around.
+const DC_OFFSET_6581: f32 = 1.0;
+const DC_OFFSET_8580: f32 = 0.04;
+const DC_OFFSET_8580_BOOST: f32 = 0.85;
+
+// The POT lines are discharged for 256 cycles and then charged for another
+// 256 cycles; the count reached when the capacitor trips is latched at the end
+const POT_DISCHARGE_CYCLES: u32 = 256;
+const POT_PERIOD_CYCLES: u32 = 512;
+
+// Output stage high pass (the coupling capacitor on the C64 board)
+const HIGHPASS_ALPHA: f32 = 0.9993;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChipModel {
+    Mos6581,
+    Mos8580,
+}
+
+impl ChipModel {
+    pub fn from_str(s: &str) -> Option<ChipModel> {
+        match s {
+            "6581" => Some(ChipModel::Mos6581),
+            "8580" => Some(ChipModel::Mos8580),
+            _ => None,
+        }
+    }
+}
+
+struct SidOutput {
+    samples: Arc<Mutex<VecDeque<i16>>>,
+}
+
+impl AudioCallback for SidOutput {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [i16]) {
+        let mut samples = self.samples.lock().unwrap();
+        for x in out.iter_mut() {
+            *x = samples.pop_front().unwrap_or(0);
+        }
+    }
+}
+
+pub struct SID {
+    model: ChipModel,
+    digi_boost: bool,
+    samples: Arc<Mutex<VecDeque<i16>>>,
+    device: Option<AudioDevice<SidOutput>>,
+    sample_cycles: u32,
+    pot_cycles: u32,
+    pot_inputs: (u8, u8),
+    last_in: f32,
+    last_out: f32,
+}
+
+impl SID {
+    pub fn new(audio: Option<&AudioSubsystem>, model: ChipModel, digi_boost: bool) -> SID {
+        let samples = Arc::new(Mutex::new(VecDeque::new()));
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(1),
+            samples: Some(1024),
+        };
+
+        let shared = samples.clone();
+        let opened = audio.map(|a| a.open_playback(None, &spec, |_| SidOutput { samples: shared }));
+        let device = match opened {
+            Some(Ok(d)) => {
+                d.resume();
+                Some(d)
+            }
+            Some(Err(e)) => {
+                println!("Unable to open audio device, SID output disabled: {}", e);
+                None
+            }
+            None => None,
+        };
+
+        SID {
+            model: model,
+            digi_boost: digi_boost,
+            samples: samples,
+            device: device,
+            sample_cycles: 0,
+            pot_cycles: 0,
+            pot_inputs: (0xFF, 0xFF),
+            last_in: 0.0,
+            last_out: 0.0,
+        }
+    }
+
+    pub fn clock(&mut self, mem: &mut SidIO, cycles: u8) {
+        self.clock_pots(mem, cycles);
+
+        // Advance by CPU cycles and emit a sample every time we cross a
+        // sample boundary (SID_CLOCK_HZ / SAMPLE_RATE cycles per sample)
+        self.sample_cycles += cycles as u32 * SAMPLE_RATE as u32;
+        while self.sample_cycles >= SID_CLOCK_HZ {
+            self.sample_cycles -= SID_CLOCK_HZ;
+            let s = self.output(mem);
+            self.push_sample(s);
+        }
+    }
+
+    fn clock_pots(&mut self, mem: &mut SidIO, cycles: u8) {
+        for _ in 0..cycles {
+            self.pot_cycles = (self.pot_cycles + 1) % POT_PERIOD_CYCLES;
+            if self.pot_cycles == POT_DISCHARGE_CYCLES {
+                // Charging starts - whatever port is selected now is measured
+                self.pot_inputs = mem.sid_pot_inputs();
+            } else if self.pot_cycles == 0 {
+                let (x, y) = self.pot_inputs;
+                mem.sid_write_register(SID_POTX, x);
+                mem.sid_write_register(SID_POTY, y);
+            }
+        }
+    }
+
+    fn output(&mut self, mem: &SidIO) -> i16 {
+        // No voices are emulated yet, so the only contribution to the output
+        // is the master volume DAC multiplied with the mixer DC offset
+        let volume = (mem.sid_read_register(SID_MODE_VOLUME) & 0x0F) as f32 / 15.0;
+        let dc = match self.model {
+            ChipModel::Mos6581 => DC_OFFSET_6581,
+            ChipModel::Mos8580 if self.digi_boost => DC_OFFSET_8580_BOOST,
+            ChipModel::Mos8580 => DC_OFFSET_8580,
+        };
+        let level = volume * dc;
+
+        // Remove the DC component so that a static volume is silent
+        let out = HIGHPASS_ALPHA * (self.last_out + level - self.last_in);
+        self.last_in = level;
+        self.last_out = out;
+
+        (out * 0.5 * i16::max_value() as f32) as i16
+    }
+
+    fn push_sample(&mut self, s: i16) {
+        if self.device.is_none() {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() < MAX_QUEUED_SAMPLES {
+            samples.push_back(s);
+        }
+    }
+}
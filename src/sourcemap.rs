@@ -0,0 +1,270 @@
+// Source line information from ca65/ld65 .dbg files: which lines of
+// assembler source produced the code at each address, for showing the
+// source in the monitor, stepping by line and file:line addresses
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use symbols::*;
+
+struct SourceFile {
+    name: String,
+    lines: Option<Vec<String>>,
+}
+
+pub struct SourceMap {
+    files: HashMap<u32, SourceFile>,
+    // Code generated by each line: start address -> (end address, file, line)
+    ranges: BTreeMap<u16, (u16, u32, u32)>,
+    // The first address of each line
+    starts: BTreeMap<(u32, u32), u16>,
+}
+
+// ca65 line types: 0 is assembler source, 1 external (such as C) source
+// and 2 a line inside a macro expansion, which we leave out so the line
+// invoking the macro is shown
+const LINE_TYPE_MACRO: u32 = 2;
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap {
+            files: HashMap::new(),
+            ranges: BTreeMap::new(),
+            starts: BTreeMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.ranges.clear();
+        self.starts.clear();
+    }
+
+    // Replace the source map with the line information in a .dbg file,
+    // returning the number of lines with code (0 for other symbol files).
+    // Source files are looked for as named in the .dbg file, then next to it.
+    pub fn load(&mut self, filename: &str) -> usize {
+        let text = match fs::read(filename) {
+            Ok(data) => String::from_utf8_lossy(&data).into_owned(),
+            Err(_) => return 0,
+        };
+        let mut map = SourceMap::new();
+        map.parse(&text);
+        if map.starts.is_empty() {
+            return 0;
+        }
+
+        let dir = Path::new(filename).parent().unwrap_or(Path::new(""));
+        for file in map.files.values_mut() {
+            let name = Path::new(&file.name);
+            let candidates = [
+                name.to_path_buf(),
+                dir.join(name),
+                dir.join(name.file_name().unwrap_or(name.as_os_str())),
+            ];
+            file.lines = candidates
+                .iter()
+                .filter_map(|p| fs::read(p).ok())
+                .next()
+                .map(|data| {
+                    String::from_utf8_lossy(&data)
+                        .lines()
+                        .map(|l| l.to_string())
+                        .collect()
+                });
+            if file.lines.is_none() {
+                println!("Source file {} not found", file.name);
+            }
+        }
+        *self = map;
+        self.starts.len()
+    }
+
+    fn parse(&mut self, text: &str) {
+        let mut segs = HashMap::new();
+        let mut spans = HashMap::new();
+        let mut lines = Vec::new();
+        for line in text.lines() {
+            let (kind, fields) = match parse_dbg_line(line.trim()) {
+                Some(r) => r,
+                None => continue,
+            };
+            let num = |key: &str| fields.get(key).and_then(|v| parse_dbg_number(v));
+            let id = match num("id") {
+                Some(id) => id,
+                None => continue,
+            };
+            match kind {
+                "file" => {
+                    if let Some(name) = fields.get("name") {
+                        self.files.insert(
+                            id,
+                            SourceFile {
+                                name: name.to_string(),
+                                lines: None,
+                            },
+                        );
+                    }
+                }
+                "seg" => {
+                    if let Some(start) = num("start") {
+                        segs.insert(id, start);
+                    }
+                }
+                "span" => {
+                    if let (Some(seg), Some(start), Some(size)) =
+                        (num("seg"), num("start"), num("size"))
+                    {
+                        spans.insert(id, (seg, start, size));
+                    }
+                }
+                "line" => {
+                    if let (Some(file), Some(n), Some(span)) =
+                        (num("file"), num("line"), fields.get("span"))
+                    {
+                        if num("type") != Some(LINE_TYPE_MACRO) {
+                            lines.push((file, n, span.to_string()));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Spans are listed after the lines that use them, so resolve
+        // addresses once everything is read
+        for &(file, n, ref span_ids) in lines.iter() {
+            for span in span_ids.split('+').filter_map(|s| s.parse::<u32>().ok()) {
+                let (seg, offset, size) = match spans.get(&span) {
+                    Some(&s) => s,
+                    None => continue,
+                };
+                let start = match segs.get(&seg) {
+                    Some(&base) if size > 0 => base + offset,
+                    _ => continue,
+                };
+                if start + size > 0x10000 {
+                    continue;
+                }
+                let (start, end) = (start as u16, (start + size - 1) as u16);
+                self.ranges.entry(start).or_insert((end, file, n));
+                let first = self.starts.entry((file, n)).or_insert(start);
+                if start < *first {
+                    *first = start;
+                }
+            }
+        }
+    }
+
+    // The file and line of the code at addr
+    pub fn line_at(&self, addr: u16) -> Option<(u32, u32)> {
+        match self.ranges.range(..=addr).next_back() {
+            Some((_, &(end, file, line))) if addr <= end => Some((file, line)),
+            _ => None,
+        }
+    }
+
+    // The file and line starting at addr
+    pub fn line_starting(&self, addr: u16) -> Option<(u32, u32)> {
+        self.ranges.get(&addr).map(|&(_, file, line)| (file, line))
+    }
+
+    pub fn describe(&self, file: u32, line: u32) -> String {
+        match self.files.get(&file) {
+            Some(f) => format!("{}:{}", f.name, line),
+            None => format!("?:{}", line),
+        }
+    }
+
+    pub fn text(&self, file: u32, line: u32) -> Option<&str> {
+        self.files
+            .get(&file)
+            .and_then(|f| f.lines.as_ref())
+            .and_then(|lines| {
+                if line == 0 {
+                    None
+                } else {
+                    lines.get(line as usize - 1)
+                }
+            })
+            .map(|s| s.as_str())
+    }
+
+    // The address of file:line, where file is the name in the .dbg file or
+    // just the last part of it. A line without code stands for the next
+    // line that has some.
+    pub fn find(&self, spec: &str) -> Option<u16> {
+        let ix = match spec.rfind(':') {
+            Some(ix) => ix,
+            None => return None,
+        };
+        let (name, line) = match spec[ix + 1..].parse::<u32>() {
+            Ok(n) => (&spec[..ix], n),
+            Err(_) => return None,
+        };
+        let file = self.files.iter().find(|&(_, f)| {
+            f.name == name || Path::new(&f.name).file_name().map_or(false, |n| n == name)
+        });
+        let id = match file {
+            Some((&id, _)) => id,
+            None => return None,
+        };
+        self.starts
+            .range((id, line)..(id + 1, 0))
+            .next()
+            .map(|(_, &addr)| addr)
+    }
+
+    // Show the source around the line of the code at addr, marking it
+    pub fn print_context(&self, addr: u16, around: u32) {
+        let (file, line) = match self.line_at(addr) {
+            Some(l) => l,
+            None => return,
+        };
+        println!("{}", self.describe(file, line));
+        for n in line.saturating_sub(around).max(1)..line + around + 1 {
+            if let Some(text) = self.text(file, n) {
+                let mark = if n == line { ">" } else { " " };
+                println!("{} {:>5}  {}", mark, n, text);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        let mut map = SourceMap::new();
+        map.parse(
+            "version\tmajor=2,minor=0\n\
+             file\tid=0,name=\"src/hello.s\",size=120,mtime=0x5F5E1000,mod=0\n\
+             seg\tid=0,name=\"CODE\",start=0x000810,size=0x000008,addrsize=absolute,type=rw\n\
+             line\tid=0,file=0,line=5,span=0\n\
+             line\tid=1,file=0,line=6,span=1\n\
+             line\tid=2,file=0,line=8,span=2\n\
+             line\tid=3,file=0,line=20,type=2,span=3\n\
+             line\tid=4,file=0,line=9,span=3\n\
+             line\tid=70000,file=0,line=70000,span=70000\n\
+             span\tid=70000,seg=0,start=9,size=1\n\
+             span\tid=0,seg=0,start=0,size=2\n\
+             span\tid=1,seg=0,start=2,size=3\n\
+             span\tid=2,seg=0,start=5,size=2\n\
+             span\tid=3,seg=0,start=7,size=1\n",
+        );
+        assert_eq!(map.line_at(0x0810), Some((0, 5)));
+        assert_eq!(map.line_at(0x0814), Some((0, 6)));
+        assert_eq!(map.line_at(0x0817), Some((0, 9)));
+        assert_eq!(map.line_at(0x0818), None);
+        assert_eq!(map.line_starting(0x0813), None);
+        assert_eq!(map.find("src/hello.s:6"), Some(0x0812));
+        assert_eq!(map.find("hello.s:7"), Some(0x0815));
+        assert_eq!(map.find("hello.s:10"), Some(0x0819));
+        assert_eq!(map.find("hello.s:70001"), None);
+        assert_eq!(map.find("other.s:5"), None);
+        assert_eq!(map.describe(0, 9), "src/hello.s:9");
+        assert_eq!(map.line_at(0x0819), Some((0, 70000)));
+        assert_eq!(map.find("hello.s:70000"), Some(0x0819));
+    }
+}
@@ -0,0 +1,316 @@
+// Symbol table for the monitor: labels for addresses, used when
+// disassembling and accepted wherever the monitor takes an address
+use std::collections::{BTreeMap, HashMap};
+use utils::*;
+
+// How far past a label an address can be and still be shown by it
+const MAX_LABEL_OFFSET: u16 = 255;
+
+// Scopes nested deeper than this are taken to be a loop in a broken file
+const MAX_SCOPE_DEPTH: usize = 32;
+
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+    addrs: HashMap<String, u16>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols {
+            names: BTreeMap::new(),
+            addrs: HashMap::new(),
+        }
+    }
+
+    // Add a label. An address keeps the first label given to it, but every
+    // label can be looked up.
+    pub fn add(&mut self, addr: u16, name: &str) {
+        if !self.names.contains_key(&addr) {
+            self.names.insert(addr, name.to_string());
+        }
+        self.addrs.insert(name.to_string(), addr);
+    }
+
+    pub fn clear(&mut self) {
+        self.names.clear();
+        self.addrs.clear();
+    }
+
+    pub fn name_at(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(|s| s.as_str())
+    }
+
+    // The label at or shortly before addr, as name or name+offset
+    pub fn nearest(&self, addr: u16) -> Option<String> {
+        match self.names.range(..=addr).next_back() {
+            Some((&a, name)) if a == addr => Some(name.clone()),
+            Some((&a, name)) if addr - a <= MAX_LABEL_OFFSET => {
+                Some(format!("{}+{}", name, addr - a))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.addrs.get(name).cloned()
+    }
+
+    // Load labels from a VICE label file (also written by KickAssembler as
+    // .vs), a ca65/ld65 .dbg file or a KickAssembler .sym file. The format
+    // is recognised line by line, so the file name doesn't matter.
+    pub fn load(&mut self, filename: &str) -> Option<usize> {
+        let data = match load_file(filename) {
+            Some(d) => d,
+            None => return None,
+        };
+        let text = String::from_utf8_lossy(&data);
+        let count = self.parse(&text);
+        if count == 0 {
+            println!("No symbols found in {}", filename);
+            return None;
+        }
+        Some(count)
+    }
+
+    fn parse(&mut self, text: &str) -> usize {
+        let mut count = 0;
+        let mut namespaces: Vec<String> = Vec::new();
+        let mut dbg = DbgSymbols::new();
+        for line in text.lines() {
+            let line = line.trim();
+            let (addr, name) = if line.starts_with("al ") {
+                // al C:0810 .start
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() < 3 {
+                    continue;
+                }
+                let hex = fields[1].trim_start_matches("C:");
+                match u16::from_str_radix(hex, 16) {
+                    Ok(addr) => (addr, fields[2].trim_start_matches('.').to_string()),
+                    Err(_) => continue,
+                }
+            } else if line.starts_with(".label ") {
+                // .label start=$0810, inside .namespace blocks
+                let mut parts = line[7..].splitn(2, '=');
+                let name = parts.next().unwrap_or("").trim();
+                let val = parts
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .trim_end_matches('{')
+                    .trim();
+                match parse_number(val) {
+                    Some(addr) if !name.is_empty() => {
+                        let mut full = namespaces.join(".");
+                        if !full.is_empty() {
+                            full.push('.');
+                        }
+                        full.push_str(name);
+                        (addr, full)
+                    }
+                    _ => continue,
+                }
+            } else if line.starts_with(".namespace ") {
+                let name = line[11..].trim_end_matches('{').trim();
+                namespaces.push(name.to_string());
+                continue;
+            } else if line == "}" {
+                namespaces.pop();
+                continue;
+            } else {
+                if let Some((kind, fields)) = parse_dbg_line(line) {
+                    dbg.add(kind, &fields);
+                }
+                continue;
+            };
+            self.add(addr, &name);
+            count += 1;
+        }
+        for (addr, name) in dbg.labels() {
+            self.add(addr, &name);
+            count += 1;
+        }
+        count
+    }
+
+    pub fn print(&self) {
+        if self.names.is_empty() {
+            println!("No symbols");
+        }
+        for (addr, name) in self.names.iter() {
+            println!("${:04X} {}", addr, name);
+        }
+    }
+}
+
+// $hex, 0xhex or decimal. Ids, line numbers and sizes in .dbg files can
+// be more than 16 bits.
+pub fn parse_dbg_number(s: &str) -> Option<u32> {
+    let s = s.trim();
+    let n = if s.starts_with('$') {
+        u32::from_str_radix(&s[1..], 16)
+    } else if s.starts_with("0x") || s.starts_with("0X") {
+        u32::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse::<u32>()
+    };
+    n.ok()
+}
+
+// An address, written as for parse_dbg_number
+pub fn parse_number(s: &str) -> Option<u16> {
+    match parse_dbg_number(s) {
+        Some(n) if n <= 0xFFFF => Some(n as u16),
+        _ => None,
+    }
+}
+
+// Split a line of a ca65 .dbg file, such as
+//   sym id=3,name="loop",addrsize=absolute,scope=1,val=0x810,type=lab
+// into its record type and fields. Quoted values are unquoted.
+pub fn parse_dbg_line(line: &str) -> Option<(&str, HashMap<&str, &str>)> {
+    let ix = match line.find(char::is_whitespace) {
+        Some(ix) => ix,
+        None => return None,
+    };
+    let (kind, rest) = (&line[..ix], line[ix..].trim());
+    let mut fields = HashMap::new();
+    let mut rest = rest;
+    while !rest.is_empty() {
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => return None,
+        };
+        let key = rest[..eq].trim();
+        let after = &rest[eq + 1..];
+        let (val, tail) = if after.starts_with('"') {
+            match after[1..].find('"') {
+                Some(end) => (&after[1..end + 1], &after[end + 2..]),
+                None => return None,
+            }
+        } else {
+            match after.find(',') {
+                Some(end) => (&after[..end], &after[end..]),
+                None => (after, ""),
+            }
+        };
+        fields.insert(key, val);
+        rest = tail.trim_start_matches(',');
+    }
+    Some((kind, fields))
+}
+
+// Labels from a ca65 .dbg file. Names are qualified by their scope
+// (main::loop) and cheap locals by the label they follow (main@loop),
+// so that labels repeated in different procedures can be told apart.
+struct DbgSymbols {
+    scopes: HashMap<u32, (String, Option<u32>)>,
+    syms: Vec<(u32, String, u32, Option<u32>, u16)>,
+}
+
+impl DbgSymbols {
+    fn new() -> DbgSymbols {
+        DbgSymbols {
+            scopes: HashMap::new(),
+            syms: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, kind: &str, fields: &HashMap<&str, &str>) {
+        let num = |key: &str| fields.get(key).and_then(|v| parse_dbg_number(v));
+        let (id, name) = match (num("id"), fields.get("name")) {
+            (Some(id), Some(name)) => (id, name.to_string()),
+            _ => return,
+        };
+        match kind {
+            "scope" => {
+                self.scopes.insert(id, (name, num("parent")));
+            }
+            "sym" if fields.get("type") == Some(&"lab") => {
+                if let Some(val) = fields.get("val").and_then(|v| parse_number(v)) {
+                    self.syms
+                        .push((id, name, num("scope").unwrap_or(0), num("parent"), val));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn scope_name(&self, id: u32) -> String {
+        let mut names = Vec::new();
+        let mut id = id;
+        while let Some(&(ref name, Some(parent))) = self.scopes.get(&id) {
+            if name.is_empty() || names.len() == MAX_SCOPE_DEPTH {
+                break;
+            }
+            names.push(name.as_str());
+            id = parent;
+        }
+        names.reverse();
+        names.join("::")
+    }
+
+    fn labels(&self) -> Vec<(u16, String)> {
+        let mut labels = Vec::new();
+        for &(_, ref name, scope, parent, val) in self.syms.iter() {
+            let base = match parent.and_then(|p| self.syms.iter().find(|s| s.0 == p)) {
+                Some(p) => format!("{}{}", p.1, name),
+                None => name.clone(),
+            };
+            let scope = self.scope_name(scope);
+            if scope.is_empty() {
+                labels.push((val, base));
+            } else {
+                labels.push((val, format!("{}::{}", scope, base)));
+            }
+        }
+        labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vice_and_kickass() {
+        let mut symbols = Symbols::new();
+        let text = "al C:0810 .start\nal C:fffe .irq\n\
+                    .label main=$1000\n.namespace sprites {\n.label init=$2000\n}\n\
+                    .const COUNT=5\n.label after=$3000\n";
+        assert_eq!(symbols.parse(text), 5);
+        assert_eq!(symbols.lookup("start"), Some(0x0810));
+        assert_eq!(symbols.lookup("irq"), Some(0xFFFE));
+        assert_eq!(symbols.lookup("main"), Some(0x1000));
+        assert_eq!(symbols.lookup("sprites.init"), Some(0x2000));
+        assert_eq!(symbols.lookup("after"), Some(0x3000));
+        assert_eq!(symbols.lookup("COUNT"), None);
+    }
+
+    #[test]
+    fn ca65_dbg() {
+        let mut symbols = Symbols::new();
+        let text = "version\tmajor=2,minor=0\n\
+                    file\tid=0,name=\"a b.s\",size=100,mtime=0x5F000000,mod=0\n\
+                    scope\tid=0,name=\"\",mod=0,size=20\n\
+                    scope\tid=1,name=\"main\",mod=0,type=scope,size=20,parent=0,sym=0\n\
+                    sym\tid=0,name=\"main\",addrsize=absolute,scope=0,def=1,val=0x80D,seg=0,type=lab\n\
+                    sym\tid=1,name=\"loop\",addrsize=absolute,scope=1,def=2,val=0x810,seg=0,type=lab\n\
+                    sym\tid=2,name=\"@skip\",addrsize=absolute,scope=1,def=3,val=0x815,parent=1,type=lab\n\
+                    sym\tid=3,name=\"SCREEN\",addrsize=absolute,scope=0,def=4,val=0x400,type=equ\n\
+                    scope\tid=65536,name=\"big\",mod=0,type=scope,size=4,parent=0\n\
+                    sym\tid=70000,name=\"far\",addrsize=absolute,scope=65536,val=0x820,type=lab\n\
+                    scope\tid=2,name=\"a\",mod=0,type=scope,size=4,parent=3\n\
+                    scope\tid=3,name=\"b\",mod=0,type=scope,size=4,parent=2\n\
+                    sym\tid=4,name=\"lost\",addrsize=absolute,scope=2,val=0x830,type=lab\n";
+        assert_eq!(symbols.parse(text), 5);
+        assert_eq!(symbols.lookup("big::far"), Some(0x0820));
+        assert_eq!(symbols.lookup("main"), Some(0x080D));
+        assert_eq!(symbols.lookup("main::loop"), Some(0x0810));
+        assert_eq!(symbols.lookup("main::loop@skip"), Some(0x0815));
+        assert_eq!(symbols.name_at(0x0810), Some("main::loop"));
+        assert_eq!(symbols.lookup("SCREEN"), None);
+        // Scopes that are each other's parent don't recurse forever
+        assert!(symbols.name_at(0x0830).unwrap().ends_with("a::lost"));
+    }
+}
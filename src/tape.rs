@@ -0,0 +1,286 @@
+// Datasette: plays TAP pulse files into the CIA1 FLAG line and records the
+// cassette write line back into TAP files.
+use std::fs::File;
+use std::io::prelude::*;
+use utils::*;
+
+const TAP_SIGNATURE: &'static [u8] = b"C64-TAPE-RAW";
+const TAP_HEADER_SIZE: usize = 20;
+const TAP_VERSION: u8 = 1;
+
+// PAL clock, for the tape counter
+const CYCLES_PER_SECOND: u64 = 985248;
+
+// Pulse lengths in cycles from TAP data. Version 0 marks overflows with a
+// zero byte, versions 1 and 2 follow it with a 24 bit cycle count. Version 2
+// holds half waves, which are joined into whole pulses.
+pub fn read_tap(data: &[u8]) -> Result<Vec<u32>, String> {
+    if data.len() < TAP_HEADER_SIZE || &data[0..12] != TAP_SIGNATURE {
+        return Err("not a TAP file".to_string());
+    }
+    let version = data[12];
+    if version > 2 {
+        return Err(format!("unsupported TAP version {}", version));
+    }
+    let len = data[16] as usize
+        | (data[17] as usize) << 8
+        | (data[18] as usize) << 16
+        | (data[19] as usize) << 24;
+    let end = (TAP_HEADER_SIZE + len).min(data.len());
+
+    let mut pulses = Vec::new();
+    let mut ix = TAP_HEADER_SIZE;
+    while ix < end {
+        let pulse = match data[ix] {
+            0 if version == 0 => 256 * 8,
+            0 if ix + 3 < end => {
+                ix += 3;
+                data[ix - 2] as u32 | (data[ix - 1] as u32) << 8 | (data[ix] as u32) << 16
+            }
+            0 => break,
+            b => b as u32 * 8,
+        };
+        pulses.push(pulse);
+        ix += 1;
+    }
+
+    if version == 2 {
+        pulses = pulses.chunks(2).map(|p| p.iter().sum()).collect();
+    }
+    Ok(pulses)
+}
+
+// TAP version 1 data for a list of pulses
+pub fn write_tap(pulses: &[u32]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for &p in pulses {
+        if p / 8 > 0 && p / 8 < 256 {
+            body.push((p / 8) as u8);
+        } else {
+            let p = p.min(0xFFFFFF);
+            body.extend_from_slice(&[0, (p & 0xFF) as u8, (p >> 8) as u8, (p >> 16) as u8]);
+        }
+    }
+
+    let mut data = TAP_SIGNATURE.to_vec();
+    data.extend_from_slice(&[TAP_VERSION, 0, 0, 0]);
+    let len = body.len();
+    data.extend_from_slice(&[
+        len as u8,
+        (len >> 8) as u8,
+        (len >> 16) as u8,
+        (len >> 24) as u8,
+    ]);
+    data.extend(body);
+    data
+}
+
+pub struct Datasette {
+    pulses: Vec<u32>,
+    pos: usize,
+    countdown: i32,
+    name: Option<String>,
+    playing: bool,
+    recording: bool,
+    record_path: String,
+    write_line: bool,
+    since_edge: u32,
+    tape_cycles: u64,
+}
+
+impl Datasette {
+    pub fn new() -> Datasette {
+        Datasette {
+            pulses: Vec::new(),
+            pos: 0,
+            countdown: 0,
+            name: None,
+            playing: false,
+            recording: false,
+            record_path: String::new(),
+            write_line: false,
+            since_edge: 0,
+            tape_cycles: 0,
+        }
+    }
+
+    pub fn insert(&mut self, filename: &str) -> bool {
+        self.stop();
+        let data = match load_file(filename) {
+            Some(d) => d,
+            None => return false,
+        };
+        match read_tap(&data) {
+            Ok(pulses) => {
+                self.pulses = pulses;
+                self.name = Some(filename.to_string());
+                self.rewind();
+                true
+            }
+            Err(e) => {
+                println!("Unable to read {}: {}", filename, e);
+                false
+            }
+        }
+    }
+
+    pub fn is_inserted(&self) -> bool {
+        self.name.is_some()
+    }
+
+    pub fn play(&mut self) {
+        if !self.is_inserted() {
+            return println!("No tape inserted");
+        }
+        self.stop();
+        self.playing = true;
+    }
+
+    // Press RECORD and PLAY on a fresh tape saved to the given file on stop
+    pub fn record(&mut self, filename: &str) {
+        self.stop();
+        self.pulses = Vec::new();
+        self.name = Some(filename.to_string());
+        self.record_path = filename.to_string();
+        self.rewind();
+        self.recording = true;
+        self.since_edge = 0;
+    }
+
+    pub fn stop(&mut self) {
+        if self.recording {
+            let data = write_tap(&self.pulses);
+            match File::create(&self.record_path).and_then(|mut f| f.write_all(&data)) {
+                Ok(_) => println!(
+                    "Recorded {} pulses to {}",
+                    self.pulses.len(),
+                    self.record_path
+                ),
+                Err(e) => println!("Couldn't write {}: {:?}", self.record_path, e),
+            }
+        }
+        self.playing = false;
+        self.recording = false;
+    }
+
+    pub fn rewind(&mut self) {
+        self.pos = 0;
+        self.tape_cycles = 0;
+        self.countdown = self.pulses.first().map_or(0, |&p| p as i32);
+    }
+
+    // Sense line: a button is held down
+    pub fn sense(&self) -> bool {
+        self.playing || self.recording
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.pos >= self.pulses.len()
+    }
+
+    // Run the tape for the given cycles. Returns true when a pulse from the
+    // read line arrives on the CIA1 FLAG pin.
+    pub fn clock(&mut self, cycles: u8, motor: bool, write_line: bool) -> bool {
+        if !motor || !self.sense() {
+            self.write_line = write_line;
+            return false;
+        }
+        self.tape_cycles += cycles as u64;
+
+        if self.recording {
+            // A pulse lasts from one rising edge of the write line to the next
+            self.since_edge += cycles as u32;
+            if write_line && !self.write_line {
+                if self.since_edge > 0 {
+                    self.pulses.push(self.since_edge);
+                }
+                self.since_edge = 0;
+            }
+            self.write_line = write_line;
+            return false;
+        }
+
+        if self.at_end() {
+            println!("End of tape");
+            self.stop();
+            return false;
+        }
+        self.countdown -= cycles as i32;
+        if self.countdown > 0 {
+            return false;
+        }
+        self.pos += 1;
+        if let Some(&p) = self.pulses.get(self.pos) {
+            self.countdown += p as i32;
+        }
+        true
+    }
+
+    pub fn status(&self) -> String {
+        let name = match self.name {
+            Some(ref n) => n.clone(),
+            None => return "No tape inserted".to_string(),
+        };
+        let state = if self.recording {
+            "recording"
+        } else if self.playing {
+            "playing"
+        } else {
+            "stopped"
+        };
+        let seconds = self.tape_cycles / CYCLES_PER_SECOND;
+        format!(
+            "{}: {}, counter {:03} ({}:{:02}), pulse {}/{}",
+            name,
+            state,
+            seconds % 1000,
+            seconds / 60,
+            seconds % 60,
+            self.pos,
+            self.pulses.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tap(version: u8, body: &[u8]) -> Vec<u8> {
+        let mut data = TAP_SIGNATURE.to_vec();
+        data.extend_from_slice(&[version, 0, 0, 0, body.len() as u8, 0, 0, 0]);
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        // Pulses too short or too long for a byte use the overflow form
+        let pulses = vec![0x180, 8, 0x7F8, 0x800, 0x12345, 5, 0x2F0];
+        let data = write_tap(&pulses);
+        assert_eq!(data.len(), TAP_HEADER_SIZE + 4 + 3 * 4);
+        assert_eq!(
+            &data[TAP_HEADER_SIZE + 3..TAP_HEADER_SIZE + 7],
+            &[0, 0x00, 0x08, 0x00]
+        );
+        assert_eq!(read_tap(&data).unwrap(), pulses);
+    }
+
+    #[test]
+    fn v0_overflow() {
+        let data = tap(0, &[0x30, 0, 0x40]);
+        assert_eq!(read_tap(&data).unwrap(), vec![0x180, 256 * 8, 0x200]);
+    }
+
+    #[test]
+    fn v2_half_waves() {
+        let data = tap(2, &[0x10, 0x10, 0x20, 0, 0x00, 0x01, 0x00]);
+        assert_eq!(read_tap(&data).unwrap(), vec![0x100, 0x200]);
+    }
+
+    #[test]
+    fn bad_files() {
+        assert!(read_tap(b"C64-TAPE").is_err());
+        assert!(read_tap(&tap(3, &[0x30])).is_err());
+    }
+}
@@ -1,6 +1,7 @@
+use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub fn hexdump(buf: &Vec<u8>, from: usize, bytes: usize) {
     // Print offset
@@ -53,3 +54,16 @@ pub fn load_file(filename: &str) -> Option<Vec<u8>> {
 
     Some(buf)
 }
+
+// Entries of a host directory, sorted by name
+pub fn dir_entries(dir: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Err(err) => {
+            println!("Couldn't read {}: {:?}", dir, err);
+            return Vec::new();
+        }
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+    };
+    paths.sort();
+    paths
+}
@@ -0,0 +1,240 @@
+use std::cell::Cell;
+
+// MOS 6522 VIA registers
+pub const VIA_ORB: u16 = 0x0;
+pub const VIA_ORA: u16 = 0x1;
+pub const VIA_DDRB: u16 = 0x2;
+pub const VIA_DDRA: u16 = 0x3;
+pub const VIA_T1CL: u16 = 0x4;
+pub const VIA_T1CH: u16 = 0x5;
+pub const VIA_T1LL: u16 = 0x6;
+pub const VIA_T1LH: u16 = 0x7;
+pub const VIA_T2CL: u16 = 0x8;
+pub const VIA_T2CH: u16 = 0x9;
+pub const VIA_SR: u16 = 0xA;
+pub const VIA_ACR: u16 = 0xB;
+pub const VIA_PCR: u16 = 0xC;
+pub const VIA_IFR: u16 = 0xD;
+pub const VIA_IER: u16 = 0xE;
+pub const VIA_ORA_NH: u16 = 0xF;
+
+// Interrupt flag bits
+pub const VIA_INT_CA2: u8 = 0x01;
+pub const VIA_INT_CA1: u8 = 0x02;
+pub const VIA_INT_T2: u8 = 0x20;
+pub const VIA_INT_T1: u8 = 0x40;
+
+const ACR_T1_FREE_RUN: u8 = 0x40;
+
+pub struct VIA {
+    orb: u8,
+    ora: u8,
+    ddrb: u8,
+    ddra: u8,
+    pins_a: u8,
+    pins_b: u8,
+    t1: i32,
+    t1_latch: u16,
+    t1_armed: bool,
+    t2: i32,
+    t2_latch_lo: u8,
+    t2_armed: bool,
+    sr: u8,
+    acr: u8,
+    pcr: u8,
+    ifr: Cell<u8>,
+    ier: u8,
+    ca1: bool,
+}
+
+impl VIA {
+    pub fn new() -> VIA {
+        VIA {
+            orb: 0,
+            ora: 0,
+            ddrb: 0,
+            ddra: 0,
+            pins_a: 0xFF,
+            pins_b: 0xFF,
+            t1: 0xFFFF,
+            t1_latch: 0xFFFF,
+            t1_armed: false,
+            t2: 0xFFFF,
+            t2_latch_lo: 0xFF,
+            t2_armed: false,
+            sr: 0,
+            acr: 0,
+            pcr: 0,
+            ifr: Cell::new(0),
+            ier: 0,
+            ca1: false,
+        }
+    }
+
+    pub fn read_register(&self, reg: u16) -> u8 {
+        match reg & 0x0F {
+            VIA_ORB => (self.orb & self.ddrb) | (self.pins_b & !self.ddrb),
+            VIA_ORA => {
+                self.clear_flags(VIA_INT_CA1 | VIA_INT_CA2);
+                (self.ora & self.ddra) | (self.pins_a & !self.ddra)
+            }
+            VIA_ORA_NH => (self.ora & self.ddra) | (self.pins_a & !self.ddra),
+            VIA_DDRB => self.ddrb,
+            VIA_DDRA => self.ddra,
+            VIA_T1CL => {
+                self.clear_flags(VIA_INT_T1);
+                (self.t1 & 0xFF) as u8
+            }
+            VIA_T1CH => ((self.t1 >> 8) & 0xFF) as u8,
+            VIA_T1LL => (self.t1_latch & 0xFF) as u8,
+            VIA_T1LH => (self.t1_latch >> 8) as u8,
+            VIA_T2CL => {
+                self.clear_flags(VIA_INT_T2);
+                (self.t2 & 0xFF) as u8
+            }
+            VIA_T2CH => ((self.t2 >> 8) & 0xFF) as u8,
+            VIA_SR => self.sr,
+            VIA_ACR => self.acr,
+            VIA_PCR => self.pcr,
+            VIA_IFR => {
+                let ifr = self.ifr.get();
+                if self.irq() {
+                    ifr | 0x80
+                } else {
+                    ifr
+                }
+            }
+            VIA_IER => self.ier | 0x80,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_register(&mut self, reg: u16, val: u8) {
+        match reg & 0x0F {
+            VIA_ORB => self.orb = val,
+            VIA_ORA => {
+                self.clear_flags(VIA_INT_CA1 | VIA_INT_CA2);
+                self.ora = val
+            }
+            VIA_ORA_NH => self.ora = val,
+            VIA_DDRB => self.ddrb = val,
+            VIA_DDRA => self.ddra = val,
+            VIA_T1CL | VIA_T1LL => self.t1_latch = (self.t1_latch & 0xFF00) | val as u16,
+            VIA_T1CH => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | ((val as u16) << 8);
+                self.t1 = self.t1_latch as i32;
+                self.t1_armed = true;
+                self.clear_flags(VIA_INT_T1);
+            }
+            VIA_T1LH => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | ((val as u16) << 8);
+                self.clear_flags(VIA_INT_T1);
+            }
+            VIA_T2CL => self.t2_latch_lo = val,
+            VIA_T2CH => {
+                self.t2 = (((val as u16) << 8) | self.t2_latch_lo as u16) as i32;
+                self.t2_armed = true;
+                self.clear_flags(VIA_INT_T2);
+            }
+            VIA_SR => self.sr = val,
+            VIA_ACR => self.acr = val,
+            VIA_PCR => self.pcr = val,
+            VIA_IFR => self.clear_flags(val & 0x7F),
+            VIA_IER => {
+                if (val & 0x80) > 0 {
+                    self.ier |= val & 0x7F
+                } else {
+                    self.ier &= !val
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Count the timers down by the given number of cycles
+    pub fn clock(&mut self, cycles: u8) {
+        self.t1 -= cycles as i32;
+        if self.t1 < 0 {
+            if self.t1_armed {
+                self.set_flags(VIA_INT_T1);
+            }
+            if (self.acr & ACR_T1_FREE_RUN) > 0 {
+                // Reload takes the counter through $FFFF first
+                let period = self.t1_latch as i32 + 2;
+                while self.t1 < 0 {
+                    self.t1 += period;
+                }
+            } else {
+                self.t1_armed = false;
+                self.t1 &= 0xFFFF;
+            }
+        }
+
+        self.t2 -= cycles as i32;
+        if self.t2 < 0 {
+            if self.t2_armed {
+                self.set_flags(VIA_INT_T2);
+                self.t2_armed = false;
+            }
+            self.t2 &= 0xFFFF;
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        (self.ifr.get() & self.ier & 0x7F) > 0
+    }
+
+    pub fn set_flags(&self, flags: u8) {
+        self.ifr.set(self.ifr.get() | flags);
+    }
+
+    fn clear_flags(&self, flags: u8) {
+        self.ifr.set(self.ifr.get() & !flags);
+    }
+
+    // Port pins driven by the outside world (seen on inputs)
+    pub fn set_pins_a(&mut self, val: u8) {
+        self.pins_a = val;
+    }
+
+    pub fn set_pins_b(&mut self, val: u8) {
+        self.pins_b = val;
+    }
+
+    // Port values as driven on the pins: outputs, with inputs pulled up
+    pub fn port_a(&self) -> u8 {
+        (self.ora & self.ddra) | !self.ddra
+    }
+
+    pub fn port_b(&self) -> u8 {
+        (self.orb & self.ddrb) | !self.ddrb
+    }
+
+    // Drive the CA1 input, flagging an interrupt on the edge selected in PCR
+    pub fn set_ca1(&mut self, level: bool) {
+        if level != self.ca1 {
+            let positive = (self.pcr & 0x01) > 0;
+            if level == positive {
+                self.set_flags(VIA_INT_CA1);
+            }
+            self.ca1 = level;
+        }
+    }
+
+    // Output level of CA2 and CB2 when PCR sets them to manual output
+    pub fn ca2_output(&self) -> Option<bool> {
+        match (self.pcr >> 1) & 0x07 {
+            0b110 => Some(false),
+            0b111 => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn cb2_output(&self) -> Option<bool> {
+        match (self.pcr >> 5) & 0x07 {
+            0b110 => Some(false),
+            0b111 => Some(true),
+            _ => None,
+        }
+    }
+}